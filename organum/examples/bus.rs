@@ -0,0 +1,86 @@
+// Measures bus throughput on a Taleä-like layout: a small rom followed by ram on the main bus, and a data bus
+// with a row of device registers in front of its memory. Fetches and data words are single 4 byte reads,
+// copies and fills move whole blocks like the memory instructions do
+//
+//     cargo run --release --example bus [seconds per test]
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+use organum::core::{wrap_transmutable, Address, Addressable};
+use organum::error::Error;
+use organum::premade::bus::{Bus, BusPort};
+use organum::premade::memory::MemoryBlock;
+
+const MAIN_BITS: u8 = 24;
+const DATA_BITS: u8 = 16;
+const ROM_SIZE: usize = 0x1000;
+const IO_END: Address = 0x44;
+const BLOCK_SIZE: usize = 0x1000;
+
+fn main_bus() -> Rc<RefCell<Bus>> {
+    let mut bus = Bus::new();
+    bus.insert(0, wrap_transmutable(MemoryBlock::new(vec![0; ROM_SIZE])));
+    bus.insert(ROM_SIZE as Address, wrap_transmutable(MemoryBlock::new(vec![0; (1 << MAIN_BITS) - ROM_SIZE])));
+    Rc::new(RefCell::new(bus))
+}
+
+fn data_bus() -> Rc<RefCell<Bus>> {
+    let mut bus = Bus::new();
+    for base in (0..IO_END).step_by(4) {
+        bus.insert(base, wrap_transmutable(MemoryBlock::new(vec![0; 4])));
+    }
+    bus.insert(IO_END, wrap_transmutable(MemoryBlock::new(vec![0; (1 << DATA_BITS) - IO_END as usize])));
+    Rc::new(RefCell::new(bus))
+}
+
+// Runs the test in rounds until the time is up, returns how many rounds it managed per second
+fn measure(seconds: f64, mut round: impl FnMut() -> Result<(), Error>) -> Result<f64, Error> {
+    let start = Instant::now();
+    let mut rounds = 0;
+    while start.elapsed().as_secs_f64() < seconds {
+        for _ in 0..100 {
+            round()?;
+        }
+        rounds += 100;
+    }
+    Ok(rounds as f64 / start.elapsed().as_secs_f64())
+}
+
+fn main() -> Result<(), Error> {
+    let seconds: f64 = std::env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(1.0);
+    let mut main_port = BusPort::new(0, MAIN_BITS, 32, main_bus());
+    let mut data_port = BusPort::new(0, DATA_BITS, 32, data_bus());
+
+    // 1024 instructions in a row from ram
+    let rate = measure(seconds, || {
+        for pc in (0x2000..0x3000).step_by(4) {
+            main_port.read_beu32(pc)?;
+        }
+        Ok(())
+    })?;
+    println!("fetch: {:>10.2} M words/s", rate * 1024.0 / 1e6);
+
+    let rate = measure(seconds, || {
+        for addr in (0xf000..0xf400).step_by(4) {
+            let value = data_port.read_beu32(addr)?;
+            data_port.write_beu32(addr, value.wrapping_add(1))?;
+        }
+        Ok(())
+    })?;
+    println!("data:  {:>10.2} M words/s", rate * 512.0 / 1e6);
+
+    let mut buffer = vec![0u8; BLOCK_SIZE];
+    let rate = measure(seconds, || {
+        main_port.read(0x10000, &mut buffer)?;
+        main_port.write(0x20000, &buffer)
+    })?;
+    println!("copy:  {:>10.2} MB/s", rate * BLOCK_SIZE as f64 / 1e6);
+
+    let fill = vec![0xaau8; BLOCK_SIZE];
+    let rate = measure(seconds, || {
+        main_port.write(0x30000, &fill)
+    })?;
+    println!("fill:  {:>10.2} MB/s", rate * BLOCK_SIZE as f64 / 1e6);
+    Ok(())
+}
@@ -0,0 +1,102 @@
+// Measures how fast the system can run a Taleä-like machine: a cpu fetching from memory every 100 ns,
+// a timer and a tty that poll, and two storage controllers that are either polled like they used to be,
+// or asleep until a command arrives
+//
+//     cargo run --release --example scheduler [seconds of emulated time] [--poll]
+use std::time::Instant;
+
+use organum::core::{wrap_transmutable, Address, Addressable, ClockElapsed, Steppable, Transmutable, SLEEP};
+use organum::error::Error;
+use organum::premade::memory::MemoryBlock;
+use organum::scheduler::Waker;
+use organum::sys::System;
+
+const CPU_FREQUENCY: u64 = 10_000_000;
+
+struct Cpu {
+    pc: Address,
+}
+
+impl Steppable for Cpu {
+    fn step(&mut self, system: &System) -> Result<ClockElapsed, Error> {
+        system.get_bus().read_beu32(self.pc)?;
+        self.pc = (self.pc + 4) % 0x1000;
+        Ok(1_000_000_000 / CPU_FREQUENCY)
+    }
+}
+
+impl Transmutable for Cpu {
+    fn as_steppable(&mut self) -> Option<&mut dyn Steppable> {
+        Some(self)
+    }
+}
+
+struct Poller {
+    period: ClockElapsed,
+}
+
+impl Steppable for Poller {
+    fn step(&mut self, _system: &System) -> Result<ClockElapsed, Error> {
+        Ok(self.period)
+    }
+}
+
+impl Transmutable for Poller {
+    fn as_steppable(&mut self) -> Option<&mut dyn Steppable> {
+        Some(self)
+    }
+}
+
+struct Sleeper {
+    waker: Waker,
+}
+
+impl Steppable for Sleeper {
+    fn step(&mut self, _system: &System) -> Result<ClockElapsed, Error> {
+        Ok(SLEEP)
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        Some(self.waker.clone())
+    }
+}
+
+impl Transmutable for Sleeper {
+    fn as_steppable(&mut self) -> Option<&mut dyn Steppable> {
+        Some(self)
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let poll = args.iter().any(|arg| arg == "--poll");
+    let seconds: f64 = args.iter().find_map(|arg| arg.parse().ok()).unwrap_or(1.0);
+
+    let mut system = System::new();
+    system.add_addressable_device(0, wrap_transmutable(MemoryBlock::new(vec![0; 0x1000])))?;
+    system.add_device("cpu", wrap_transmutable(Cpu { pc: 0 }))?;
+    for name in ["disk", "tps"] {
+        if poll {
+            system.add_device(name, wrap_transmutable(Poller { period: 100 }))?;
+        } else {
+            system.add_device(name, wrap_transmutable(Sleeper { waker: Waker::new() }))?;
+        }
+    }
+    system.add_device("tty", wrap_transmutable(Poller { period: 1_000_000_000 / 38_400 }))?;
+    system.add_device("timer", wrap_transmutable(Poller { period: 1_000 }))?;
+
+    let emulated = (seconds * 1e9) as ClockElapsed;
+    let start = Instant::now();
+    system.run_for(emulated)?;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let cycles = system.clock as f64 / 1e9 * CPU_FREQUENCY as f64;
+    println!(
+        "{} storage controllers: {:.3}s of emulated time in {:.3}s, {:.2} emulated MHz",
+        if poll { "polled" } else { "sleeping" },
+        system.clock as f64 / 1e9,
+        elapsed,
+        cycles / elapsed / 1e6
+    );
+    Ok(())
+}
@@ -0,0 +1,362 @@
+// Backends carry the bytes of a serial line between the emulator and whatever sits on the other end:
+// a tcp client, the host terminal, a pseudo-terminal, a pair of files or just memory.
+// None of them ever blocks the machine, reads only return what has already arrived
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::{self, JoinHandle};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::openpty;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, LocalFlags, OutputFlags, SetArg, Termios};
+use nix::unistd::{isatty, ttyname};
+
+use crate::error::Error;
+use crate::server::Server;
+
+pub trait SerialBackend {
+    // The next byte from the other end, if one has arrived
+    fn read(&mut self) -> Option<u8>;
+    fn write(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    // Called every step, for backends that hold on to data until they can pass it on
+    fn flush(&mut self) { }
+
+    // Where the line goes, to tell the user
+    fn describe(&self) -> String;
+}
+
+// Forwards everything read from `input` to the channel, until it runs out or fails
+fn spawn_reader<R: Read + Send + 'static>(mut input: R, sender: Sender<u8>) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        loop {
+            match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if buf[..n].iter().any(|byte| sender.send(*byte).is_err()) {
+                        break
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+fn received(receiver: &Receiver<u8>) -> Option<u8> {
+    match receiver.try_recv() {
+        Ok(byte) => Some(byte),
+        Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+    }
+}
+
+
+impl SerialBackend for Server {
+    fn read(&mut self) -> Option<u8> {
+        self.receiver.try_recv().ok().map(|buf| buf[0])
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.send(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        Server::flush(self)
+    }
+
+    fn describe(&self) -> String {
+        match self.client_addr() {
+            Some(addr) => format!("tcp {}:{}, client {}", self.ip, self.port, addr),
+            None => format!("tcp {}:{}", self.ip, self.port),
+        }
+    }
+}
+
+
+// The terminal settings stdin had before Stdio changed them, put back when the emulator exits
+static ORIGINAL_TERMIOS: Mutex<Option<Termios>> = Mutex::new(None);
+static RESTORE_AT_EXIT: Once = Once::new();
+
+extern "C" fn restore_terminal() {
+    if let Ok(mut original) = ORIGINAL_TERMIOS.lock() {
+        if let Some(termios) = original.take() {
+            let _ = tcsetattr(io::stdin().as_raw_fd(), SetArg::TCSANOW, &termios);
+        }
+    }
+}
+
+// Exit handlers don't run when a signal ends the process, so the terminal is put back first
+extern "C" fn restore_terminal_and_raise(signal: nix::libc::c_int) {
+    restore_terminal();
+    unsafe {
+        nix::libc::signal(signal, nix::libc::SIG_DFL);
+        nix::libc::raise(signal);
+    }
+}
+
+// The host's own terminal. Keys go to the guest as they are typed, without echo or line editing,
+// though output processing stays on so the emulator's own messages still print properly, and
+// Ctrl-C and Ctrl-\ still stop the emulator
+pub struct Stdio {
+    receiver: Receiver<u8>,
+}
+
+impl Stdio {
+    pub fn new() -> Result<Self, Error> {
+        let fd = io::stdin().as_raw_fd();
+        if isatty(fd).unwrap_or(false) {
+            let original = tcgetattr(fd).map_err(|e| Error::new(&format!("serial: unable to read the terminal settings: {}", e)))?;
+            let mut raw = original.clone();
+            cfmakeraw(&mut raw);
+            raw.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+            raw.local_flags |= LocalFlags::ISIG;
+            tcsetattr(fd, SetArg::TCSANOW, &raw).map_err(|e| Error::new(&format!("serial: unable to set the terminal to raw mode: {}", e)))?;
+
+            *ORIGINAL_TERMIOS.lock().unwrap() = Some(original);
+            RESTORE_AT_EXIT.call_once(|| unsafe {
+                nix::libc::atexit(restore_terminal);
+                let handler = restore_terminal_and_raise as extern "C" fn(nix::libc::c_int) as nix::libc::sighandler_t;
+                nix::libc::signal(nix::libc::SIGINT, handler);
+                nix::libc::signal(nix::libc::SIGQUIT, handler);
+            });
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        spawn_reader(io::stdin(), sender);
+        Ok(Self { receiver })
+    }
+}
+
+impl Drop for Stdio {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+impl SerialBackend for Stdio {
+    fn read(&mut self) -> Option<u8> {
+        received(&self.receiver)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(data).and_then(|_| stdout.flush())
+            .map_err(|e| Error::new(&format!("serial: unable to write to stdout: {}", e)))
+    }
+
+    fn describe(&self) -> String {
+        "stdio".to_string()
+    }
+}
+
+
+// A pseudo-terminal, any terminal program can open the slave side (screen /dev/pts/N).
+// The emulator keeps the slave open itself so the line stays up between sessions, output sent
+// while nobody is reading is dropped once the kernel's buffer is full
+pub struct Pty {
+    master: File,
+    // Only held open
+    _slave: File,
+    path: PathBuf,
+    receiver: Receiver<u8>,
+}
+
+impl Pty {
+    pub fn new() -> Result<Self, Error> {
+        let pty = openpty(None, None).map_err(|e| Error::new(&format!("serial: unable to open a pty: {}", e)))?;
+        let (master, slave) = unsafe { (File::from_raw_fd(pty.master), File::from_raw_fd(pty.slave)) };
+        let path = ttyname(slave.as_raw_fd()).map_err(|e| Error::new(&format!("serial: unable to name the pty: {}", e)))?;
+
+        if let Ok(mut termios) = tcgetattr(slave.as_raw_fd()) {
+            cfmakeraw(&mut termios);
+            let _ = tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &termios);
+        }
+        fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .map_err(|e| Error::new(&format!("serial: unable to set up the pty: {}", e)))?;
+
+        // The descriptor is shared with the reader, so it waits for input instead of spinning on it
+        let mut input = master.try_clone().map_err(|e| Error::new(&format!("serial: unable to set up the pty: {}", e)))?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 256];
+            loop {
+                let mut fds = [PollFd::new(input.as_raw_fd(), PollFlags::POLLIN)];
+                if poll(&mut fds, 100).is_err() {
+                    continue;
+                }
+                match input.read(&mut buf) {
+                    Ok(n) => {
+                        if buf[..n].iter().any(|byte| sender.send(*byte).is_err()) {
+                            break
+                        }
+                    },
+                    Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => continue,
+                    Err(_) => break,
+                }
+            }
+        });
+
+        println!("[SERIAL] pty at {}", path.display());
+        Ok(Self { master, _slave: slave, path, receiver })
+    }
+}
+
+impl SerialBackend for Pty {
+    fn read(&mut self) -> Option<u8> {
+        received(&self.receiver)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.master.write(data) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(Error::new(&format!("serial: unable to write to {}: {}", self.path.display(), e))),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("pty {}", self.path.display())
+    }
+}
+
+
+// Reads the guest's input from one file and writes its output to another. Both are opened in
+// the background, so they can be named pipes whose other end shows up later
+pub struct FilePair {
+    input: PathBuf,
+    output: PathBuf,
+    receiver: Receiver<u8>,
+    sender: Option<Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+    opened: Arc<AtomicBool>,
+}
+
+impl FilePair {
+    pub fn new(input: &Path, output: &Path) -> Self {
+        let (in_sender, receiver) = mpsc::channel();
+        let path = input.to_path_buf();
+        thread::spawn(move || {
+            match File::open(&path) {
+                Ok(file) => spawn_reader(file, in_sender),
+                Err(e) => println!("[SERIAL] Unable to open {}: {}", path.display(), e),
+            }
+        });
+
+        let (sender, out_receiver) = mpsc::channel::<Vec<u8>>();
+        let path = output.to_path_buf();
+        let opened = Arc::new(AtomicBool::new(false));
+        let writer_opened = opened.clone();
+        let writer = thread::spawn(move || {
+            let mut file = match OpenOptions::new().write(true).create(true).truncate(true).open(&path) {
+                Ok(file) => {
+                    writer_opened.store(true, Ordering::Release);
+                    file
+                },
+                Err(e) => {
+                    println!("[SERIAL] Unable to open {}: {}", path.display(), e);
+                    return
+                },
+            };
+            for data in out_receiver {
+                if let Err(e) = file.write_all(&data).and_then(|_| file.flush()) {
+                    println!("[SERIAL] Unable to write to {}: {}", path.display(), e);
+                    break
+                }
+            }
+        });
+
+        Self {
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            receiver,
+            sender: Some(sender),
+            writer: Some(writer),
+            opened,
+        }
+    }
+}
+
+// Waits for the output to be written out, unless the file never opened (a pipe nobody read from)
+impl Drop for FilePair {
+    fn drop(&mut self) {
+        self.sender.take();
+        if self.opened.load(Ordering::Acquire) {
+            if let Some(writer) = self.writer.take() {
+                let _ = writer.join();
+            }
+        }
+    }
+}
+
+impl SerialBackend for FilePair {
+    fn read(&mut self) -> Option<u8> {
+        received(&self.receiver)
+    }
+
+    // Once the writer has given up the output is dropped, like on a line nobody listens to
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(data.to_vec());
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("files {} -> {}", self.input.display(), self.output.display())
+    }
+}
+
+
+#[derive(Debug, Default)]
+pub struct LoopbackState {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+// Everything the guest sends comes straight back to it. The state is shared, so whoever built
+// the backend can also feed it input and look at what was sent
+#[derive(Clone, Debug, Default)]
+pub struct Loopback {
+    pub state: Arc<Mutex<LoopbackState>>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_input(&self, data: &[u8]) {
+        self.state.lock().unwrap().input.extend(data);
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.state.lock().unwrap().output)
+    }
+}
+
+impl SerialBackend for Loopback {
+    fn read(&mut self) -> Option<u8> {
+        self.state.lock().unwrap().input.pop_front()
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.output.extend_from_slice(data);
+        state.input.extend(data);
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        "loopback".to_string()
+    }
+}
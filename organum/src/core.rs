@@ -2,11 +2,15 @@
 use core::cell::RefCell;
 use std::rc::Rc;
 use crate::error::Error;
+use crate::scheduler::Waker;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 use crate::sys::System;
 
 pub type Address = u64;
 pub type Clock = u64;
 pub type ClockElapsed = u64;
+/// Returned by `Steppable::step` to stay out of the schedule until the device's waker is used
+pub const SLEEP: ClockElapsed = ClockElapsed::MAX;
 pub type TransmutableBox = Rc<RefCell<Box<dyn Transmutable>>>;
 
 /// A device that can be addressed to read data from or write data to the device.
@@ -15,6 +19,16 @@ pub trait Addressable {
     fn read(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error>;
     fn write(&mut self, addr: Address, data: &[u8]) -> Result<(), Error>;
 
+    // Devices backed by plain memory hand out their contents, so the bus can copy to and from them directly.
+    // Writes to devices without mutable contents, like read only memory, go through `write`
+    fn contents(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn contents_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
     fn read_u8(&mut self, addr: Address) -> Result<u8, Error> {
         let mut data = [0; 1];
         self.read(addr, &mut data)?;
@@ -78,6 +92,12 @@ pub trait Addressable {
 pub trait Steppable {
     fn step(&mut self, system: &System) -> Result<ClockElapsed, Error>;
     fn on_error(&mut self, _system: &System) { }
+    /// Called once when the run is over without an error, for devices that hold on to output
+    fn on_shutdown(&mut self, _system: &System) { }
+    /// Devices that may sleep give the system a way to wake them up
+    fn waker(&self) -> Option<Waker> {
+        None
+    }
 }
 /// A device (cpu) that can debugged using the built-in debugger
 pub trait Debuggable {
@@ -88,11 +108,31 @@ pub trait Debuggable {
 
     fn print_current_step(&mut self, system: &System) -> Result<(), Error>;
     fn execute_command(&mut self, system: &System, args: &[&str]) -> Result<bool, Error>;
+
+    // Remote debuggers see the registers in the order given by the target description
+    fn get_registers(&mut self) -> Vec<u32> {
+        vec![]
+    }
+
+    fn set_register(&mut self, _index: usize, _value: u32) -> Result<(), Error> {
+        Err(Error::new("debugger: registers can't be written"))
+    }
+
+    fn target_description(&self) -> Option<String> {
+        None
+    }
 }
 
 pub trait Interruptable {
     
 }
+
+/// A device whose state can be saved into a snapshot and restored from it later
+pub trait Snapshotable {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error>;
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error>;
+}
+
 pub trait Transmutable {
     fn as_steppable(&mut self) -> Option<&mut dyn Steppable> {
         None
@@ -109,6 +149,10 @@ pub trait Transmutable {
     fn as_debuggable(&mut self) -> Option<&mut dyn Debuggable> {
         None
     }
+
+    fn as_snapshotable(&mut self) -> Option<&mut dyn Snapshotable> {
+        None
+    }
 }
 
 pub fn wrap_transmutable<T: Transmutable + 'static>(value: T) -> TransmutableBox {
@@ -143,6 +187,12 @@ pub fn read_leu32(data: &[u8]) -> u32 {
     (data[0] as u32)
 }
 
+#[inline(always)]
+pub fn read_beu64(data: &[u8]) -> u64 {
+    (read_beu32(data) as u64) << 32 |
+    (read_beu32(&data[4..]) as u64)
+}
+
 
 
 #[inline(always)]
@@ -2,13 +2,15 @@
 use std::io::Write;
 
 use crate::error::Error;
+use crate::gdb::GdbStub;
 use crate::sys::System;
 use crate::core::{Address, Addressable, Debuggable, TransmutableBox};
 
 
 pub struct Debugger {
     last_command: Option<String>,
-    repeat: u32
+    repeat: u32,
+    gdb: Option<GdbStub>,
 }
 
 
@@ -16,20 +18,40 @@ impl Debugger {
     pub fn new() -> Self {
         Self {
             last_command: None,
-            repeat: 0
+            repeat: 0,
+            gdb: None,
         }
     }
 
+    // A connected gdb takes over the prompt until it detaches
+    pub fn attach_gdb(&mut self, gdb: GdbStub) {
+        self.gdb = Some(gdb);
+    }
+
     pub fn run_debugger(&mut self, system: &System, target: TransmutableBox) -> Result<(), Error> {
         let mut target = target.borrow_mut();
         let debug_obj = target.as_debuggable().unwrap();
-        debug_obj.print_current_step(system)?;
+
+        // The session with gdb ends with an error only when it kills the system
+        if let Some(gdb) = self.gdb.as_mut() {
+            if !gdb.run(system, debug_obj)? {
+                println!("gdb detached");
+                self.gdb = None;
+            }
+            return Ok(());
+        }
+
+        if let Err(err) = debug_obj.print_current_step(system) {
+            println!("Error: {:?}", err);
+        }
 
         if self.repeat > 0 {
             self.repeat -= 1;
             let last_command = self.last_command.clone().unwrap();
             let args: Vec<&str> = vec![&last_command];
-            self.run_debugger_command(system, debug_obj, &args)?;
+            if let Err(err) = self.run_debugger_command(system, debug_obj, &args) {
+                println!("Error: {:?}", err);
+            }
             return Ok(());
         }
 
@@ -59,16 +81,16 @@ impl Debugger {
                 if args.len() != 2 {
                     println!("Usage: breakpoint <addr>");
                 } else {
-                    let (name, addr) = parse_address(args[1])?;
+                    let (name, addr) = parse_address(system, args[1])?;
                     match name {
                         Some(name) => {
                             let target = system.get_device(name)?;
                             target.borrow_mut().as_debuggable().unwrap().add_breakpoint(addr);
-                            println!("Breakpoint set for devices {:?} at {:08x}", name, addr);
+                            println!("Breakpoint set for devices {:?} at {}", name, system.symbols.format_address(addr));
                         },
                         None => {
                             debug_obj.add_breakpoint(addr);
-                            println!("Breakpoint set for {:08x}", addr);
+                            println!("Breakpoint set for {}", system.symbols.format_address(addr));
                         },
                     }
                 }
@@ -77,16 +99,16 @@ impl Debugger {
                 if args.len() != 2 {
                     println!("Usage: remove <addr>");
                 } else {
-                    let (name, addr) = parse_address(args[1])?;
+                    let (name, addr) = parse_address(system, args[1])?;
                     match name {
                         Some(name) => {
                             let target = system.get_device(name)?;
                             target.borrow_mut().as_debuggable().unwrap().remove_breakpoint(addr);
-                            println!("Breakpoint removed for devices {:?} at {:08x}", name, addr);
+                            println!("Breakpoint removed for devices {:?} at {}", name, system.symbols.format_address(addr));
                         },
                         None => {
                             debug_obj.remove_breakpoint(addr);
-                            println!("Breakpoint removed for {:08x}", addr);
+                            println!("Breakpoint removed for {}", system.symbols.format_address(addr));
                         },
                     }
                 }
@@ -95,7 +117,7 @@ impl Debugger {
                 if args.len() != 2 {
                     println!("Usage: watch <addr>");
                 } else {
-                    let addr = Address::from_str_radix(args[1], 16).map_err(|_| Error::new("Unable to parse address"))?;
+                    let addr = system.symbols.parse_address(args[1])?;
                     system.get_bus().add_watcher(addr);
                 }
             },
@@ -103,16 +125,16 @@ impl Debugger {
                 if args.len() != 2 {
                     println!("Usage: remove_watch <addr>");
                 } else {
-                    let addr = Address::from_str_radix(args[1], 16).map_err(|_| Error::new("Unable to parse address"))?;
+                    let addr = system.symbols.parse_address(args[1])?;
                     system.get_bus().remove_watcher(addr);
                 }
             },
 
             "d" | "dump" => {
                 if args.len() > 1 {
-                    let addr = u32::from_str_radix(args[1], 16).map_err(|_| Error::new("Unable to parse address"))?;
+                    let addr = system.symbols.parse_address(args[1])?;
                     let len = if args.len() > 2 { u32::from_str_radix(args[2], 16).map_err(|_| Error::new("Unable to parse length"))? } else { 0x20 };
-                    system.get_bus().dump_memory(addr as Address, len as Address);
+                    system.get_bus().dump_memory_symbols(addr, len as Address, &system.symbols);
                 } else {
                     //self.port.dump_memory(self.state.ssp as Address, 0x40 as Address);
                 }
@@ -130,7 +152,7 @@ impl Debugger {
                 if args.len() != 3 {
                     println!("Usage: set[b|w|l] <addr> <data>");
                 } else {
-                    let addr = u64::from_str_radix(args[1], 16).map_err(|_| Error::new("Unable to parse set address"))?;
+                    let addr = system.symbols.parse_address(args[1])?;
                     let data = u32::from_str_radix(args[2], 16).map_err(|_| Error::new("Unable to parse data"))?;
                     match args[0] {
                         "setb" => system.get_bus().write_u8(addr, data as u8)?,
@@ -158,7 +180,7 @@ impl Debugger {
     }
 }
 
-fn parse_address(arg: &str) -> Result<(Option<&str>, Address), Error> {
+fn parse_address<'a>(system: &System, arg: &'a str) -> Result<(Option<&'a str>, Address), Error> {
     let (name, addrstr) = match arg.find(':') {
         Some(index) => {
             let (name, addrstr) = arg.split_at(index);
@@ -167,7 +189,7 @@ fn parse_address(arg: &str) -> Result<(Option<&str>, Address), Error> {
         None => (None, arg),
     };
 
-    let addr = Address::from_str_radix(addrstr, 16).map_err(|_| Error::new("Unable to parse address"))?;
+    let addr = system.symbols.parse_address(addrstr)?;
     Ok((name, addr))
 }
 
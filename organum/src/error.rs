@@ -5,6 +5,8 @@ pub enum ErrorType {
     Emulator,
     Processor,
     Breakpoint,
+    // Nothing answered on the bus, native holds the address
+    Bus,
 }
 
 #[derive(Debug)]
@@ -39,6 +41,14 @@ impl Error {
         }
     }
 
+    pub fn bus(addr: u64, msg: &str) -> Error {
+        Error {
+            err: ErrorType::Bus,
+            native: addr as u32,
+            msg: msg.to_string(),
+        }
+    }
+
     pub fn assertion(msg: &str) -> Error {
         Error {
             err: ErrorType::Assertion,
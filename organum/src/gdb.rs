@@ -0,0 +1,328 @@
+// gdb.rs implements a stub of the GDB remote serial protocol, so an external debugger
+// can take the place of the built-in prompt when the system stops
+use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+
+use crate::core::{Address, Addressable, Debuggable};
+use crate::error::Error;
+use crate::premade::bus::BusPort;
+use crate::sys::System;
+
+// Addresses from this base up are mapped to the data bus, gdb only knows about one address space
+pub const DATA_SPACE_BASE: Address = 0x8000_0000;
+
+const PACKET_SIZE: usize = 0x1000;
+const SIGTRAP: u8 = 5;
+
+pub struct GdbStub {
+    stream: TcpStream,
+    pub client_addr: SocketAddr,
+    port: BusPort,
+    port_d: BusPort,
+    running: bool,
+    no_ack: bool,
+}
+
+impl GdbStub {
+    // Blocks until gdb connects, with `target remote ip:port`
+    pub fn listen(ip: IpAddr, tcp_port: u16, port: BusPort, port_d: BusPort) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((ip, tcp_port))?;
+        let (stream, addr) = listener.accept()?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            stream,
+            client_addr: addr,
+            port,
+            port_d,
+            running: false,
+            no_ack: false,
+        })
+    }
+
+    // Serves requests until the target is resumed. Returns false if the client went away
+    pub fn run(&mut self, system: &System, debug_obj: &mut dyn Debuggable) -> Result<bool, Error> {
+        if self.running {
+            self.running = false;
+            self.send_packet(&format!("S{:02x}", SIGTRAP))?;
+        }
+
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(false),
+            };
+
+            // Malformed requests get an error reply instead of stopping the session
+            let reply = self.handle_packet(system, debug_obj, &packet).unwrap_or_else(|_| Reply::Packet("E02".to_string()));
+            match reply {
+                Reply::Packet(reply) => {
+                    self.send_packet(&reply)?;
+                    // The reply itself is still acknowledged
+                    if packet == "QStartNoAckMode" {
+                        self.no_ack = true;
+                    }
+                },
+                Reply::Resume => {
+                    self.running = true;
+                    return Ok(true);
+                },
+                Reply::Detach => {
+                    self.send_packet("OK")?;
+                    system.disable_debugging();
+                    return Ok(false);
+                },
+                // Kill gets no reply, the system stops with the connection
+                Reply::Kill => {
+                    return Err(Error::new("gdb: the system was killed by the client"));
+                },
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, system: &System, debug_obj: &mut dyn Debuggable, packet: &str) -> Result<Reply, Error> {
+        let command = match packet.get(..1) {
+            Some(command) => command,
+            None => return Ok(Reply::Packet(String::new())),
+        };
+        let args = &packet[1..];
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => debug_obj.get_registers().iter().map(|reg| hex_encode(&reg.to_be_bytes())).collect(),
+            "G" => {
+                let bytes = hex_decode(args)?;
+                for (index, value) in bytes.chunks_exact(4).enumerate() {
+                    debug_obj.set_register(index, u32::from_be_bytes(value.try_into().unwrap()))?;
+                }
+                "OK".to_string()
+            },
+            "p" => {
+                let index = parse_hex(args)? as usize;
+                match debug_obj.get_registers().get(index) {
+                    Some(reg) => hex_encode(&reg.to_be_bytes()),
+                    None => "E01".to_string(),
+                }
+            },
+            "P" => {
+                let (index, value) = args.split_once('=').ok_or_else(|| Error::new("gdb: malformed P packet"))?;
+                let value = hex_decode(value)?;
+                if value.len() != 4 {
+                    return Ok(Reply::Packet("E01".to_string()));
+                }
+                debug_obj.set_register(parse_hex(index)? as usize, u32::from_be_bytes(value.try_into().unwrap()))?;
+                "OK".to_string()
+            },
+            "m" => {
+                let (addr, len) = parse_pair(args)?;
+                let mut data = vec![0; std::cmp::min(len as usize, PACKET_SIZE / 2)];
+                match self.read_memory(addr, &mut data) {
+                    Ok(()) => hex_encode(&data),
+                    Err(_) => "E01".to_string(),
+                }
+            },
+            "M" => {
+                let (range, data) = args.split_once(':').ok_or_else(|| Error::new("gdb: malformed M packet"))?;
+                let (addr, _) = parse_pair(range)?;
+                match self.write_memory(addr, &hex_decode(data)?) {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => "E01".to_string(),
+                }
+            },
+            "c" => {
+                system.disable_debugging();
+                return Ok(Reply::Resume);
+            },
+            "s" => {
+                debug_obj.set_debugging(true);
+                return Ok(Reply::Resume);
+            },
+            // Software and hardware breakpoints are handled the same way by the cpu
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let kind = fields.next().unwrap_or("");
+                let addr = parse_hex(fields.next().unwrap_or(""))?;
+                match kind {
+                    "0" | "1" if command == "Z" => {
+                        debug_obj.add_breakpoint(addr);
+                        "OK".to_string()
+                    },
+                    "0" | "1" => {
+                        debug_obj.remove_breakpoint(addr);
+                        "OK".to_string()
+                    },
+                    _ => String::new(),
+                }
+            },
+            "H" => "OK".to_string(),
+            "D" => return Ok(Reply::Detach),
+            "k" => return Ok(Reply::Kill),
+            "q" => self.handle_query(debug_obj, packet),
+            "Q" if packet == "QStartNoAckMode" => "OK".to_string(),
+            _ => String::new(),
+        };
+        Ok(Reply::Packet(reply))
+    }
+
+    fn handle_query(&mut self, debug_obj: &mut dyn Debuggable, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE);
+        }
+
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let description = match debug_obj.target_description() {
+                Some(description) => description,
+                None => return "E00".to_string(),
+            };
+            let (offset, len) = match parse_pair(args) {
+                Ok(pair) => pair,
+                Err(_) => return "E00".to_string(),
+            };
+            let offset = std::cmp::min(offset as usize, description.len());
+            let end = std::cmp::min(offset + len as usize, description.len());
+            let marker = if end == description.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, &description[offset..end]);
+        }
+
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn read_memory(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error> {
+        if addr >= DATA_SPACE_BASE {
+            self.port_d.read(addr - DATA_SPACE_BASE, data)
+        } else {
+            self.port.read(addr, data)
+        }
+    }
+
+    fn write_memory(&mut self, addr: Address, data: &[u8]) -> Result<(), Error> {
+        if addr >= DATA_SPACE_BASE {
+            self.port_d.write(addr - DATA_SPACE_BASE, data)
+        } else {
+            self.port.write(addr, data)
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, Error> {
+        let mut buf = [0u8; 1];
+        match self.stream.read(&mut buf) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(buf[0])),
+            Err(e) => Err(Error::new(&format!("gdb: {}", e))),
+        }
+    }
+
+    // Packets have the form `$data#cc`, where cc is the checksum of data
+    fn read_packet(&mut self) -> Result<Option<String>, Error> {
+        loop {
+            let mut byte = match self.read_byte()? {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+            // Acks and interrupt requests outside of a packet are ignored while stopped
+            if byte != b'$' {
+                continue;
+            }
+
+            let mut data = vec![];
+            loop {
+                byte = match self.read_byte()? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+                if byte == b'#' {
+                    break;
+                }
+                data.push(byte);
+            }
+
+            let mut checksum = [0u8; 2];
+            for digit in checksum.iter_mut() {
+                *digit = match self.read_byte()? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+            }
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|cs| u8::from_str_radix(cs, 16).ok());
+
+            if !self.no_ack {
+                let ack = if expected == Some(checksum_of(&data)) { b"+" } else { b"-" };
+                self.write_all(ack)?;
+                if ack == b"-" {
+                    continue;
+                }
+            }
+            return Ok(Some(String::from_utf8_lossy(&unescape(&data)).into_owned()));
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), Error> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        loop {
+            self.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.stream.write_all(data).map_err(|e| Error::new(&format!("gdb: {}", e)))
+    }
+}
+
+enum Reply {
+    Packet(String),
+    Resume,
+    Detach,
+    Kill,
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Bytes prefixed with `}` are escaped by xoring them with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'}' => result.push(iter.next().map(|b| b ^ 0x20).unwrap_or(0)),
+            _ => result.push(*byte),
+        }
+    }
+    result
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(data: &str) -> Result<Vec<u8>, Error> {
+    data.as_bytes().chunks_exact(2)
+        .map(|pair| {
+            std::str::from_utf8(pair).ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| Error::new("gdb: invalid hex data"))
+        })
+        .collect()
+}
+
+fn parse_hex(data: &str) -> Result<Address, Error> {
+    Address::from_str_radix(data, 16).map_err(|_| Error::new(&format!("gdb: invalid number {}", data)))
+}
+
+fn parse_pair(data: &str) -> Result<(Address, Address), Error> {
+    let (first, second) = data.split_once(',').ok_or_else(|| Error::new("gdb: expected two numbers"))?;
+    Ok((parse_hex(first)?, parse_hex(second)?))
+}
@@ -1,20 +1,72 @@
+// interrupts.rs models an interrupt controller with one line per interrupt source. Each line has
+// its own pending, enable and in-service bit, so sources sharing a priority level don't overwrite each other
+use std::cell::RefCell;
+use std::rc::Rc;
 
 use crate::error::Error;
-use crate::core::TransmutableBox;
+use crate::core::{Address, Addressable, Snapshotable, Transmutable, TransmutableBox};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 
+pub const MAX_SOURCES: usize = 32;
+
+/* Guest registers, 32 bit words in big endian with one bit per line:
+    ╭────┬──────────┬─────────────────────────────────────────────────────╮
+    │0x00│PENDING   │writing ones clears pending edge triggered lines     │
+    │0x04│ENABLE    │lines that may interrupt, all of them after reset    │
+    │0x08│IN_SERVICE│acknowledged lines waiting for an end of interrupt   │
+    │0x0c│EOI       │write the vector number of a line to end its service │
+    │0x10│CTRL      │bit 0: auto end of interrupt, set after reset        │
+    │0x14│TRIGGER   │read only, set for level triggered lines             │
+    ╰────┴──────────┴─────────────────────────────────────────────────────╯
+*/
+#[repr(usize)]
+pub enum Register {
+    PENDING = 0x00,
+    ENABLE = 0x04,
+    INSERVICE = 0x08,
+    EOI = 0x0c,
+    CTRL = 0x10,
+    TRIGGER = 0x14,
+}
+
+pub const REGISTER_COUNT: usize = 0x18;
+
+pub const CTRL_AUTO_EOI: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    // Every `set(true, ..)` is an event that stays pending until acknowledged
+    Edge,
+    // Pending for as long as the source holds the line up
+    Level,
+}
+
+struct Source {
+    number: u8,
+    priority: u8,
+    trigger: Trigger,
+}
 
 pub struct InterruptController {
     target: Option<TransmutableBox>,
-    interrupts: Vec<(bool, u8)>,
-    highest: u8,
+    // Indexed by line, lines may be left without a source
+    sources: Vec<Option<Source>>,
+    pending: u32,
+    enabled: u32,
+    in_service: u32,
+    ctrl: u32,
 }
 
 impl InterruptController {
     pub fn new() -> InterruptController {
         InterruptController {
             target: None,
-            interrupts: vec![(false, 0); 7],
-            highest: 0,
+            sources: vec![],
+            pending: 0,
+            enabled: u32::MAX,
+            in_service: 0,
+            // Without auto EOI, guests that don't know about the controller would only be interrupted once
+            ctrl: CTRL_AUTO_EOI,
         }
     }
 
@@ -27,30 +79,216 @@ impl InterruptController {
         Ok(())
     }
 
-    pub fn set(&mut self, state: bool, priority: u8, number: u8) -> Result<(), Error> {
-        self.interrupts[priority as usize].0 = state;
-        self.interrupts[priority as usize].1 = number;
-        if state && priority > self.highest {
-            self.highest = priority;
+    // Sources take the free lines in the order they are added
+    pub fn add_source(&mut self, number: u8, priority: u8, trigger: Trigger) -> Result<(), Error> {
+        let line = self.sources.iter().position(|source| source.is_none()).unwrap_or(self.sources.len());
+        self.add_source_at(line, number, priority, trigger)
+    }
+
+    // Machines that leave out a device can keep the lines of the others where they were
+    pub fn add_source_at(&mut self, line: usize, number: u8, priority: u8, trigger: Trigger) -> Result<(), Error> {
+        if line >= MAX_SOURCES {
+            return Err(Error::new(&format!("interrupts: there is no line {} for vector {:#x}", line, number)));
         }
+        if matches!(self.sources.get(line), Some(Some(_))) {
+            return Err(Error::new(&format!("interrupts: line {} already has a source", line)));
+        }
+        if self.sources.iter().flatten().any(|source| source.number == number) {
+            return Err(Error::new(&format!("interrupts: vector {:#x} already has a source", number)));
+        }
+        if priority == 0 || priority > 7 {
+            return Err(Error::new(&format!("interrupts: invalid priority {} for vector {:#x}", priority, number)));
+        }
+
+        if self.sources.len() <= line {
+            self.sources.resize_with(line + 1, || None);
+        }
+        self.sources[line] = Some(Source { number, priority, trigger });
         Ok(())
     }
 
+    fn lines(&self) -> impl Iterator<Item = (usize, &Source)> {
+        self.sources.iter().enumerate().filter_map(|(line, source)| source.as_ref().map(|source| (line, source)))
+    }
+
+    fn line(&self, number: u8) -> Result<usize, Error> {
+        self.lines().find(|(_, source)| source.number == number).map(|(line, _)| line)
+            .ok_or_else(|| Error::new(&format!("interrupts: no source for vector {:#x}", number)))
+    }
+
+    fn source(&self, line: usize) -> &Source {
+        self.sources[line].as_ref().unwrap()
+    }
+
+    pub fn set(&mut self, state: bool, number: u8) -> Result<(), Error> {
+        let line = self.line(number)?;
+        let bit = 1 << line;
+        match (self.source(line).trigger, state) {
+            (_, true) => self.pending |= bit,
+            (Trigger::Level, false) => self.pending &= !bit,
+            (Trigger::Edge, false) => { },
+        }
+        Ok(())
+    }
+
+    fn deliverable(&self) -> u32 {
+        self.pending & self.enabled & !self.in_service
+    }
+
+    // Highest priority among the lines that can interrupt
     pub fn check(&mut self) -> (bool, u8) {
-        if self.highest > 0 {
-            (true, self.highest)
-        } else {
-            (false, 0)
+        let deliverable = self.deliverable();
+        if deliverable == 0 {
+            return (false, 0);
         }
+
+        let highest = self.lines()
+            .filter(|(line, _)| deliverable & (1 << line) != 0)
+            .map(|(_, source)| source.priority)
+            .max()
+            .unwrap_or(0);
+        (true, highest)
     }
 
+    // Hands out the vector of the first line at that priority, which goes in service until its end of interrupt
     pub fn acknowledge(&mut self, priority: u8) -> Result<u8, Error> {
-        let acknowledge = self.interrupts[priority as usize].1;
-        self.interrupts[priority as usize].0 = false;
-        while self.highest > 0 && !self.interrupts[self.highest as usize].0 {
-            self.highest -= 1;
+        let deliverable = self.deliverable();
+        let line = self.lines()
+            .find(|(line, source)| deliverable & (1 << line) != 0 && source.priority == priority)
+            .map(|(line, _)| line)
+            .ok_or_else(|| Error::new(&format!("interrupts: nothing to acknowledge at priority {}", priority)))?;
+
+        let bit = 1 << line;
+        if self.source(line).trigger == Trigger::Edge {
+            self.pending &= !bit;
+        }
+        if self.ctrl & CTRL_AUTO_EOI == 0 {
+            self.in_service |= bit;
+        }
+        Ok(self.source(line).number)
+    }
+
+    pub fn end_of_interrupt(&mut self, number: u8) -> Result<(), Error> {
+        let line = self.line(number)?;
+        self.in_service &= !(1 << line);
+        Ok(())
+    }
+
+    fn level_lines(&self) -> u32 {
+        self.lines()
+            .filter(|(_, source)| source.trigger == Trigger::Level)
+            .fold(0, |lines, (line, _)| lines | 1 << line)
+    }
+
+    fn read_register(&self, register: usize) -> u32 {
+        match register {
+            r if r == Register::PENDING as usize => self.pending,
+            r if r == Register::ENABLE as usize => self.enabled,
+            r if r == Register::INSERVICE as usize => self.in_service,
+            r if r == Register::CTRL as usize => self.ctrl,
+            r if r == Register::TRIGGER as usize => self.level_lines(),
+            _ => 0,
+        }
+    }
+
+    // Registers that trigger an action on write start from zero on partial writes, instead of their value
+    fn merge_base(&self, register: usize) -> u32 {
+        match register {
+            r if r == Register::ENABLE as usize || r == Register::CTRL as usize => self.read_register(register),
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, register: usize, value: u32) {
+        match register {
+            // Level triggered lines stay pending for as long as they are up
+            r if r == Register::PENDING as usize => self.pending &= !(value & !self.level_lines()),
+            r if r == Register::ENABLE as usize => self.enabled = value,
+            // Vectors without a line have nothing in service
+            r if r == Register::EOI as usize => { let _ = self.end_of_interrupt(value as u8); },
+            r if r == Register::CTRL as usize => self.ctrl = value & CTRL_AUTO_EOI,
+            _ => { },
         }
-        Ok(acknowledge)
     }
 }
 
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshotable for InterruptController {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        snapshot.write_u8(self.sources.len() as u8);
+        snapshot.write_beu32(self.pending);
+        snapshot.write_beu32(self.enabled);
+        snapshot.write_beu32(self.in_service);
+        snapshot.write_beu32(self.ctrl);
+        Ok(())
+    }
+
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        let count = snapshot.read_u8()? as usize;
+        if count != self.sources.len() {
+            return Err(Error::new("snapshot: interrupt controller sources do not match"));
+        }
+        self.pending = snapshot.read_beu32()?;
+        self.enabled = snapshot.read_beu32()?;
+        self.in_service = snapshot.read_beu32()?;
+        self.ctrl = snapshot.read_beu32()?;
+        Ok(())
+    }
+}
+
+// Maps the registers of the system's interrupt controller into a bus
+pub struct InterruptRegisters {
+    controller: Rc<RefCell<InterruptController>>,
+}
+
+impl InterruptRegisters {
+    pub fn new(controller: Rc<RefCell<InterruptController>>) -> Self {
+        Self {
+            controller,
+        }
+    }
+}
+
+impl Addressable for InterruptRegisters {
+    fn len(&self) -> usize {
+        REGISTER_COUNT
+    }
+
+    fn read(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error> {
+        let controller = self.controller.borrow();
+        for (i, byte) in data.iter_mut().enumerate() {
+            let addr = addr as usize + i;
+            let register = addr & !3;
+            *byte = controller.read_register(register).to_be_bytes()[addr & 3];
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: Address, data: &[u8]) -> Result<(), Error> {
+        let mut controller = self.controller.borrow_mut();
+        let start = addr as usize & !3;
+        let end = addr as usize + data.len();
+        for register in (start..end).step_by(4) {
+            let mut value = controller.merge_base(register).to_be_bytes();
+            for (i, byte) in value.iter_mut().enumerate() {
+                let offset = register + i;
+                if offset >= addr as usize && offset < end {
+                    *byte = data[offset - addr as usize];
+                }
+            }
+            controller.write_register(register, u32::from_be_bytes(value));
+        }
+        Ok(())
+    }
+}
+
+impl Transmutable for InterruptRegisters {
+    fn as_addressable(&mut self) -> Option<&mut dyn Addressable> {
+        Some(self)
+    }
+}
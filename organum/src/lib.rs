@@ -4,10 +4,15 @@ and general interfaces to model and tailor pseudo-hardware modules
 for simple emulators. The code is mostly based on this article: 
 https://dev.to/transistorfet/making-a-68000-emulator-in-rust-1kfk */
 
+pub mod backend;
 pub mod core;
 pub mod debugger;
 pub mod error;
+pub mod gdb;
 pub mod interrupts;
 pub mod premade;
+pub mod scheduler;
 pub mod server;
+pub mod snapshot;
+pub mod symbols;
 pub mod sys;
\ No newline at end of file
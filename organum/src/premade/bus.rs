@@ -5,6 +5,7 @@ use std::cell::RefCell;
 
 use crate::core::*;
 use crate::error::Error;
+use crate::symbols::SymbolTable;
 
 #[derive(Clone)]
 pub struct Block {
@@ -13,9 +14,24 @@ pub struct Block {
     pub dev: TransmutableBox,
 }
 
+// Addresses are looked up one page at a time, blocks that cover a whole page are found straight away
+pub const PAGE_BITS: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+// Past 4 GiB of pages, or on buses that go further, the blocks are searched instead
+const MAX_PAGES: usize = 1 << 20;
+
+#[derive(Clone, Copy)]
+enum Page {
+    Unmapped,
+    Block(u32),
+    // Shared by several blocks, or only mapped in part
+    Shared,
+}
+
 #[derive(Clone)]
 pub struct Bus {
     blocks: Vec<Block>,
+    pages: Vec<Page>,
     ignore_unmapped: bool,
     watchers: Vec<Address>,
     watcher_modified: bool,
@@ -26,6 +42,7 @@ impl Bus {
         Bus {
             ignore_unmapped: false,
             blocks: vec!(),
+            pages: vec!(),
             watchers: vec!(),
             watcher_modified: false,
         }
@@ -37,6 +54,7 @@ impl Bus {
 
     pub fn clear_all_bus_devices(&mut self) {
         self.blocks.clear();
+        self.pages.clear();
     }
 
     pub fn insert(&mut self, base: Address, dev: TransmutableBox) {
@@ -44,25 +62,128 @@ impl Bus {
         let block = Block { base, length, dev };
         let i = self.blocks.iter().position(|cur| cur.base > block.base).unwrap_or(self.blocks.len());
         self.blocks.insert(i, block);
+        self.build_pages();
+    }
+
+    fn build_pages(&mut self) {
+        let end = self.blocks.iter().map(|block| block.base as usize + block.length).max().unwrap_or(0);
+        let count = std::cmp::min((end + PAGE_SIZE - 1) >> PAGE_BITS, MAX_PAGES);
+        self.pages = vec![Page::Unmapped; count];
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            let start = block.base as usize;
+            let end = start + block.length;
+            let first = start >> PAGE_BITS;
+            let last = std::cmp::min((end + PAGE_SIZE - 1) >> PAGE_BITS, count);
+            for page in first..last {
+                let page_start = page << PAGE_BITS;
+                let whole = start <= page_start && end >= page_start + PAGE_SIZE;
+                self.pages[page] = match self.pages[page] {
+                    Page::Unmapped if whole => Page::Block(index as u32),
+                    _ => Page::Shared,
+                };
+            }
+        }
+    }
+
+    // Index of the block that answers for the address, the first one when blocks overlap
+    fn find_block(&self, addr: Address) -> Option<usize> {
+        match self.pages.get((addr >> PAGE_BITS) as usize) {
+            Some(Page::Block(index)) => Some(*index as usize),
+            Some(Page::Unmapped) => None,
+            Some(Page::Shared) | None => self.blocks.iter()
+                .position(|block| addr >= block.base && addr < block.base + block.length as Address),
+        }
     }
 
     pub fn get_device_at(&self, addr: Address, count: usize) -> Result<(TransmutableBox, Address), Error> {
-        for block in &self.blocks {
-            if addr >= block.base && addr < (block.base + block.length as Address) {
-                let relative_addr = addr - block.base;
-                if relative_addr as usize + count <= block.length {
-                    return Ok((block.dev.clone(), relative_addr));
-                } else {
-                    return Err(Error::new(&format!("Error reading address {:#010x}", addr)));
-                }
+        let block = match self.find_block(addr) {
+            Some(index) => &self.blocks[index],
+            None => return Err(Error::bus(addr, &format!("No segment found at {:#010x}", addr))),
+        };
+        let relative_addr = addr - block.base;
+        if relative_addr as usize + count <= block.length {
+            Ok((block.dev.clone(), relative_addr))
+        } else {
+            Err(Error::bus(addr, &format!("Error reading address {:#010x}", addr)))
+        }
+    }
+
+    fn unmapped(&self, addr: Address) -> Result<(), Error> {
+        let err = Error::bus(addr, &format!("No segment found at {:#010x}", addr));
+        if self.ignore_unmapped {
+            println!("{:?}", err);
+            return Ok(());
+        }
+        Err(err)
+    }
+
+    // Reads across as many blocks as the transfer covers. Memory is copied from its contents in one go,
+    // other devices are handed at most `width` bytes at a time, as if the bus were that wide
+    pub fn read_split(&mut self, mut addr: Address, data: &mut [u8], width: usize) -> Result<(), Error> {
+        let mut done = 0;
+        while done < data.len() {
+            let block = match self.find_block(addr) {
+                Some(index) => &self.blocks[index],
+                None => return self.unmapped(addr),
+            };
+            let offset = (addr - block.base) as usize;
+            let count = std::cmp::min(data.len() - done, block.length - offset);
+            let part = &mut data[done..done + count];
+
+            let mut dev = block.dev.borrow_mut();
+            let dev = dev.as_addressable().unwrap();
+            match dev.contents() {
+                Some(contents) => part.copy_from_slice(&contents[offset..offset + count]),
+                None => for i in (0..count).step_by(width) {
+                    let end = std::cmp::min(i + width, count);
+                    dev.read((offset + i) as Address, &mut part[i..end])?;
+                },
+            }
+            done += count;
+            addr += count as Address;
+        }
+        Ok(())
+    }
+
+    pub fn write_split(&mut self, mut addr: Address, data: &[u8], width: usize) -> Result<(), Error> {
+        if self.watchers.contains(&addr) {
+            println!("watch: writing to address {:#06x} with {:?}", addr, data);
+            self.watcher_modified = true;
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let block = match self.find_block(addr) {
+                Some(index) => &self.blocks[index],
+                None => return self.unmapped(addr),
+            };
+            let offset = (addr - block.base) as usize;
+            let count = std::cmp::min(data.len() - done, block.length - offset);
+            let part = &data[done..done + count];
+
+            let mut dev = block.dev.borrow_mut();
+            let dev = dev.as_addressable().unwrap();
+            match dev.contents_mut() {
+                Some(contents) => contents[offset..offset + count].copy_from_slice(part),
+                None => for i in (0..count).step_by(width) {
+                    let end = std::cmp::min(i + width, count);
+                    dev.write((offset + i) as Address, &part[i..end])?;
+                },
             }
+            done += count;
+            addr += count as Address;
         }
-        return Err(Error::new(&format!("No segment found at {:#010x}", addr)));
+        Ok(())
+    }
+
+    pub fn dump_memory(&mut self, addr: Address, count: Address) {
+        self.dump_memory_symbols(addr, count, &SymbolTable::new())
     }
 
-    pub fn dump_memory(&mut self, mut addr: Address, mut count: Address) {
+    pub fn dump_memory_symbols(&mut self, mut addr: Address, mut count: Address, symbols: &SymbolTable) {
         while count > 0 {
-            let mut line = format!("{:#010x}: ", addr);
+            let mut line = format!("{}: ", symbols.format_address(addr));
 
             let to = if count < 16 { count / 2 } else { 8 };
             for _ in 0..to {
@@ -104,34 +225,11 @@ impl Addressable for Bus {
     }
 
     fn read(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error> {
-        let (dev, relative_addr) = match self.get_device_at(addr, data.len()) {
-            Ok(result) => result,
-            Err(err) if self.ignore_unmapped => {
-                println!("{:?}", err);
-                return Ok(())
-            },
-            Err(err) => return Err(err),
-        };
-        let result = dev.borrow_mut().as_addressable().unwrap().read(relative_addr, data);
-        result
+        self.read_split(addr, data, usize::MAX)
     }
 
     fn write(&mut self, addr: Address, data: &[u8]) -> Result<(), Error> {
-        if let Some(_) = self.watchers.iter().position(|a| *a == addr) {
-            println!("watch: writing to address {:#06x} with {:?}", addr, data);
-            self.watcher_modified = true;
-        }
-
-        let (dev, relative_addr) = match self.get_device_at(addr, data.len()) {
-            Ok(result) => result,
-            Err(err) if self.ignore_unmapped => {
-                println!("{:?}", err);
-                return Ok(())
-            },
-            Err(err) => return Err(err),
-        };
-        let result = dev.borrow_mut().as_addressable().unwrap().write(relative_addr, data);
-        result
+        self.write_split(addr, data, usize::MAX)
     }
 }
 
@@ -162,6 +260,10 @@ impl BusPort { //TODO: creo que puedo usar esto para los perifericoes en vez de
         self.subdevice.borrow_mut().dump_memory(self.offset + (addr & self.address_mask), count)
     }
 
+    pub fn dump_memory_symbols(&mut self, addr: Address, count: Address, symbols: &SymbolTable) {
+        self.subdevice.borrow_mut().dump_memory_symbols(self.offset + (addr & self.address_mask), count, symbols)
+    }
+
     pub fn address_mask(&self) -> Address {
         self.address_mask
     }
@@ -176,24 +278,15 @@ impl Addressable for BusPort {
         self.subdevice.borrow().len()
     }
 
+    // Transfers go through whole, only devices other than memory see them split to the width of the port
     fn read(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error> {
         let addr = self.offset + (addr & self.address_mask);
-        let mut subdevice = self.subdevice.borrow_mut();
-        for i in (0..data.len()).step_by(self.data_width as usize) {
-            let end = std::cmp::min(i + self.data_width as usize, data.len());
-            subdevice.read(addr + i as Address, &mut data[i..end])?;
-        }
-        Ok(())
+        self.subdevice.borrow_mut().read_split(addr, data, self.data_width as usize)
     }
 
     fn write(&mut self, addr: Address, data: &[u8]) -> Result<(), Error> {
         let addr = self.offset + (addr & self.address_mask);
-        let mut subdevice = self.subdevice.borrow_mut();
-        for i in (0..data.len()).step_by(self.data_width as usize) {
-            let end = std::cmp::min(i + self.data_width as usize, data.len());
-            subdevice.write(addr + i as Address, &data[i..end])?;
-        }
-        Ok(())
+        self.subdevice.borrow_mut().write_split(addr, data, self.data_width as usize)
     }
 }
 
@@ -1,6 +1,7 @@
 // memory.rs provides simple abstractions for byte-addressable memory
 use crate::core::*;
 use crate::error::Error;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 use std::fs;
 use std::io;
 use std::io::Read;
@@ -36,9 +37,7 @@ impl MemoryBlock {
     pub fn load_at(&mut self, addr: Address, filename: &str) -> Result<(), Error> {
         match fs::read(filename) {
             Ok(contents) => {
-                for i in 0..contents.len() {
-                    self.contents[(addr as usize) + i] = contents[i];
-                }
+                self.contents[addr as usize..addr as usize + contents.len()].copy_from_slice(&contents);
                 Ok(())
             },
             Err(_) => Err(Error::new(&format!("Error reading contents of {}", filename))),
@@ -60,9 +59,7 @@ impl Addressable for MemoryBlock {
     }
 
     fn read(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error> {
-        for i in 0..data.len() {
-            data[i] = self.contents[(addr as usize) + i];
-        }
+        data.copy_from_slice(&self.contents[addr as usize..addr as usize + data.len()]);
         Ok(())
     }
 
@@ -71,15 +68,41 @@ impl Addressable for MemoryBlock {
             return Err(Error::breakpoint(&format!("Attempt to write to read-only memory at {:x} with data {:?}", addr, data)));
         }
 
-        for i in 0..data.len() {
-            self.contents[(addr as usize) + i] = data[i];
+        self.contents[addr as usize..addr as usize + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn contents(&self) -> Option<&[u8]> {
+        Some(&self.contents)
+    }
+
+    fn contents_mut(&mut self) -> Option<&mut [u8]> {
+        if self.read_only {
+            return None;
         }
+        Some(&mut self.contents)
+    }
+}
+
+impl Snapshotable for MemoryBlock {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        snapshot.write_bool(self.read_only);
+        snapshot.write_bytes(&self.contents);
         Ok(())
     }
+
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        self.read_only = snapshot.read_bool()?;
+        snapshot.read_into(&mut self.contents)
+    }
 }
 
 impl Transmutable for MemoryBlock {
     fn as_addressable(&mut self) -> Option<&mut dyn Addressable> {
         Some(self)
     }
+
+    fn as_snapshotable(&mut self) -> Option<&mut dyn Snapshotable> {
+        Some(self)
+    }
 }
\ No newline at end of file
@@ -1,25 +1,41 @@
-// Serial provides a simple generic serial interface
- 
-use crate::core::{Transmutable, Address, Addressable, wrap_transmutable};
-use crate::premade::{memory::MemoryBlock, bus::Block};
+// Serial provides a simple generic serial interface, modeled on a UART with small fifos on both sides.
+// Bytes go over the line at the configured bit rate, 8 data bits framed by a start and a stop bit.
+// Registers are named from the cpu's point of view: it reads from RX and writes to TX
+
+use std::collections::VecDeque;
+
+use crate::core::{Transmutable, Address, Addressable, ClockElapsed, Snapshotable};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 use crate::error::Error;
 
-#[derive(Clone)]
+pub const FIFO_SIZE: usize = 16;
+// Start, data and stop bits
+pub const BITS_PER_BYTE: u64 = 10;
+
+#[derive(Clone, Debug)]
 pub struct Serial {
-    pub mem: Block,
-    pub tx_buffer: Vec<u8>,
-    pub rx_buffer: Vec<u8>,
-    pub transmitting: bool,
-    pub receiving: bool,
-    pub busy: bool,
-    pub frequency: u64
+    pub base: Address,
+    pub baud: u32,
+    // Received from the line, waiting for the cpu
+    pub rx_fifo: VecDeque<u8>,
+    // Written by the cpu, waiting for the line
+    pub tx_fifo: VecDeque<u8>,
+    // The byte going over the line right now
+    pub shifting: Option<u8>,
+    // Only the sticky bits, the others follow the fifos
+    pub status: u8,
+    pub ctrl: u8,
 }
 
 #[repr(usize)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum Register {
-    TX = 0x00,
-    RX = 0x02,
+    // Reads take the oldest received byte, 0 when there is none
+    RX = 0x00,
+    RXCOUNT = 0x01,
+    // Writes queue a byte to send
+    TX = 0x02,
+    TXCOUNT = 0x03,
     STATUS = 0x04,
     CTRL = 0x05,
 }
@@ -29,89 +45,152 @@ pub const REGISTER_COUNT: usize = 6;
 #[repr(u8)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum Flag {
-    BUSY  = 1<<0,
-    DONE = 1<<1,
+    // Sending, or there is something left to send
+    BUSY = 1<<0,
+    // Everything written has gone over the line
+    TXEMPTY = 1<<1,
+    RXREADY = 1<<2,
+    TXFULL = 1<<3,
+    // A byte arrived with the rx fifo full and was lost. Sticky, writing a one to it clears it
+    RXOVERRUN = 1<<4,
+    // A byte was written with the tx fifo full and was lost. Sticky as well
+    TXOVERRUN = 1<<5,
+}
+
+const STICKY: u8 = Flag::RXOVERRUN as u8 | Flag::TXOVERRUN as u8;
+
+#[repr(u8)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ctrl {
+    // Interrupt when a byte is received
+    RXINT = 1<<0,
+    // Interrupt when the last byte in the tx fifo has been sent
+    TXINT = 1<<1,
 }
 
 
 impl Serial {
-    pub fn new(base: Address, frequency: u64) -> Self {
-        let dev = MemoryBlock::new(vec![0u8;  REGISTER_COUNT]);
-        Self { 
-            mem: Block {
-                base,
-                length: dev.len(),
-                dev: wrap_transmutable(dev)
-            },
-            tx_buffer: Vec::new(),
-            rx_buffer: Vec::new(),
-            transmitting: false,
-            receiving: false,
-            busy: false,
-            frequency
+    pub fn new(base: Address, baud: u32) -> Self {
+        Self {
+            base,
+            baud,
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            tx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            shifting: None,
+            status: 0,
+            ctrl: 0,
         }
     }
 
-    pub fn tx(&mut self) -> Result<u8, Error> {
-            match self.tx_buffer.pop() {
-                Some(byte) => {
-                    Ok(byte)
-                }
-                None => {
-                    Ok(0)
-                }
+    // How long a byte takes to go over the line, in ns
+    pub fn byte_time(&self) -> ClockElapsed {
+        BITS_PER_BYTE * 1_000_000_000 / self.baud as u64
+    }
+
+    // A byte arrives from the line, returns false if it was lost to an overrun
+    pub fn receive(&mut self, byte: u8) -> bool {
+        if self.rx_fifo.len() >= FIFO_SIZE {
+            self.status |= Flag::RXOVERRUN as u8;
+            return false;
         }
+        self.rx_fifo.push_back(byte);
+        true
     }
 
-    pub fn rx(&mut self) -> Result<bool, Error> {
-        let byte = self.read_u8(Register::RX as Address)?;
-        self.rx_buffer.push(byte);
-        self.write_u8(Register::RX as Address, 0)?;
+    // Called once per byte time: the byte on the line is done and the next one in the fifo starts
+    pub fn shift(&mut self) -> Option<u8> {
+        let sent = self.shifting.take();
+        self.shifting = self.tx_fifo.pop_front();
+        sent
+    }
 
-        Ok(byte == 0)
+    pub fn is_busy(&self) -> bool {
+        self.shifting.is_some() || !self.tx_fifo.is_empty()
     }
 
-    pub fn is_busy(&mut self) -> Result<bool, Error> {
-        Ok(self.read_u8(Register::STATUS as Address)? & Flag::BUSY as u8 != 0)
+    pub fn interrupt_enabled(&self, ctrl: Ctrl) -> bool {
+        self.ctrl & ctrl as u8 != 0
     }
 
-    pub fn set_done(&mut self) -> Result<(), Error> {
-        let stat = self.read_u8(Register::STATUS as Address)?;
-        self.write_u8(Register::STATUS as Address, stat | Flag::DONE as u8)?;
-        Ok(())
+    pub fn status(&self) -> u8 {
+        let mut status = self.status;
+        if self.is_busy() {
+            status |= Flag::BUSY as u8;
+        } else {
+            status |= Flag::TXEMPTY as u8;
+        }
+        if !self.rx_fifo.is_empty() {
+            status |= Flag::RXREADY as u8;
+        }
+        if self.tx_fifo.len() >= FIFO_SIZE {
+            status |= Flag::TXFULL as u8;
+        }
+        status
     }
 
+    fn transmit(&mut self, byte: u8) {
+        if self.tx_fifo.len() >= FIFO_SIZE {
+            self.status |= Flag::TXOVERRUN as u8;
+            return;
+        }
+        self.tx_fifo.push_back(byte);
+    }
 }
 
 impl Addressable for Serial {
     fn len(&self) -> usize {
-        self.mem.dev.borrow_mut().as_addressable().unwrap().len()
+        REGISTER_COUNT
     }
 
     fn read(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error> {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = match addr as usize + i {
+                reg if reg == Register::RX as usize => self.rx_fifo.pop_front().unwrap_or(0),
+                reg if reg == Register::RXCOUNT as usize => self.rx_fifo.len() as u8,
+                reg if reg == Register::TX as usize => 0,
+                reg if reg == Register::TXCOUNT as usize => self.tx_fifo.len() as u8,
+                reg if reg == Register::STATUS as usize => self.status(),
+                reg if reg == Register::CTRL as usize => self.ctrl,
+                _ => return Err(Error::new(&format!("serial: no register at {:#x}", addr as usize + i))),
+            };
+        }
+        Ok(())
+    }
 
-        for i in 0..data.len() {
-            let addr = addr + i as Address;
-            match addr {
-                0 => {
-                    let b = self.tx()?;
-                    data[i] = b;
-                }
-                1 => {
-                    let b = self.tx_buffer.len() as u8;
-                    data[i] = b;
-                }
-                _ => data[i] = self.mem.dev.borrow_mut().as_addressable().unwrap().read_u8(addr)?
+    // The counts are read only
+    fn write(&mut self, addr: Address, data: &[u8]) -> Result<(), Error> {
+        for (i, byte) in data.iter().enumerate() {
+            match addr as usize + i {
+                reg if reg == Register::TX as usize => self.transmit(*byte),
+                reg if reg == Register::STATUS as usize => self.status &= !(byte & STICKY),
+                reg if reg == Register::CTRL as usize => self.ctrl = *byte,
+                reg if reg < REGISTER_COUNT => { },
+                _ => return Err(Error::new(&format!("serial: no register at {:#x}", addr as usize + i))),
             }
         }
-
         Ok(())
+    }
+}
 
+impl Snapshotable for Serial {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        snapshot.write_bytes(self.rx_fifo.make_contiguous());
+        snapshot.write_bytes(self.tx_fifo.make_contiguous());
+        snapshot.write_bool(self.shifting.is_some());
+        snapshot.write_u8(self.shifting.unwrap_or(0));
+        snapshot.write_u8(self.status);
+        snapshot.write_u8(self.ctrl);
+        Ok(())
     }
 
-    fn write(&mut self, addr:  Address, data: &[u8]) -> Result<(), Error> {
-        self.mem.dev.borrow_mut().as_addressable().unwrap()
-        .write(addr, data)?;
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        self.rx_fifo = snapshot.read_bytes()?.iter().copied().collect();
+        self.tx_fifo = snapshot.read_bytes()?.iter().copied().collect();
+        let shifting = snapshot.read_bool()?;
+        let byte = snapshot.read_u8()?;
+        self.shifting = shifting.then_some(byte);
+        self.status = snapshot.read_u8()? & STICKY;
+        self.ctrl = snapshot.read_u8()?;
         Ok(())
     }
 }
@@ -120,4 +199,8 @@ impl Transmutable for Serial {
     fn as_addressable(&mut self) -> Option<&mut dyn Addressable> {
         Some(self)
     }
-}
\ No newline at end of file
+
+    fn as_snapshotable(&mut self) -> Option<&mut dyn Snapshotable> {
+        Some(self)
+    }
+}
@@ -0,0 +1,155 @@
+// scheduler.rs decides which device steps next. Devices run in order of their next clock,
+// and devices due at the same time run in the order they were added to the system
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+use crate::core::{Clock, TransmutableBox};
+
+/// Handed out by a device that sleeps, so whoever has a copy can make it step again.
+/// Devices usually keep one for themselves and wake up when one of their registers is written
+#[derive(Clone, Default)]
+pub struct Waker {
+    woken: Rc<Cell<bool>>,
+}
+
+impl Waker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn wake(&self) {
+        self.woken.set(true);
+    }
+
+    fn take(&self) -> bool {
+        self.woken.replace(false)
+    }
+}
+
+struct Scheduled {
+    device: TransmutableBox,
+    next_clock: Clock,
+    waker: Option<Waker>,
+    asleep: bool,
+}
+
+pub struct Scheduler {
+    devices: Vec<Scheduled>,
+    // Entries are (next clock, device index), the smallest pops first
+    queue: BinaryHeap<Reverse<(Clock, usize)>>,
+    sleeping: usize,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            devices: vec![],
+            queue: BinaryHeap::new(),
+            sleeping: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn add(&mut self, device: TransmutableBox, waker: Option<Waker>) {
+        let index = self.devices.len();
+        self.devices.push(Scheduled {
+            device,
+            next_clock: 0,
+            waker,
+            asleep: false,
+        });
+        self.queue.push(Reverse((0, index)));
+    }
+
+    pub fn device(&self, index: usize) -> &TransmutableBox {
+        &self.devices[index].device
+    }
+
+    // The device that will step next, without taking it out of the queue
+    pub fn peek(&self) -> Option<&TransmutableBox> {
+        self.queue.peek().map(|Reverse((_, index))| self.device(*index))
+    }
+
+    // Index and clock of the device due next. It stays at the top of the queue until it's rescheduled
+    pub fn next(&self) -> Option<(usize, Clock)> {
+        self.queue.peek().map(|Reverse((clock, index))| (*index, *clock))
+    }
+
+    // Moves the device due next to a later clock, replacing the top of the queue saves a push and a pop
+    pub fn reschedule_next(&mut self, clock: Clock) {
+        if let Some(mut top) = self.queue.peek_mut() {
+            let Reverse((_, index)) = *top;
+            self.devices[index].next_clock = clock;
+            *top = Reverse((clock, index));
+        }
+    }
+
+    // The device due next is left out of the queue until its waker is used, devices without one can't sleep
+    pub fn sleep_next(&mut self) -> bool {
+        let index = match self.queue.peek() {
+            Some(Reverse((_, index))) if self.devices[*index].waker.is_some() => *index,
+            _ => return false,
+        };
+        self.queue.pop();
+        self.devices[index].asleep = true;
+        self.sleeping += 1;
+        true
+    }
+
+    fn schedule(&mut self, index: usize, clock: Clock) {
+        self.devices[index].next_clock = clock;
+        self.queue.push(Reverse((clock, index)));
+    }
+
+    // Queues the devices woken since the last call, they step at the current clock
+    pub fn wake_pending(&mut self, clock: Clock) {
+        if self.sleeping == 0 {
+            return;
+        }
+        for index in 0..self.devices.len() {
+            let entry = &mut self.devices[index];
+            if entry.asleep && entry.waker.as_ref().map(|waker| waker.take()).unwrap_or(false) {
+                entry.asleep = false;
+                self.sleeping -= 1;
+                self.schedule(index, clock);
+            }
+        }
+    }
+
+    // Next clock of every device in order, None for the ones that are asleep
+    pub fn clocks(&self) -> impl Iterator<Item = (&TransmutableBox, Option<Clock>)> {
+        self.devices.iter().map(|entry| (&entry.device, if entry.asleep { None } else { Some(entry.next_clock) }))
+    }
+
+    pub fn restore(&mut self, clocks: &[Option<Clock>]) {
+        self.queue.clear();
+        self.sleeping = 0;
+        for (index, clock) in clocks.iter().enumerate() {
+            match clock {
+                Some(clock) => {
+                    self.devices[index].asleep = false;
+                    self.schedule(index, *clock);
+                },
+                None => {
+                    self.devices[index].asleep = true;
+                    self.sleeping += 1;
+                },
+            }
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
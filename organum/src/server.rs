@@ -1,70 +1,156 @@
-// A very simple TCP server that should allow to implement comunications 
-// between the emulator and other devices such as tty's or debuggers
+// A very simple TCP server that should allow to implement comunications
+// between the emulator and other devices such as tty's or debuggers.
+// Clients are accepted in the background, so the machine runs whether one is connected or not,
+// and a client that goes away can be replaced by a new one
 
+use std::collections::VecDeque;
 use std::net::{TcpListener, TcpStream, IpAddr, SocketAddr};
 use std::sync::mpsc::{Sender, Receiver};
+use std::sync::{Arc, Mutex};
 use std::{thread, sync::mpsc, io::prelude::*};
 
+// At most this many bytes wait for a client, the oldest ones are dropped first
+pub const MAX_PENDING: usize = 64 * 1024;
+
+// What happens to the data sent while there is no client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disconnected {
+    // Kept until a client connects, up to MAX_PENDING bytes
+    Buffer,
+    Drop,
+}
+
 #[derive(Debug)]
 pub struct Server {
-    pub sender: Sender<[u8; 1]>,
     pub receiver: Receiver<[u8; 1]>,
-    pub to_client:   TcpStream,
-    pub client_addr: SocketAddr,
     pub ip: IpAddr,
     pub port: u16,
-
+    pub disconnected: Disconnected,
+    client: Arc<Mutex<Option<(TcpStream, SocketAddr)>>>,
+    pending: VecDeque<u8>,
 }
 
 
 impl Server {
-    pub fn new(ip: IpAddr, port: u16) -> std::io::Result<Self> {
+    pub fn new(ip: IpAddr, port: u16, disconnected: Disconnected) -> std::io::Result<Self> {
         let listener = TcpListener::bind((ip, port))?;
-        let (stream, addr) = listener.accept()?;
-
         let (sender, receiver) = mpsc::channel();
+        let client = Arc::new(Mutex::new(None));
+
+        let accepted = client.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("[SERVER] Accept Error: {}", e);
+                        continue
+                    },
+                };
+                let addr = match stream.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(_) => continue,
+                };
+                let from_client = match stream.try_clone() {
+                    Ok(from_client) => from_client,
+                    Err(_) => continue,
+                };
+
+                // A new client takes the place of the previous one
+                println!("[SERVER] CLIENT {} connected.", addr);
+                *accepted.lock().unwrap() = Some((stream, addr));
+                Self::read_client(from_client, sender.clone(), accepted.clone(), addr);
+            }
+        });
 
         Ok(Self {
-            sender: sender,
-            receiver: receiver,
-            to_client: stream,
-            client_addr: addr,
-            ip: ip,
-            port: port
+            receiver,
+            ip,
+            port,
+            disconnected,
+            client,
+            pending: VecDeque::new(),
         })
     }
 
-    pub fn run(&mut self) {
-        let mut from_client = self.to_client.try_clone().unwrap();
-        let sender = self.sender.clone();
-
-        thread::spawn( move || {
+    // Forwards what the client sends, until it closes the connection
+    fn read_client(mut from_client: TcpStream, sender: Sender<[u8; 1]>, client: Arc<Mutex<Option<(TcpStream, SocketAddr)>>>, addr: SocketAddr) {
+        thread::spawn(move || {
             loop {
                 let mut buf = [0u8; 1];
                 match from_client.read(&mut buf) {
                     Ok(0) => {
-                        drop(sender);
                         println!("[SERVER] CLIENT closed connection."); // TODO: change println for logs
                         break
                     },
-    
+
                     Ok(_) => {
-                        match sender.send(buf) {
-                            Ok(_) => continue,
-                            Err(e) => { 
-                                println!("Stream Error: {}", e);
-                                break
-                            }
+                        if sender.send(buf).is_err() {
+                            break
                         }
                     },
-    
-                    Err(e) => { 
+
+                    Err(e) => {
                         println!("Stream Error: {}", e);
                         break
                     }
                 }
             }
-            
+
+            // Unless another client has already taken its place
+            let mut client = client.lock().unwrap();
+            if matches!(client.as_ref(), Some((_, current)) if *current == addr) {
+                *client = None;
+            }
         });
     }
-}
\ No newline at end of file
+
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.client.lock().unwrap().as_ref().map(|(_, addr)| *addr)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.lock().unwrap().is_some()
+    }
+
+    // Sends to the client if there is one. Failing to write drops the client, and the data is
+    // treated as if nobody had been connected
+    pub fn send(&mut self, data: &[u8]) {
+        match self.disconnected {
+            Disconnected::Buffer => {
+                self.pending.extend(data);
+                let excess = self.pending.len().saturating_sub(MAX_PENDING);
+                self.pending.drain(..excess);
+            },
+            Disconnected::Drop => {
+                if !self.is_connected() {
+                    return;
+                }
+                self.pending.extend(data);
+            },
+        }
+        self.flush();
+    }
+
+    // Writes out whatever is waiting for a client, when there is one
+    pub fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let mut client = self.client.lock().unwrap();
+        if let Some((stream, addr)) = client.as_mut() {
+            let (first, second) = self.pending.as_slices();
+            match stream.write_all(first).and_then(|_| stream.write_all(second)) {
+                Ok(_) => self.pending.clear(),
+                Err(e) => {
+                    println!("[SERVER] Error sending data to {}: {}", addr, e);
+                    *client = None;
+                    if self.disconnected == Disconnected::Drop {
+                        self.pending.clear();
+                    }
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+// snapshot.rs provides a simple binary format to freeze and restore the state of a system
+use crate::core::{read_beu16, read_beu32, read_beu64};
+use crate::error::Error;
+
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_beu16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_beu32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_beu64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // Variable length data is prefixed with its length
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.write_beu64(data.len() as u64);
+        self.data.extend_from_slice(data);
+    }
+
+    pub fn write_str(&mut self, value: &str) {
+        self.write_bytes(value.as_bytes());
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for SnapshotWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() - self.pos < count {
+            return Err(Error::new(&format!("snapshot: unexpected end of data at {}", self.pos)));
+        }
+        let slice = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_beu16(&mut self) -> Result<u16, Error> {
+        Ok(read_beu16(self.take(2)?))
+    }
+
+    pub fn read_beu32(&mut self) -> Result<u32, Error> {
+        Ok(read_beu32(self.take(4)?))
+    }
+
+    pub fn read_beu64(&mut self) -> Result<u64, Error> {
+        Ok(read_beu64(self.take(8)?))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], Error> {
+        let count = self.read_beu64()? as usize;
+        self.take(count)
+    }
+
+    // Reads variable length data into a buffer that must be of the same length
+    pub fn read_into(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != data.len() {
+            return Err(Error::new(&format!("snapshot: expected {} bytes, found {}", data.len(), bytes.len())));
+        }
+        data.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn read_str(&mut self) -> Result<String, Error> {
+        let bytes = self.read_bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::new("snapshot: invalid string"))
+    }
+}
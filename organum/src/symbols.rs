@@ -0,0 +1,125 @@
+// symbols.rs loads the symbol files produced by the assembler so addresses can be shown and entered as labels
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use crate::core::Address;
+use crate::error::Error;
+
+// Labels further away than this are unlikely to be related to the address being annotated
+const MAX_LABEL_OFFSET: Address = 0x1000;
+
+pub struct SymbolTable {
+    by_name: HashMap<String, Address>,
+    labels: BTreeMap<Address, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self {
+            by_name: HashMap::new(),
+            labels: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(filename: &str) -> Result<Self, Error> {
+        let contents = fs::read_to_string(filename)
+            .map_err(|_| Error::new(&format!("Error reading symbols from {}", filename)))?;
+        Self::parse(&contents)
+    }
+
+    // Each line has the form `NAME = 0x1234`, as written by the assembler
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        let mut symbols = Self::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, value) = line.split_once('=')
+                .ok_or_else(|| Error::new(&format!("symbols: expected `NAME = value` on line {}", number + 1)))?;
+            let value = parse_number(value.trim())
+                .ok_or_else(|| Error::new(&format!("symbols: invalid value on line {}", number + 1)))?;
+            symbols.insert(name.trim(), value);
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, name: &str, addr: Address) {
+        // Constants from the include files are written in capitals, only labels are used to annotate
+        if is_label(name) {
+            let label = self.labels.entry(addr).or_insert_with(|| name.to_string());
+            // Prefer the outer label over a local one at the same address
+            if label.contains('.') && !name.contains('.') {
+                *label = name.to_string();
+            }
+        }
+        self.by_name.insert(name.to_string(), addr);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Address> {
+        self.by_name.get(name).copied()
+    }
+
+    // Finds the closest label at or before the address
+    pub fn lookup(&self, addr: Address) -> Option<(&str, Address)> {
+        self.labels.range(..=addr).next_back()
+            .filter(|(label_addr, _)| addr - **label_addr < MAX_LABEL_OFFSET)
+            .map(|(label_addr, name)| (name.as_str(), addr - label_addr))
+    }
+
+    pub fn annotate(&self, addr: Address) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => format!("<{}>", name),
+            Some((name, offset)) => format!("<{}+{:#x}>", name, offset),
+            None => String::new(),
+        }
+    }
+
+    pub fn format_address(&self, addr: Address) -> String {
+        match self.lookup(addr) {
+            Some(_) => format!("{:#010x} {}", addr, self.annotate(addr)),
+            None => format!("{:#010x}", addr),
+        }
+    }
+
+    // Accepts a hex address, a symbol name, or a symbol with an hex offset like `buffer+10`
+    pub fn parse_address(&self, arg: &str) -> Result<Address, Error> {
+        let (base, offset) = match arg.split_once('+') {
+            Some((base, offset)) => {
+                let offset = Address::from_str_radix(offset.trim_start_matches("0x"), 16)
+                    .map_err(|_| Error::new("Unable to parse offset"))?;
+                (base, offset)
+            },
+            None => (arg, 0),
+        };
+
+        let addr = match self.get(base) {
+            Some(addr) => addr,
+            None => Address::from_str_radix(base.trim_start_matches("0x"), 16)
+                .map_err(|_| Error::new(&format!("Unable to parse address or unknown symbol {}", base)))?,
+        };
+        Ok(addr.wrapping_add(offset))
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_label(name: &str) -> bool {
+    name.chars().next().map(|c| c.is_ascii_lowercase() || c == '_').unwrap_or(false)
+}
+
+fn parse_number(value: &str) -> Option<Address> {
+    match value.strip_prefix("0x") {
+        Some(hex) => Address::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
@@ -7,20 +7,24 @@ use crate::premade::bus::Bus;
 use crate::debugger::Debugger;
 use crate::error::{Error, ErrorType};
 use crate::interrupts::InterruptController;
-use crate::core::{Clock, ClockElapsed, Address, TransmutableBox};
+use crate::core::{ClockElapsed, Clock, Address, Snapshotable, TransmutableBox, SLEEP};
+use crate::scheduler::Scheduler;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::symbols::SymbolTable;
 
 
 pub struct System {
     pub clock: Clock,
     pub devices: HashMap<String, TransmutableBox>,
-    pub event_queue: Vec<NextStep>,
+    pub scheduler: Scheduler,
 
     pub debug_enabled: Cell<bool>,
     pub debugger: RefCell<Debugger>,
+    pub symbols: SymbolTable,
 
     pub bus: Rc<RefCell<Bus>>,
     pub bus_data: Rc<RefCell<Bus>>,
-    pub interrupt_controller: RefCell<InterruptController>,
+    pub interrupt_controller: Rc<RefCell<InterruptController>>,
     pub cycles: u128
 }
 
@@ -29,14 +33,15 @@ impl System {
         System {
             clock: 0,
             devices: HashMap::new(),
-            event_queue: vec![],
+            scheduler: Scheduler::new(),
 
             debug_enabled: Cell::new(false),
             debugger: RefCell::new(Debugger::new()),
+            symbols: SymbolTable::new(),
 
             bus: Rc::new(RefCell::new(Bus::new())),
             bus_data: Rc::new(RefCell::new(Bus::new())),
-            interrupt_controller: RefCell::new(InterruptController::new()),
+            interrupt_controller: Rc::new(RefCell::new(InterruptController::new())),
             cycles: 0,
 
         }
@@ -103,21 +108,33 @@ impl System {
     }
 
     fn process_one_event(&mut self) -> Result<(), Error> {
-        let mut event_device = self.event_queue.pop().unwrap();
-        self.clock = event_device.next_clock;
-        let result = match event_device.device.borrow_mut().as_steppable().unwrap().step(&self) {
+        let (index, clock) = self.scheduler.next().ok_or_else(|| Error::new("system: every device is asleep"))?;
+        self.clock = clock;
+        let result = self.scheduler.device(index).borrow_mut().as_steppable().unwrap().step(self);
+        let result = match result {
+            Ok(SLEEP) if self.scheduler.sleep_next() => Ok(()),
+            Ok(SLEEP) => {
+                let name = self.get_device_name(self.scheduler.device(index))?;
+                Err(Error::new(&format!("system: {} can't sleep without a waker", name)))
+            },
             Ok(diff) => {
-                event_device.next_clock = self.clock + diff;
+                self.scheduler.reschedule_next(self.clock + diff);
                 Ok(())
             },
+            // The device stays where it was, as it used to
             Err(err) => Err(err),
         };
-        self.queue_device(event_device);
+        // Devices woken during this step run next, at the same clock
+        self.scheduler.wake_pending(self.clock);
         result
     }
 
     pub fn step(&mut self) -> Result<(), Error> {
-        self.check_debugger();
+        if let Err(err) = self.check_debugger() {
+            self.exit_error();
+            println!("{:?}", err);
+            return Err(err);
+        }
 
         match self.process_one_event() {
             Ok(()) => {
@@ -151,6 +168,14 @@ impl System {
         self.run_for(u64::MAX).unwrap();
     }
 
+    pub fn shutdown(&mut self) {
+        for (_, dev) in self.devices.iter() {
+            if let Some(dev) = dev.borrow_mut().as_steppable() {
+                dev.on_shutdown(&self);
+            }
+        }
+    }
+
     pub fn exit_error(&mut self) {
         for (_, dev) in self.devices.iter() {
             match dev.borrow_mut().as_steppable() {
@@ -160,46 +185,98 @@ impl System {
         }
     }
 
-    fn check_debugger(&mut self) {
+    // Errors from the prompt are reported there, those that reach here stop the system
+    fn check_debugger(&mut self) -> Result<(), Error> {
         if self.debug_enabled.get() {
-            let top = self.event_queue[self.event_queue.len() - 1].device.clone();
+            let top = match self.scheduler.peek() {
+                Some(top) => top.clone(),
+                None => return Ok(()),
+            };
             if top.borrow_mut().as_debuggable().map(|debug| debug.debugging_enabled()).unwrap_or(false) {
-                if let Err(err) = self.debugger.borrow_mut().run_debugger(&self, top.clone()) {
-                    println!("Error: {:?}", err);
-                }
+                self.debugger.borrow_mut().run_debugger(&self, top.clone())?;
             }
         }
+        Ok(())
     }
 
-    fn try_queue_device(&mut self, device: TransmutableBox) {
-        if device.borrow_mut().as_steppable().is_some() {
-            self.queue_device(NextStep::new(device));
-        }
+    pub fn get_device_name(&self, device: &TransmutableBox) -> Result<&str, Error> {
+        self.devices.iter()
+            .find(|(_, dev)| Rc::ptr_eq(dev, device))
+            .map(|(name, _)| name.as_str())
+            .ok_or_else(|| Error::new("system: device is not registered"))
     }
 
-    fn queue_device(&mut self, device_step: NextStep) {
-        for i in (0..self.event_queue.len()).rev() {
-            if self.event_queue[i].next_clock > device_step.next_clock {
-                self.event_queue.insert(i + 1, device_step);
-                return;
+    pub fn save_snapshot(&self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        snapshot.write_beu64(self.clock);
+        snapshot.write_bytes(&self.cycles.to_be_bytes());
+        self.interrupt_controller.borrow_mut().save_state(snapshot)?;
+
+        // Sleeping devices are saved with the largest clock
+        snapshot.write_beu32(self.scheduler.len() as u32);
+        for (device, next_clock) in self.scheduler.clocks() {
+            snapshot.write_str(self.get_device_name(device)?);
+            snapshot.write_beu64(next_clock.unwrap_or(Clock::MAX));
+        }
+
+        // Devices are saved by name, in order, so the same machine can find them again
+        let mut names: Vec<&String> = self.devices.keys().collect();
+        names.sort();
+        let mut states = vec![];
+        for name in names {
+            if let Some(dev) = self.devices[name].borrow_mut().as_snapshotable() {
+                let mut state = SnapshotWriter::new();
+                dev.save_state(&mut state)?;
+                states.push((name, state.into_inner()));
             }
         }
-        self.event_queue.insert(0, device_step);
-    }
-}
 
+        snapshot.write_beu32(states.len() as u32);
+        for (name, state) in states {
+            snapshot.write_str(name);
+            snapshot.write_bytes(&state);
+        }
+        Ok(())
+    }
 
-pub struct NextStep {
-    pub next_clock: Clock,
-    pub device: TransmutableBox,
-}
+    pub fn load_snapshot(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        self.clock = snapshot.read_beu64()?;
+        let mut cycles = [0u8; 16];
+        snapshot.read_into(&mut cycles)?;
+        self.cycles = u128::from_be_bytes(cycles);
+        self.interrupt_controller.borrow_mut().load_state(snapshot)?;
 
-impl NextStep {
-    pub fn new(device: TransmutableBox) -> Self {
-        Self {
-            next_clock: 0,
-            device,
+        let count = snapshot.read_beu32()? as usize;
+        if count != self.scheduler.len() {
+            return Err(Error::new("snapshot: the steppable devices do not match this system"));
+        }
+        let mut clocks = vec![None; count];
+        for _ in 0..count {
+            let device = self.get_device(&snapshot.read_str()?)?;
+            let next_clock = snapshot.read_beu64()?;
+            let index = self.scheduler.clocks().position(|(dev, _)| Rc::ptr_eq(dev, &device))
+                .ok_or_else(|| Error::new("snapshot: the steppable devices do not match this system"))?;
+            clocks[index] = if next_clock == Clock::MAX { None } else { Some(next_clock) };
         }
+        self.scheduler.restore(&clocks);
+
+        let count = snapshot.read_beu32()?;
+        for _ in 0..count {
+            let name = snapshot.read_str()?;
+            let state = snapshot.read_bytes()?;
+            let device = self.get_device(&name)?;
+            let mut device = device.borrow_mut();
+            let dev = device.as_snapshotable().ok_or_else(|| Error::new(&format!("snapshot: device {} can't be restored", name)))?;
+            dev.load_state(&mut SnapshotReader::new(state))?;
+        }
+        Ok(())
+    }
+
+    fn try_queue_device(&mut self, device: TransmutableBox) {
+        let waker = match device.borrow_mut().as_steppable() {
+            Some(dev) => dev.waker(),
+            None => return,
+        };
+        self.scheduler.add(device, waker);
     }
 }
 
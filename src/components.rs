@@ -1,6 +1,7 @@
 // this module contains the hardware configuration for the Taleä system
 use pixels::{Pixels, SurfaceTexture};
 use regex::Regex;
+use serde::Deserialize;
 use std::{fs, io, net::IpAddr, path::Path, path::PathBuf};
 use winit::{
     dpi::LogicalSize,
@@ -9,18 +10,23 @@ use winit::{
 };
 
 use organum::{
-    core::{wrap_transmutable, Address, Addressable, Debuggable},
+    core::{wrap_transmutable, Address, Addressable, Debuggable, Snapshotable, TransmutableBox},
     error::Error,
-    premade::{bus::BusPort, memory::MemoryBlock, serial::Serial, serial},
+    gdb::GdbStub,
+    interrupts::InterruptRegisters,
+    premade::{bus::BusPort, memory::MemoryBlock, serial::Serial},
+    snapshot::{SnapshotReader, SnapshotWriter},
     sys::System,
 };
 use winit_input_helper::WinitInputHelper;
 
 use crate::components::{
     cpu::state::Sirius,
-    storage::{drive, tps},
-    tty::Tty,
-    video::{font::Font, Video, W_HEIGHT, W_WIDTH},
+    cpu::trace::Tracer,
+    machine::{BusKind, DeviceConfig, DeviceKind, Machine},
+    storage::{drive, tps, MediaConfig},
+    tty::{SerialConfig, Tty},
+    video::{font::Font, screen::Framebuffer, Video, W_HEIGHT, W_WIDTH},
     timer::Timer
 };
 
@@ -38,34 +44,34 @@ pub const DATSIZE: usize = 1 << ADDR_BUS_DATA_SIZE;
 
 pub const IVT_SIZE: usize = 4 * 256;
 
-pub const CPU_FREQUENCY: u32 = 10_000_000;
-pub const TTY_FREQUENCY: u64 = 10_000_000;
-
-pub const TTY_BASE: Address     = 0;
-pub const VIDEO_BASE: Address   = TTY_BASE   + serial::REGISTER_COUNT as Address;
-pub const TPS_BASE: Address     = VIDEO_BASE + video::REGISTER_COUNT as Address;
-pub const DRIVE_BASE: Address   = TPS_BASE   + tps::REGISTER_COUNT as Address;
-pub const TIMER_BASE: Address   = DRIVE_BASE + drive::REGISTER_COUNT as Address;
-pub const END_IO: Address       = TIMER_BASE + timer::REGISTER_COUNT as Address;
-
-pub const DATA_MEMORY_REST: usize = DATSIZE - END_IO as usize;
+// Used for ttys whose machine description doesn't give a bit rate
+pub const TTY_BAUD: u32 = 115_200;
 
 pub const TITLE: &'static str = "Taleä Computing System";
-pub const FONT_PATH: &'static str = "assets/fonts";
-pub const TPS_PATH: &'static str = "dev/tps/tps";
-pub const DISK_PATH: &'static str = "dev/drive";
 
+pub const SNAPSHOT_MAGIC: &'static str = "TALEA-SNAPSHOT";
+pub const SNAPSHOT_VERSION: u8 = 6;
+
+// The Codex runs the same instruction set as the Tabula, without the MMU
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
 pub enum TaleaCpuType {
+    #[default]
+    #[serde(rename = "sirius")]
     SiriusType,
+    #[serde(rename = "codex")]
+    CodexType,
 }
 
+pub mod bios;
 pub mod cpu;
+pub mod machine;
 pub mod storage;
 pub mod tty;
 pub mod video;
 pub mod timer;
 
 pub struct Talea {
+    pub machine: Machine,
     pub system: System,
     //pub tty: Tty,
     pub video: Video,
@@ -74,34 +80,19 @@ pub struct Talea {
     pub input: WinitInputHelper,
 }
 
-pub fn build_talea(root_path: &PathBuf, rom_file: &Path, ip: IpAddr, port: u16, debug: bool) -> Result<Talea, Error> {
-    let mut system = System::new();
-    let main_port = BusPort::new(
-        0,
-        ADDR_BUS_MAIN_SIZE as u8,
-        DATA_BUS_SIZE as u8,
-        system.bus.clone(),
-    );
-    let data_port = BusPort::new(
-        0,
-        ADDR_BUS_DATA_SIZE as u8,
-        DATA_BUS_SIZE as u8,
-        system.bus_data.clone(),
-    );
-
-    let mut rom = MemoryBlock::load(rom_file.to_str().unwrap())?;
-    let ram = MemoryBlock::new(vec![0; MEMSIZE - rom.len()]);
-    let data = MemoryBlock::new(vec![0; DATA_MEMORY_REST]);
-
-    //rom.read_only(); //TODO: figure out a way around this
-    let rom_len = rom.len() as Address;
-    system.add_addressable_device(0, wrap_transmutable(rom))?;
-    system.add_addressable_device(rom_len, wrap_transmutable(ram))?;
+// A Taleä without a window: the video device, if the machine has one, renders into memory
+// and the system is driven by a plain loop instead of the winit event loop
+pub struct HeadlessTalea {
+    pub machine: Machine,
+    pub system: System,
+    pub video: Option<Video>,
+}
 
-    build_cpu(&mut system, CPU_FREQUENCY, main_port, data_port, debug)?;
-    build_storage(root_path.to_owned(), &mut system, DRIVE_BASE, TPS_BASE)?;
-    build_tty(&mut system, TTY_BASE, TTY_FREQUENCY, ip, port)?;
-    build_timer(&mut system, TIMER_BASE, CPU_FREQUENCY as u64)?;
+pub fn build_talea(machine: Machine, root_path: &PathBuf, rom_file: Option<&Path>, serial: &SerialConfig, media: &MediaConfig, debug: bool, strict: bool, trace: Option<Tracer>) -> Result<Talea, Error> {
+    if machine.device(DeviceKind::Video).is_none() {
+        return Err(Error::new(&format!("{} has no video device, it can only run headless", machine.name)));
+    }
+    let mut system = build_system(&machine, root_path, rom_file, serial, media, debug, strict, trace)?;
 
     let event_loop = EventLoop::new();
     let input = WinitInputHelper::new();
@@ -120,17 +111,10 @@ pub fn build_talea(root_path: &PathBuf, rom_file: &Path, ip: IpAddr, port: u16,
         Pixels::new(W_WIDTH as u32, W_HEIGHT as u32, surface_texture)
             .or_else(|e| Err(organum::error::Error::new(&format!("{}", e))))?
     };
-    system.add_addressable_device_data(END_IO, wrap_transmutable(data))?;
-    let video = Video::new(
-        &mut system,
-        VIDEO_BASE,
-        W_WIDTH,
-        W_HEIGHT,
-        pixels,
-        collect_fonts(&root_path.clone().join(FONT_PATH)).unwrap(),
-    )?;
+    let video = build_video(&machine, &mut system, root_path, Framebuffer::Window(pixels))?.unwrap();
 
     Ok(Talea {
+        machine,
         system,
         //tty,
         video,
@@ -140,56 +124,208 @@ pub fn build_talea(root_path: &PathBuf, rom_file: &Path, ip: IpAddr, port: u16,
     })
 }
 
-fn build_tty(
-    system: &mut System,
-    addr: Address,
-    frequency: u64,
-    ip: IpAddr,
-    port: u16,
-) -> Result<(), Error> {
-    let serial = Serial::new(addr, frequency);
-    let mut tty = Tty::new(ip, port, serial);
-    tty.server.run();
-    system.add_peripheral_data("Tty-0", addr, wrap_transmutable(tty))?;
+pub fn build_talea_headless(machine: Machine, root_path: &PathBuf, rom_file: Option<&Path>, serial: &SerialConfig, media: &MediaConfig, debug: bool, strict: bool, trace: Option<Tracer>) -> Result<HeadlessTalea, Error> {
+    let mut system = build_system(&machine, root_path, rom_file, serial, media, debug, strict, trace)?;
+    let framebuffer = Framebuffer::headless(W_WIDTH as u32, W_HEIGHT as u32);
+    let video = build_video(&machine, &mut system, root_path, framebuffer)?;
+
+    Ok(HeadlessTalea {
+        machine,
+        system,
+        video,
+    })
+}
+
+fn build_system(machine: &Machine, root_path: &PathBuf, rom_file: Option<&Path>, serial: &SerialConfig, media: &MediaConfig, debug: bool, strict: bool, trace: Option<Tracer>) -> Result<System, Error> {
+    let mut system = System::new();
+    let main_port = BusPort::new(
+        0,
+        machine.memory.main_bits,
+        DATA_BUS_SIZE as u8,
+        system.bus.clone(),
+    );
+    let data_port = BusPort::new(
+        0,
+        ADDR_BUS_DATA_SIZE as u8,
+        DATA_BUS_SIZE as u8,
+        system.bus_data.clone(),
+    );
+
+    // Without an image the machine boots the built in monitor
+    let rom = match rom_file {
+        Some(rom_file) => MemoryBlock::load(rom_file.to_str().unwrap())?,
+        None => MemoryBlock::new(bios::monitor(machine)?),
+    };
+    if rom.len() > machine.memory.main_size {
+        let name = rom_file.map_or("The monitor".to_string(), |rom_file| rom_file.display().to_string());
+        return Err(Error::new(&format!("{} doesn't fit in the main memory of {}", name, machine.name)));
+    }
+    let ram = MemoryBlock::new(vec![0; machine.memory.main_size - rom.len()]);
+    let data = MemoryBlock::new(vec![0; machine.memory.data_size]);
+
+    //rom.read_only(); //TODO: figure out a way around this
+    let rom_len = rom.len() as Address;
+    system.add_addressable_device(0, wrap_transmutable(rom))?;
+    system.add_addressable_device(rom_len, wrap_transmutable(ram))?;
+
+    build_cpu(&mut system, machine.cpu.kind, machine.cpu.frequency, main_port, data_port, debug, strict, trace)?;
+    for device in machine.devices.iter() {
+        match device.kind {
+            DeviceKind::Tty => build_tty(&mut system, device, serial)?,
+            DeviceKind::Tps => build_tps(&mut system, device, root_path, media)?,
+            DeviceKind::Disk => build_disk(&mut system, device, root_path, media)?,
+            DeviceKind::Timer => build_timer(&mut system, device, machine.cpu.frequency as u64)?,
+            DeviceKind::Interrupts => build_interrupts(&mut system, device)?,
+            // The video device needs a framebuffer, it's built once the system is
+            DeviceKind::Video => { },
+        }
+    }
+    build_interrupt_sources(&mut system, machine)?;
+
+    system.add_addressable_device_data(machine.memory.data_base, wrap_transmutable(data))?;
+    Ok(system)
+}
+
+fn add_to_bus(system: &mut System, device: &DeviceConfig, dev: TransmutableBox) -> Result<(), Error> {
+    match device.bus {
+        BusKind::Main => system.add_peripheral(&device.name, device.base, dev),
+        BusKind::Data => system.add_peripheral_data(&device.name, device.base, dev),
+    }
+}
+
+// Waits for gdb to connect and stops the cpu before its first instruction
+pub fn attach_gdb(system: &mut System, machine: &Machine, ip: IpAddr, port: u16) -> Result<(), Error> {
+    let main_port = BusPort::new(
+        0,
+        machine.memory.main_bits,
+        DATA_BUS_SIZE as u8,
+        system.bus.clone(),
+    );
+    let data_port = BusPort::new(
+        0,
+        ADDR_BUS_DATA_SIZE as u8,
+        DATA_BUS_SIZE as u8,
+        system.bus_data.clone(),
+    );
+
+    println!("Waiting for gdb on {}:{}", ip, port);
+    let gdb = GdbStub::listen(ip, port, main_port, data_port)
+        .map_err(|e| Error::new(&format!("gdb: {}", e)))?;
+    println!("gdb connected from {}", gdb.client_addr);
+
+    system.debugger.borrow_mut().attach_gdb(gdb);
+    system.get_device("Sirius-cpu")?.borrow_mut().as_debuggable().unwrap().set_debugging(true);
+    system.enable_debugging();
     Ok(())
 }
 
-fn build_storage(path: PathBuf, system: &mut System, drive_addr: Address, tps_addr: Address) -> Result<(), Error> {
-    let drive_ports = MemoryBlock::new(vec![0; drive::REGISTER_COUNT]);
-    let tps_ports = MemoryBlock::new(vec![0; tps::REGISTER_COUNT]);
-    let drive = drive::Drive::new(path.join(DISK_PATH).to_str().unwrap());
-    let tps = tps::Drive::new(path.join(TPS_PATH).to_str().unwrap());
-    let drive_controller =
-        drive::Controller::new(drive, wrap_transmutable(drive_ports.clone()), 10_000_000);
-    let tps_controller = tps::Controller::new(tps, wrap_transmutable(tps_ports.clone()), 10_000_000);
-
-    system.add_peripheral_data(
-        "Disk-Controller",
-        drive_addr,
-        wrap_transmutable(drive_controller),
-    )?;
-    system.add_peripheral_data(
-        "Tps-Controller",
-        tps_addr,
-        wrap_transmutable(tps_controller),
-    )?;
+fn build_video(machine: &Machine, system: &mut System, root_path: &PathBuf, framebuffer: Framebuffer) -> Result<Option<Video>, Error> {
+    let device = match machine.device(DeviceKind::Video) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    let fonts = collect_fonts(&root_path.join(device.path.as_ref().unwrap()))
+        .map_err(|e| Error::new(&format!("{}: unable to read the fonts: {}", device.name, e)))?;
+    let vectors = device.vectors();
+    let video = Video::new(W_WIDTH, W_HEIGHT, framebuffer, fonts, (vectors[0], vectors[1]))?;
+    add_to_bus(system, device, video.mem.clone())?;
+    Ok(Some(video))
+}
+
+// The video device lives outside of the system, so it is saved after it
+pub fn save_snapshot(path: &Path, system: &System, video: Option<&mut Video>) -> Result<(), Error> {
+    let mut snapshot = SnapshotWriter::new();
+    snapshot.write_str(SNAPSHOT_MAGIC);
+    snapshot.write_u8(SNAPSHOT_VERSION);
+    system.save_snapshot(&mut snapshot)?;
+    if let Some(video) = video {
+        video.save_state(&mut snapshot)?;
+    }
+
+    fs::write(path, snapshot.into_inner())
+        .or_else(|e| Err(Error::new(&format!("Error writing snapshot {}: {}", path.display(), e))))
+}
+
+pub fn load_snapshot(path: &Path, system: &mut System, video: Option<&mut Video>) -> Result<(), Error> {
+    let data = fs::read(path)
+        .or_else(|e| Err(Error::new(&format!("Error reading snapshot {}: {}", path.display(), e))))?;
+    let mut snapshot = SnapshotReader::new(&data);
+
+    if snapshot.read_str()? != SNAPSHOT_MAGIC {
+        return Err(Error::new(&format!("{} is not a Taleä snapshot", path.display())));
+    }
+    let version = snapshot.read_u8()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(Error::new(&format!("Unsupported snapshot version {}", version)));
+    }
+    system.load_snapshot(&mut snapshot)?;
+    if let Some(video) = video {
+        video.load_state(&mut snapshot)?;
+    }
     Ok(())
 }
 
-fn build_timer(system: &mut System, addr: Address, frequency: u64) -> Result<(), Error> {
-    let timer = Timer::new(addr, frequency);
-    system.add_peripheral_data("Timer-0", addr, wrap_transmutable(timer))?;
+fn build_tty(system: &mut System, device: &DeviceConfig, config: &SerialConfig) -> Result<(), Error> {
+    let serial = Serial::new(device.base, device.baud.unwrap_or(TTY_BAUD));
+    let vectors = device.vectors();
+    let tty = Tty::new(config, serial, system.interrupt_controller.clone(), vectors[0], vectors[1])?;
+    add_to_bus(system, device, wrap_transmutable(tty))
+}
+
+fn build_tps(system: &mut System, device: &DeviceConfig, root_path: &Path, media: &MediaConfig) -> Result<(), Error> {
+    let ports = MemoryBlock::new(vec![0; tps::REGISTER_COUNT]);
+    let tps = tps::Drive::new(root_path.join(device.path.as_ref().unwrap()).to_str().unwrap(), media);
+    let controller = tps::Controller::new(tps, wrap_transmutable(ports), device.vectors()[0], device.timing.unwrap_or_default());
+    add_to_bus(system, device, wrap_transmutable(controller))
+}
+
+fn build_disk(system: &mut System, device: &DeviceConfig, root_path: &Path, media: &MediaConfig) -> Result<(), Error> {
+    let ports = MemoryBlock::new(vec![0; drive::REGISTER_COUNT]);
+    let drive = drive::Drive::new(root_path.join(device.path.as_ref().unwrap()).to_str().unwrap(), media);
+    let controller = drive::Controller::new(drive, wrap_transmutable(ports), device.base, device.vectors()[0], device.timing.unwrap_or_default());
+    add_to_bus(system, device, wrap_transmutable(controller))
+}
+
+// Timers count at the cpu frequency unless the machine says otherwise
+fn build_timer(system: &mut System, device: &DeviceConfig, cpu_frequency: u64) -> Result<(), Error> {
+    let vectors = device.vectors();
+    let timer = Timer::new(device.base, device.frequency.unwrap_or(cpu_frequency), vectors[0], vectors[1]);
+    add_to_bus(system, device, wrap_transmutable(timer))
+}
+
+fn build_interrupts(system: &mut System, device: &DeviceConfig) -> Result<(), Error> {
+    let registers = InterruptRegisters::new(system.interrupt_controller.clone());
+    add_to_bus(system, device, wrap_transmutable(registers))
+}
+
+// Sources take their bit in the interrupt controller registers in the order the machine lists them
+fn build_interrupt_sources(system: &mut System, machine: &Machine) -> Result<(), Error> {
+    for device in machine.devices.iter() {
+        for (index, interrupt) in device.interrupts().iter().enumerate() {
+            let mut controller = system.get_interrupt_controller();
+            let trigger = device.kind.trigger(index);
+            match interrupt.line {
+                Some(line) => controller.add_source_at(line, interrupt.vector, interrupt.priority, trigger)?,
+                None => controller.add_source(interrupt.vector, interrupt.priority, trigger)?,
+            }
+        }
+    }
     Ok(())
 }
 
 fn build_cpu(
     system: &mut System,
+    cputype: TaleaCpuType,
     frequency: u32,
     port: BusPort,
     port_d: BusPort,
     debug: bool,
+    strict: bool,
+    trace: Option<Tracer>,
 ) -> Result<(), Error> {
-    let mut cpu = Sirius::new(TaleaCpuType::SiriusType, frequency, port, port_d);
+    let mut cpu = Sirius::new(cputype, frequency, port, port_d);
+    cpu.strict = strict;
+    cpu.trace = trace;
     if debug {
         cpu.add_breakpoint(0);
     }
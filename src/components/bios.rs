@@ -0,0 +1,709 @@
+// bios.rs holds the monitor the machine boots when no image is given. It is written with the small
+// assembler below, so the rom always matches the instruction set and devices of the emulator running it.
+// The monitor talks over the tty, boots from a tps marked as bootable and otherwise waits for commands:
+//   e ADDR [LEN]       examine main memory
+//   d ADDR BYTE...     deposit bytes in main memory
+//   g ADDR             call ADDR, returning with `jalr zero, ra, 0` gets back to the prompt
+//   t N SECTOR ADDR    load a tps sector at ADDR, which must be 512 aligned
+//   r N SECTOR ADDR    load a disk sector at ADDR
+//   b [N]              boot from tps N, or from the first bootable one
+// Numbers are hex, device faults are printed as `error NN`. A booted sector is loaded at BOOT and called with the tps number in a0
+use organum::core::Address;
+use organum::error::Error;
+use organum::premade::serial::{Flag, Register as SerialRegister};
+
+use crate::components::cpu::decode::decode_word;
+use crate::components::cpu::encode::encode;
+use crate::components::cpu::instructions::{Instruction, B, I, J, R, S, U};
+use crate::components::cpu::state::Register::{self, *};
+use crate::components::machine::{BusKind, DeviceKind, Machine};
+use crate::components::storage::{drive, tps, Flag as StorageFlag};
+use crate::components::IVT_SIZE;
+
+// Where the monitor keeps its line buffer, its stack grows down from the same address
+pub const LINE: u32 = 0xf000;
+pub const LINE_SIZE: i32 = 64;
+pub const STACK: u32 = LINE;
+// Boot sectors are loaded here
+pub const BOOT: u32 = 0x10000;
+// The ivt the cpu starts with, the monitor fills its first entries
+pub const IVT: u32 = 62 * IVT_SIZE as u32;
+pub const VECTORS: i32 = 32;
+
+// The largest immediate the decoder keeps intact
+const IMM_MAX: i32 = 0x3fff;
+const SECTOR_BITS: i32 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Label(usize);
+
+enum Item {
+    Instruction(Instruction),
+    // Completed with the offset from the instruction to the label
+    Relative(Label, Box<dyn Fn(i32) -> Instruction>),
+    // Loads the address of the label, takes two words
+    Address(Register, Label),
+}
+
+// Just enough of an assembler for the monitor: instructions, labels and strings placed after the code
+struct Asm {
+    items: Vec<Item>,
+    labels: Vec<Option<u32>>,
+    strings: Vec<(Label, Vec<u8>)>,
+    at: u32,
+}
+
+impl Asm {
+    fn new() -> Self {
+        Self { items: Vec::new(), labels: Vec::new(), strings: Vec::new(), at: 0 }
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.at);
+    }
+
+    fn here(&mut self) -> Label {
+        let label = self.label();
+        self.bind(label);
+        label
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.items.push(Item::Instruction(instruction));
+        self.at += 4;
+    }
+
+    fn relative(&mut self, label: Label, instruction: impl Fn(i32) -> Instruction + 'static) {
+        self.items.push(Item::Relative(label, Box::new(instruction)));
+        self.at += 4;
+    }
+
+    fn la(&mut self, rd: Register, label: Label) {
+        self.items.push(Item::Address(rd, label));
+        self.at += 8;
+    }
+
+    fn string(&mut self, text: &str) -> Label {
+        let label = self.label();
+        let mut bytes = text.as_bytes().to_vec();
+        bytes.push(0);
+        self.strings.push((label, bytes));
+        label
+    }
+
+    fn li(&mut self, rd: Register, value: u32) {
+        if value <= IMM_MAX as u32 {
+            self.addi(rd, Zero, value as i32);
+        } else {
+            self.emit(Instruction::U(U::Lui(rd, value & !0xfff)));
+            if value & 0xfff != 0 {
+                self.emit(Instruction::I(I::Ori(rd, rd, (value & 0xfff) as i32)));
+            }
+        }
+    }
+
+    fn addi(&mut self, rd: Register, rs: Register, imm: i32) {
+        self.emit(Instruction::I(I::Addi(rd, rs, imm)));
+    }
+
+    fn subi(&mut self, rd: Register, rs: Register, imm: i32) {
+        self.emit(Instruction::I(I::Subi(rd, rs, imm)));
+    }
+
+    fn andi(&mut self, rd: Register, rs: Register, imm: i32) {
+        self.emit(Instruction::I(I::Andi(rd, rs, imm)));
+    }
+
+    fn ori(&mut self, rd: Register, rs: Register, imm: i32) {
+        self.emit(Instruction::I(I::Ori(rd, rs, imm)));
+    }
+
+    fn shli(&mut self, rd: Register, rs: Register, imm: i32) {
+        self.emit(Instruction::I(I::ShiLl(rd, rs, imm)));
+    }
+
+    fn shri(&mut self, rd: Register, rs: Register, imm: i32) {
+        self.emit(Instruction::I(I::ShiRl(rd, rs, imm)));
+    }
+
+    // Main memory
+    fn lbu(&mut self, rd: Register, base: Register, offset: i32) {
+        self.emit(Instruction::I(I::Lbu(rd, base, offset)));
+    }
+
+    fn sb(&mut self, value: Register, base: Register, offset: i32) {
+        self.emit(Instruction::S(S::Sb(value, base, offset)));
+    }
+
+    // Data memory, where the devices are
+    fn lbud(&mut self, rd: Register, base: Register, offset: i32) {
+        self.emit(Instruction::I(I::Lbud(rd, base, offset)));
+    }
+
+    fn sbd(&mut self, value: Register, base: Register, offset: i32) {
+        self.emit(Instruction::S(S::Sbd(value, base, offset)));
+    }
+
+    fn swd(&mut self, value: Register, base: Register, offset: i32) {
+        self.emit(Instruction::S(S::Swd(value, base, offset)));
+    }
+
+    fn mv(&mut self, rd: Register, rs: Register) {
+        self.addi(rd, rs, 0);
+    }
+
+    fn j(&mut self, label: Label) {
+        self.relative(label, |offset| Instruction::J(J::Jal(Zero, offset)));
+    }
+
+    fn call(&mut self, label: Label) {
+        self.relative(label, |offset| Instruction::J(J::Jal(Ra, offset)));
+    }
+
+    fn ret(&mut self) {
+        self.emit(Instruction::I(I::Jalr(Zero, Ra, 0)));
+    }
+
+    fn beq(&mut self, a: Register, b: Register, label: Label) {
+        self.relative(label, move |offset| Instruction::B(B::Beq(a, b, offset)));
+    }
+
+    fn bne(&mut self, a: Register, b: Register, label: Label) {
+        self.relative(label, move |offset| Instruction::B(B::Bne(a, b, offset)));
+    }
+
+    fn bltu(&mut self, a: Register, b: Register, label: Label) {
+        self.relative(label, move |offset| Instruction::B(B::Bltu(a, b, offset)));
+    }
+
+    fn address(&self, label: Label) -> Result<u32, Error> {
+        self.labels[label.0].ok_or_else(|| Error::new("bios: jump to a label that was never placed"))
+    }
+
+    // Every instruction is decoded back, so anything that doesn't fit its fields is caught here
+    fn finish(mut self) -> Result<Vec<u8>, Error> {
+        for (label, bytes) in self.strings.iter() {
+            self.labels[label.0] = Some(self.at);
+            self.at += bytes.len() as u32;
+        }
+
+        let mut image = Vec::with_capacity(self.at as usize);
+        let mut at = 0;
+        for item in self.items.iter() {
+            let instructions = match item {
+                Item::Instruction(instruction) => vec![*instruction],
+                Item::Relative(label, instruction) => vec![instruction(self.address(*label)? as i32 - at as i32)],
+                Item::Address(rd, label) => {
+                    let address = self.address(*label)?;
+                    vec![
+                        Instruction::U(U::Lui(*rd, address & !0xfff)),
+                        Instruction::I(I::Ori(*rd, *rd, (address & 0xfff) as i32)),
+                    ]
+                },
+            };
+            for instruction in instructions {
+                let word = encode(&instruction);
+                if decode_word(word) != instruction {
+                    return Err(Error::new(&format!("bios: {:?} at {:#x} doesn't fit its encoding", instruction, at)));
+                }
+                image.extend_from_slice(&word.to_be_bytes());
+                at += 4;
+            }
+        }
+        for (_, bytes) in self.strings.iter() {
+            image.extend_from_slice(bytes);
+        }
+        image.resize((image.len() + 3) & !3, 0);
+        Ok(image)
+    }
+}
+
+// The monitor reaches device registers with immediates off the zero register
+fn device_base(machine: &Machine, kind: DeviceKind) -> Result<Option<i32>, Error> {
+    match machine.device(kind) {
+        None => Ok(None),
+        Some(device) if device.bus == BusKind::Data && device.base < (IMM_MAX as Address) - 8 => Ok(Some(device.base as i32)),
+        Some(device) => Err(Error::new(&format!("bios: the monitor can't reach {} at {:#x}", device.name, device.base))),
+    }
+}
+
+// Inline tty access, only t3 is used besides the byte
+fn putc(asm: &mut Asm, tty: i32, byte: Register) {
+    let wait = asm.here();
+    asm.lbud(T3, Zero, tty + SerialRegister::STATUS as i32);
+    asm.andi(T3, T3, Flag::TXFULL as i32);
+    asm.bne(T3, Zero, wait);
+    asm.sbd(byte, Zero, tty + SerialRegister::TX as i32);
+}
+
+fn getc(asm: &mut Asm, tty: i32, byte: Register) {
+    let wait = asm.here();
+    asm.lbud(T3, Zero, tty + SerialRegister::STATUS as i32);
+    asm.andi(T3, T3, Flag::RXREADY as i32);
+    asm.beq(T3, Zero, wait);
+    asm.lbud(byte, Zero, tty + SerialRegister::RX as i32);
+}
+
+// Spins until the controller whose flags are at `flags` is done, and leaves them in t0
+fn wait(asm: &mut Asm, flags: i32) {
+    let busy = asm.here();
+    asm.lbud(T0, Zero, flags);
+    asm.andi(T1, T0, StorageFlag::BUSY as i32);
+    asm.bne(T1, Zero, busy);
+}
+
+// Leaves in a0 the fault at `fault` if the command ended in error, zero otherwise
+fn fault(asm: &mut Asm, fault: i32) {
+    let done = asm.label();
+    asm.li(A0, 0);
+    asm.andi(T0, T0, StorageFlag::ERROR as i32);
+    asm.beq(T0, Zero, done);
+    asm.lbud(A0, Zero, fault);
+    asm.bind(done);
+}
+
+// Leaves a0 non zero if the tps numbered in `number` has a bootable sector 0
+fn is_bootable(asm: &mut Asm, tps: i32, number: Register) {
+    let first = asm.label();
+    let done = asm.label();
+    asm.sbd(Zero, Zero, tps + tps::Register::DATA as i32);
+    asm.li(T0, tps::Command::IsBootable as u32);
+    asm.beq(number, Zero, first);
+    asm.ori(T0, T0, 0x80);
+    asm.bind(first);
+    asm.sbd(T0, Zero, tps + tps::Register::COMMAND as i32);
+    wait(asm, tps + tps::Register::STATUSH as i32);
+    asm.li(A0, 0);
+    asm.andi(T0, T0, StorageFlag::ERROR as i32);
+    asm.bne(T0, Zero, done);
+    asm.lbud(A0, Zero, tps + tps::Register::STATUSL as i32);
+    asm.bind(done);
+}
+
+pub fn monitor(machine: &Machine) -> Result<Vec<u8>, Error> {
+    let tty = device_base(machine, DeviceKind::Tty)?.ok_or_else(|| Error::new("bios: the monitor needs a tty"))?;
+    let tps = device_base(machine, DeviceKind::Tps)?;
+    let disk = device_base(machine, DeviceKind::Disk)?;
+
+    let mut asm = Asm::new();
+    let banner = asm.string(&format!("\r\n{} monitor, ? for help", machine.name));
+    let prompt_text = asm.string("\r\n> ");
+    let help_text = asm.string(concat!(
+        "\r\ne ADDR [LEN]     examine memory",
+        "\r\nd ADDR BYTE...   deposit bytes",
+        "\r\ng ADDR           call ADDR",
+        "\r\nt N SECTOR ADDR  load a tps sector",
+        "\r\nr N SECTOR ADDR  load a disk sector",
+        "\r\nb [N]            boot from a tps",
+    ));
+    let unknown_text = asm.string("\r\nunknown command, ? for help");
+    let usage_text = asm.string("\r\nmissing or bad arguments");
+    let unaligned_text = asm.string("\r\naddress must be 512 aligned");
+    let done_text = asm.string("\r\ndone");
+    let booting_text = asm.string("\r\nbooting from tps ");
+    let not_bootable_text = asm.string("\r\nno bootable tps");
+    let no_tps_text = asm.string("\r\nno tps drive");
+    let no_disk_text = asm.string("\r\nno disk drive");
+    let exception_text = asm.string("\r\nexception ");
+    let error_text = asm.string("\r\nerror ");
+
+    let prompt = asm.label();
+    let usage = asm.label();
+    let unaligned = asm.label();
+    let failed = asm.label();
+    let puts = asm.label();
+    let puthex = asm.label();
+    let putchar = asm.label();
+    let getline = asm.label();
+    let hex = asm.label();
+    let find_boot = asm.label();
+    let boot = asm.label();
+    let tps_load = asm.label();
+    let disk_load = asm.label();
+    let stubs = asm.label();
+
+    // Reset, the exception stubs go in the ivt first so faults end up back at the prompt
+    asm.li(Sp, STACK);
+    asm.la(T0, stubs);
+    asm.li(T1, IVT);
+    asm.li(T2, VECTORS as u32);
+    let fill = asm.here();
+    asm.swd(T0, T1, 0);
+    asm.addi(T0, T0, 8);
+    asm.addi(T1, T1, 4);
+    asm.subi(T2, T2, 1);
+    asm.bne(T2, Zero, fill);
+
+    asm.la(A1, banner);
+    asm.call(puts);
+    if tps.is_some() {
+        asm.call(find_boot);
+        asm.bne(A0, Zero, boot);
+    }
+
+    // Reads a line and runs the command named by its first letter, a1 is left after the letter
+    asm.bind(prompt);
+    asm.li(Sp, STACK);
+    asm.la(A1, prompt_text);
+    asm.call(puts);
+    asm.call(getline);
+    let skip = asm.here();
+    let first = asm.label();
+    asm.lbu(T0, A1, 0);
+    asm.li(T1, b' ' as u32);
+    asm.bne(T0, T1, first);
+    asm.addi(A1, A1, 1);
+    asm.j(skip);
+    asm.bind(first);
+    asm.beq(T0, Zero, prompt);
+    asm.addi(A1, A1, 1);
+
+    let commands: Vec<(u8, Label)> = b"edgtrb?".iter().map(|c| (*c, asm.label())).collect();
+    for (c, label) in commands.iter() {
+        asm.li(T1, *c as u32);
+        asm.beq(T0, T1, *label);
+    }
+    asm.la(A1, unknown_text);
+    asm.call(puts);
+    asm.j(prompt);
+    let command = |c: u8| commands.iter().find(|(command, _)| *command == c).unwrap().1;
+
+    asm.bind(usage);
+    asm.la(A1, usage_text);
+    asm.call(puts);
+    asm.j(prompt);
+
+    asm.bind(unaligned);
+    asm.la(A1, unaligned_text);
+    asm.call(puts);
+    asm.j(prompt);
+
+    // A device command ended with the fault in a0
+    asm.bind(failed);
+    asm.mv(S1, A0);
+    asm.la(A1, error_text);
+    asm.call(puts);
+    asm.mv(A1, S1);
+    asm.li(A2, 2);
+    asm.call(puthex);
+    asm.j(prompt);
+
+    // e ADDR [LEN], 16 bytes to a line
+    asm.bind(command(b'e'));
+    asm.call(hex);
+    asm.beq(A2, Zero, usage);
+    asm.mv(S1, A0);
+    asm.call(hex);
+    asm.mv(S2, A0);
+    let length = asm.label();
+    asm.bne(A2, Zero, length);
+    asm.li(S2, 16);
+    asm.bind(length);
+    asm.beq(S2, Zero, prompt);
+    let line = asm.here();
+    asm.li(A0, b'\r' as u32);
+    asm.call(putchar);
+    asm.li(A0, b'\n' as u32);
+    asm.call(putchar);
+    asm.mv(A1, S1);
+    asm.li(A2, 8);
+    asm.call(puthex);
+    asm.li(A0, b':' as u32);
+    asm.call(putchar);
+    asm.li(S3, 16);
+    let byte = asm.here();
+    asm.li(A0, b' ' as u32);
+    asm.call(putchar);
+    asm.lbu(A1, S1, 0);
+    asm.li(A2, 2);
+    asm.call(puthex);
+    asm.addi(S1, S1, 1);
+    asm.subi(S2, S2, 1);
+    asm.beq(S2, Zero, prompt);
+    asm.subi(S3, S3, 1);
+    asm.bne(S3, Zero, byte);
+    asm.j(line);
+
+    // d ADDR BYTE...
+    asm.bind(command(b'd'));
+    asm.call(hex);
+    asm.beq(A2, Zero, usage);
+    asm.mv(S1, A0);
+    let deposit = asm.here();
+    asm.call(hex);
+    asm.beq(A2, Zero, prompt);
+    asm.sb(A0, S1, 0);
+    asm.addi(S1, S1, 1);
+    asm.j(deposit);
+
+    // g ADDR
+    asm.bind(command(b'g'));
+    asm.call(hex);
+    asm.beq(A2, Zero, usage);
+    asm.emit(Instruction::I(I::Jalr(Ra, A0, 0)));
+    asm.j(prompt);
+
+    // t N SECTOR ADDR and r N SECTOR ADDR, loaders take the unit in a0, the sector in a1 and the
+    // 512 byte block in a2, and leave the fault in a0
+    for (c, device, load, missing) in [(b't', tps, tps_load, no_tps_text), (b'r', disk, disk_load, no_disk_text)] {
+        asm.bind(command(c));
+        if device.is_none() {
+            asm.la(A1, missing);
+            asm.call(puts);
+            asm.j(prompt);
+            continue;
+        }
+        asm.call(hex);
+        asm.beq(A2, Zero, usage);
+        asm.mv(S1, A0);
+        asm.call(hex);
+        asm.beq(A2, Zero, usage);
+        asm.mv(S2, A0);
+        asm.call(hex);
+        asm.beq(A2, Zero, usage);
+        asm.andi(T0, A0, (1 << SECTOR_BITS) - 1);
+        asm.bne(T0, Zero, unaligned);
+        asm.shri(A2, A0, SECTOR_BITS);
+        asm.mv(A0, S1);
+        asm.mv(A1, S2);
+        asm.call(load);
+        asm.bne(A0, Zero, failed);
+        asm.la(A1, done_text);
+        asm.call(puts);
+        asm.j(prompt);
+    }
+
+    // b [N]
+    asm.bind(command(b'b'));
+    match tps {
+        Some(tps) => {
+            let any = asm.label();
+            let not_bootable = asm.label();
+            asm.call(hex);
+            asm.beq(A2, Zero, any);
+            asm.mv(S1, A0);
+            is_bootable(&mut asm, tps, S1);
+            asm.bne(A0, Zero, boot);
+            asm.j(not_bootable);
+            asm.bind(any);
+            asm.call(find_boot);
+            asm.bne(A0, Zero, boot);
+            asm.bind(not_bootable);
+            asm.la(A1, not_bootable_text);
+            asm.call(puts);
+            asm.j(prompt);
+        },
+        None => {
+            asm.la(A1, no_tps_text);
+            asm.call(puts);
+            asm.j(prompt);
+        },
+    }
+
+    asm.bind(command(b'?'));
+    asm.la(A1, help_text);
+    asm.call(puts);
+    asm.j(prompt);
+
+    // Loads sector 0 of the tps in s1 and calls it
+    if tps.is_some() {
+        asm.bind(boot);
+        asm.la(A1, booting_text);
+        asm.call(puts);
+        asm.mv(A1, S1);
+        asm.li(A2, 1);
+        asm.call(puthex);
+        asm.mv(A0, S1);
+        asm.li(A1, 0);
+        asm.li(A2, BOOT >> SECTOR_BITS);
+        asm.call(tps_load);
+        asm.bne(A0, Zero, failed);
+        asm.mv(A0, S1);
+        asm.li(T0, BOOT);
+        asm.emit(Instruction::I(I::Jalr(Ra, T0, 0)));
+        asm.j(prompt);
+    }
+
+    // Exceptions print their number and go back to the prompt
+    asm.bind(stubs);
+    let exception = asm.label();
+    for vector in 0..VECTORS {
+        asm.li(A0, vector as u32);
+        asm.j(exception);
+    }
+    asm.bind(exception);
+    asm.li(Sp, STACK);
+    asm.mv(S1, A0);
+    asm.la(A1, exception_text);
+    asm.call(puts);
+    asm.mv(A1, S1);
+    asm.li(A2, 2);
+    asm.call(puthex);
+    asm.j(prompt);
+
+    // Subroutines, none of them calls another so ra is all they need
+
+    // find_boot: a0 is non zero and s1 the tps number if one of them can boot
+    if let Some(tps) = tps {
+        asm.bind(find_boot);
+        let found = asm.label();
+        for number in 0..2 {
+            asm.li(S1, number);
+            is_bootable(&mut asm, tps, S1);
+            asm.bne(A0, Zero, found);
+        }
+        asm.bind(found);
+        asm.ret();
+
+        asm.bind(tps_load);
+        let first = asm.label();
+        asm.sbd(A2, Zero, tps + tps::Register::POINTL as i32);
+        asm.shri(T0, A2, 8);
+        asm.sbd(T0, Zero, tps + tps::Register::POINTH as i32);
+        asm.sbd(A1, Zero, tps + tps::Register::DATA as i32);
+        asm.li(T0, tps::Command::LoadSector as u32);
+        asm.beq(A0, Zero, first);
+        asm.ori(T0, T0, 0x80);
+        asm.bind(first);
+        asm.sbd(T0, Zero, tps + tps::Register::COMMAND as i32);
+        wait(&mut asm, tps + tps::Register::STATUSH as i32);
+        fault(&mut asm, tps + tps::Register::STATUSL as i32);
+        asm.ret();
+    }
+
+    if let Some(disk) = disk {
+        asm.bind(disk_load);
+        asm.sbd(A0, Zero, disk + drive::Register::DATA as i32);
+        asm.sbd(A1, Zero, disk + drive::Register::SECTORL as i32);
+        asm.shri(T0, A1, 8);
+        asm.sbd(T0, Zero, disk + drive::Register::SECTORH as i32);
+        asm.sbd(A2, Zero, disk + drive::Register::POINTL as i32);
+        asm.shri(T0, A2, 8);
+        asm.sbd(T0, Zero, disk + drive::Register::POINTH as i32);
+        asm.li(T0, drive::Command::LoadSector as u32);
+        asm.sbd(T0, Zero, disk + drive::Register::COMMAND as i32);
+        wait(&mut asm, disk + drive::Register::STATUS0 as i32);
+        fault(&mut asm, disk + drive::Register::STATUS1 as i32);
+        asm.ret();
+    }
+
+    // puts: the string at a1
+    asm.bind(puts);
+    let done = asm.label();
+    let next = asm.here();
+    asm.lbu(T0, A1, 0);
+    asm.beq(T0, Zero, done);
+    putc(&mut asm, tty, T0);
+    asm.addi(A1, A1, 1);
+    asm.j(next);
+    asm.bind(done);
+    asm.ret();
+
+    // putchar: the byte in a0
+    asm.bind(putchar);
+    putc(&mut asm, tty, A0);
+    asm.ret();
+
+    // puthex: the low a2 digits of a1
+    asm.bind(puthex);
+    let done = asm.label();
+    asm.shli(T2, A2, 2);
+    let next = asm.here();
+    let digit = asm.label();
+    asm.beq(T2, Zero, done);
+    asm.subi(T2, T2, 4);
+    asm.emit(Instruction::R(R::ShRl(T0, A1, T2)));
+    asm.andi(T0, T0, 0xf);
+    asm.li(T1, 10);
+    asm.bltu(T0, T1, digit);
+    asm.addi(T0, T0, (b'a' - b'0' - 10) as i32);
+    asm.bind(digit);
+    asm.addi(T0, T0, b'0' as i32);
+    putc(&mut asm, tty, T0);
+    asm.j(next);
+    asm.bind(done);
+    asm.ret();
+
+    // getline: reads a line into LINE with echo and backspace, a1 points to it
+    asm.bind(getline);
+    let (end, back, next) = (asm.label(), asm.label(), asm.label());
+    asm.li(A1, LINE);
+    asm.li(T2, 0);
+    asm.bind(next);
+    getc(&mut asm, tty, T0);
+    for (c, label) in [(b'\r', end), (b'\n', end), (0x08, back), (0x7f, back)] {
+        asm.li(T1, c as u32);
+        asm.beq(T0, T1, label);
+    }
+    asm.li(T1, b' ' as u32);
+    asm.bltu(T0, T1, next);
+    asm.li(T1, (LINE_SIZE - 1) as u32);
+    asm.beq(T2, T1, next);
+    asm.emit(Instruction::R(R::Add(T1, A1, T2)));
+    asm.sb(T0, T1, 0);
+    asm.addi(T2, T2, 1);
+    putc(&mut asm, tty, T0);
+    asm.j(next);
+    asm.bind(back);
+    asm.beq(T2, Zero, next);
+    asm.subi(T2, T2, 1);
+    for c in [0x08, b' ', 0x08] {
+        asm.li(T0, c as u32);
+        putc(&mut asm, tty, T0);
+    }
+    asm.j(next);
+    asm.bind(end);
+    asm.emit(Instruction::R(R::Add(T1, A1, T2)));
+    asm.sb(Zero, T1, 0);
+    asm.ret();
+
+    // hex: parses the number at a1 into a0, skipping blanks and a 0x. a2 counts its digits and
+    // a1 is left after it
+    asm.bind(hex);
+    let (start, next, letter, digit) = (asm.label(), asm.label(), asm.label(), asm.label());
+    let skip = asm.here();
+    asm.lbu(T0, A1, 0);
+    asm.li(T1, b' ' as u32);
+    asm.bne(T0, T1, start);
+    asm.addi(A1, A1, 1);
+    asm.j(skip);
+    asm.bind(start);
+    asm.li(A0, 0);
+    asm.li(A2, 0);
+    asm.li(T1, b'0' as u32);
+    asm.bne(T0, T1, next);
+    asm.lbu(T0, A1, 1);
+    asm.li(T1, b'x' as u32);
+    asm.bne(T0, T1, next);
+    asm.addi(A1, A1, 2);
+    asm.bind(next);
+    asm.lbu(T0, A1, 0);
+    asm.subi(T1, T0, b'0' as i32);
+    asm.li(T2, 10);
+    asm.bltu(T1, T2, digit);
+    asm.subi(T1, T0, b'a' as i32);
+    asm.li(T2, 6);
+    asm.bltu(T1, T2, letter);
+    asm.subi(T1, T0, b'A' as i32);
+    asm.bltu(T1, T2, letter);
+    asm.ret();
+    asm.bind(letter);
+    asm.addi(T1, T1, 10);
+    asm.bind(digit);
+    asm.shli(A0, A0, 4);
+    asm.emit(Instruction::R(R::Or(A0, A0, T1)));
+    asm.addi(A1, A1, 1);
+    asm.addi(A2, A2, 1);
+    asm.j(next);
+
+    let image = asm.finish()?;
+    if image.len() as u32 > STACK - 0x1000 {
+        return Err(Error::new("bios: the monitor runs into its own stack"));
+    }
+    Ok(image)
+}
@@ -1,6 +1,9 @@
 pub mod execute;
 pub mod debugger;
 pub mod decode;
+pub mod disassemble;
+pub mod encode;
 pub mod instructions;
 pub mod state;
-pub mod mmu;
\ No newline at end of file
+pub mod mmu;
+pub mod trace;
\ No newline at end of file
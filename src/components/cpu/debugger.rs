@@ -2,7 +2,8 @@ use organum::core::{Address, Debuggable};
 use organum::error::Error;
 use organum::sys::System;
 
-use crate::components::cpu::state::Sirius;
+use crate::components::cpu::disassemble::disassemble_range;
+use crate::components::cpu::state::{Register, Sirius, StatusReg, REGISTER_NAMES};
 
 pub struct Debugger {
     pub enabled: bool,
@@ -44,24 +45,90 @@ impl Debuggable for Sirius {
         }
     }
 
-    fn print_current_step(&mut self, _system: &System) -> Result<(), Error> {
-        self.decoder.decode_at(&mut self.port, self.state.pc)?;
-        self.decoder.dump_decoded(&mut self.port);
-        self.dump_state();
+    // The 32 general purpose registers followed by pc, psr, ssp and usp. sp is the stack pointer
+    // of the current mode, as the instructions see it
+    fn get_registers(&mut self) -> Vec<u32> {
+        let mut regs = self.state.reg.to_vec();
+        regs[Register::Sp as usize] = self.get_reg(Register::Sp);
+        regs.push(self.get_pc());
+        regs.push(u32::from_be_bytes(self.state.psr.into_bytes()));
+        regs.push(self.state.ssp);
+        regs.push(self.state.usp);
+        regs
+    }
+
+    fn set_register(&mut self, index: usize, value: u32) -> Result<(), Error> {
+        match index {
+            // zero is hardwired
+            0 => { },
+            2 => *self.get_stack_pointer_mut() = value,
+            1..=31 => self.state.reg[index] = value,
+            32 => self.set_pc(value)?,
+            33 => self.replace_psr(StatusReg::from_bytes(value.to_be_bytes()))?,
+            34 => self.state.ssp = value,
+            35 => self.state.usp = value,
+            _ => return Err(Error::new(&format!("Invalid register number {}", index))),
+        }
+        Ok(())
+    }
+
+    fn target_description(&self) -> Option<String> {
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\"?>\n",
+            "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+            "<target version=\"1.0\">\n",
+            "  <feature name=\"org.talea.sirius.cpu\">\n",
+        ));
+        for (regnum, name) in REGISTER_NAMES.iter().enumerate() {
+            let kind = match Register::from(regnum) {
+                Register::Ra => "code_ptr",
+                Register::Sp | Register::Fp => "data_ptr",
+                _ => "uint32",
+            };
+            xml += &format!("    <reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" type=\"{}\"/>\n", name, regnum, kind);
+        }
+        xml += "    <reg name=\"pc\" bitsize=\"32\" regnum=\"32\" type=\"code_ptr\"/>\n";
+        xml += "    <reg name=\"psr\" bitsize=\"32\" regnum=\"33\" type=\"uint32\"/>\n";
+        xml += "    <reg name=\"ssp\" bitsize=\"32\" regnum=\"34\" type=\"data_ptr\"/>\n";
+        xml += "    <reg name=\"usp\" bitsize=\"32\" regnum=\"35\" type=\"data_ptr\"/>\n";
+        xml += "  </feature>\n</target>\n";
+        Some(xml)
+    }
+
+    fn print_current_step(&mut self, system: &System) -> Result<(), Error> {
+        let pc = self.physical_pc()?;
+        self.decoder.decode_at(&mut self.port, pc)?;
+        self.decoder.dump_decoded(&mut self.port, &system.symbols);
+        self.dump_state(&system.symbols);
         Ok(())
     }
 
-    fn execute_command(&mut self, _system: &System, _args: &[&str]) -> Result<bool, Error> {
-        /* no commands to execute */
-        Ok(false)
+    fn execute_command(&mut self, system: &System, args: &[&str]) -> Result<bool, Error> {
+        match args[0] {
+            "da" | "disasm" => {
+                let addr = match args.get(1) {
+                    Some(addr) => system.symbols.parse_address(addr)? as u32,
+                    None => self.physical_pc()?,
+                };
+                let count = match args.get(2) {
+                    Some(count) => usize::from_str_radix(count, 10).map_err(|_| Error::new("Unable to parse count"))?,
+                    None => 8,
+                };
+                for line in disassemble_range(&mut self.port, addr, count, &system.symbols)? {
+                    println!("{}", line);
+                }
+                Ok(false)
+            },
+            _ => Ok(true),
+        }
     }
 }
 
 impl Sirius {
     pub fn check_breakpoints(&mut self, system: &System) {
         for breakpoint in &self.debugger.breakpoints {
-            if *breakpoint == self.state.pc {
-                println!("Breakpoint reached: {:08x}", *breakpoint);
+            if *breakpoint == self.get_pc() {
+                println!("Breakpoint reached: {}", system.symbols.format_address(*breakpoint as Address));
                 system.enable_debugging();
                 break;
             }
@@ -1,6 +1,8 @@
 use organum::core::{Address, Addressable};
 use organum::error::Error;
+use organum::symbols::SymbolTable;
 
+use crate::components::cpu::disassemble::disassemble;
 use crate::components::cpu::instructions::{Instruction, InstructionType, B, I, J, M, R, S, T, U};
 use crate::components::cpu::mmu::Mmu;
 use crate::components::{Uptr, Word};
@@ -32,97 +34,110 @@ impl Decoder {
 
     pub fn decode_one(&mut self, memory: &mut dyn Addressable) -> Result<Instruction, Error> {
         let ins = self.read_instruction(memory)?;
-        let instype = InstructionType::from(ins);
-
-        match instype {
-            InstructionType::Undefined => Ok(Instruction::Undefined(ins)),
-            InstructionType::U => {
-                let i = U::try_from(ins);
-                if i.is_err() {
-                    return Ok(Instruction::Undefined(ins));
-                }
+        Ok(decode_word(ins))
+    }
 
-                Ok(Instruction::U(i.unwrap()))
-            }
-            InstructionType::J => {
-                let i = J::try_from(ins);
-                if i.is_err() {
-                    return Ok(Instruction::Undefined(ins));
-                }
+    fn read_instruction(&mut self, device: &mut dyn Addressable) -> Result<Word, Error> {
+        let word = device.read_beu32(Address::from(self.at))?;
+        self.instruction_word = word;
+        self.at = self.at.wrapping_add(4);
+        Ok(word)
+    }
 
-                Ok(Instruction::J(i.unwrap()))
-            }
-            InstructionType::B => {
-                let i = B::try_from(ins);
-                if i.is_err() {
-                    return Ok(Instruction::Undefined(ins));
-                }
+    // Address of the last decoded instruction, `at` is left pointing past it
+    pub fn decoded_at(&self) -> Uptr {
+        self.at.wrapping_sub(4)
+    }
 
-                Ok(Instruction::B(i.unwrap()))
-            }
-            InstructionType::I => {
-                let i = I::try_from(ins);
-                if i.is_err() {
-                    return Ok(Instruction::Undefined(ins));
+    // Bytes that can't be read, as before the first decode on a machine with little memory, show as ??
+    pub fn format_instruction_bytes(&mut self, memory: &mut dyn Addressable) -> String {
+        let ins_data: String = (0..4)
+            .map(|offset| {
+                match memory.read_u8(Address::from(self.decoded_at()) + offset) {
+                    Ok(byte) => format!("{:02x} ", byte),
+                    Err(_) => "?? ".to_string(),
                 }
+            })
+            .collect();
+        ins_data
+    }
 
-                Ok(Instruction::I(i.unwrap()))
+    pub fn dump_decoded(&mut self, memory: &mut dyn Addressable, symbols: &SymbolTable) {
+        let ins_data = self.format_instruction_bytes(memory);
+        let at = self.decoded_at();
+        println!("{}: {}\n\t{}\n", symbols.format_address(at as Address), ins_data, disassemble(&self.instruction, at, symbols));
+    }
+}
+
+// Decodes a single instruction word, words that don't make sense become `Instruction::Undefined`
+pub fn decode_word(ins: Word) -> Instruction {
+    let instype = InstructionType::from(ins);
+
+    match instype {
+        InstructionType::Undefined => Instruction::Undefined(ins),
+        InstructionType::U => {
+            let i = U::try_from(ins);
+            if i.is_err() {
+                return Instruction::Undefined(ins);
             }
-            InstructionType::R => {
-                let i = R::try_from(ins);
-                if i.is_err() {
-                    return Ok(Instruction::Undefined(ins));
-                }
 
-                Ok(Instruction::R(i.unwrap()))
+            Instruction::U(i.unwrap())
+        }
+        InstructionType::J => {
+            let i = J::try_from(ins);
+            if i.is_err() {
+                return Instruction::Undefined(ins);
             }
-            InstructionType::S => {
-                let i = S::try_from(ins);
-                if i.is_err() {
-                    return Ok(Instruction::Undefined(ins));
-                }
 
-                Ok(Instruction::S(i.unwrap()))
+            Instruction::J(i.unwrap())
+        }
+        InstructionType::B => {
+            let i = B::try_from(ins);
+            if i.is_err() {
+                return Instruction::Undefined(ins);
             }
-            InstructionType::M => {
-                let i = M::try_from(ins);
-                if i.is_err() {
-                    return Ok(Instruction::Undefined(ins));
-                }
 
-                Ok(Instruction::M(i.unwrap()))
+            Instruction::B(i.unwrap())
+        }
+        InstructionType::I => {
+            let i = I::try_from(ins);
+            if i.is_err() {
+                return Instruction::Undefined(ins);
             }
-            InstructionType::T => {
-                let i = T::try_from(ins);
-                if i.is_err() {
-                    return Ok(Instruction::Undefined(ins));
-                }
 
-                Ok(Instruction::T(i.unwrap()))
+            Instruction::I(i.unwrap())
+        }
+        InstructionType::R => {
+            let i = R::try_from(ins);
+            if i.is_err() {
+                return Instruction::Undefined(ins);
             }
+
+            Instruction::R(i.unwrap())
         }
-    }
+        InstructionType::S => {
+            let i = S::try_from(ins);
+            if i.is_err() {
+                return Instruction::Undefined(ins);
+            }
 
-    fn read_instruction(&mut self, device: &mut dyn Addressable) -> Result<Word, Error> {
-        let word = device.read_beu32(Address::from(self.at))?;
-        self.at = self.at.wrapping_add(4);
-        Ok(word)
-    }
+            Instruction::S(i.unwrap())
+        }
+        InstructionType::M => {
+            let i = M::try_from(ins);
+            if i.is_err() {
+                return Instruction::Undefined(ins);
+            }
 
-    pub fn format_instruction_bytes(&mut self, memory: &mut dyn Addressable) -> String {
-        let ins_data: String = (0..4)
-            .map(|offset| {
-                format!(
-                    "{:02x} ",
-                    memory.read_u8(Address::from(self.at) + offset).unwrap()
-                )
-            })
-            .collect();
-        ins_data
-    }
+            Instruction::M(i.unwrap())
+        }
+        InstructionType::T => {
+            let i = T::try_from(ins);
+            if i.is_err() {
+                return Instruction::Undefined(ins);
+            }
 
-    pub fn dump_decoded(&mut self, memory: &mut dyn Addressable) {
-        let ins_data = self.format_instruction_bytes(memory);
-        println!("{:#06x}: {}\n\t{:?}\n", self.at, ins_data, self.instruction);
+            Instruction::T(i.unwrap())
+        }
     }
 }
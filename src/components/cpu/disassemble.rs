@@ -0,0 +1,151 @@
+// disassemble.rs turns decoded instructions back into the syntax accepted by tests/asm/lib/master.asm
+use organum::core::{Address, Addressable};
+use organum::error::Error;
+use organum::symbols::SymbolTable;
+
+use crate::components::cpu::decode::Decoder;
+use crate::components::cpu::instructions::{Instruction, B, I, J, M, R, S, T, U};
+use crate::components::cpu::state::{Register, REGISTER_NAMES};
+use crate::components::Uptr;
+
+#[inline(always)]
+fn reg(r: Register) -> &'static str {
+    REGISTER_NAMES[usize::from(r)]
+}
+
+// Jumps and branches are relative to the address of the instruction itself
+#[inline(always)]
+fn target(at: Uptr, offset: i32, symbols: &SymbolTable) -> String {
+    symbols.format_address(at.wrapping_add(offset as u32) as Address)
+}
+
+pub fn disassemble(instruction: &Instruction, at: Uptr, symbols: &SymbolTable) -> String {
+    match *instruction {
+        Instruction::Undefined(word) => format!("#d32 {:#010x}", word),
+        Instruction::U(instruction) => match instruction {
+            U::Lui(rd, imm) => format!("lui {}, {:#x}", reg(rd), imm >> 12),
+            U::Auipc(rd, imm) => format!("auipc {}, {:#x}", reg(rd), imm >> 12),
+        },
+        Instruction::J(instruction) => match instruction {
+            J::Jal(rd, imm) => format!("jal {}, {}", reg(rd), target(at, imm, symbols)),
+        },
+        Instruction::B(instruction) => {
+            let (mnemonic, rs1, rs2, imm) = match instruction {
+                B::Beq(rs1, rs2, imm) => ("beq", rs1, rs2, imm),
+                B::Bne(rs1, rs2, imm) => ("bne", rs1, rs2, imm),
+                B::Blt(rs1, rs2, imm) => ("blt", rs1, rs2, imm),
+                B::Bge(rs1, rs2, imm) => ("bge", rs1, rs2, imm),
+                B::Bltu(rs1, rs2, imm) => ("bltu", rs1, rs2, imm),
+                B::Bgeu(rs1, rs2, imm) => ("bgeu", rs1, rs2, imm),
+            };
+            format!("{} {}, {}, {}", mnemonic, reg(rs1), reg(rs2), target(at, imm, symbols))
+        },
+        Instruction::I(instruction) => match instruction {
+            I::Jalr(rd, rs1, imm) => format!("jalr {}, {}({})", reg(rd), imm, reg(rs1)),
+
+            I::Lb(rd, rs1, imm) => format!("lb {}, {}({})", reg(rd), imm, reg(rs1)),
+            I::Lbu(rd, rs1, imm) => format!("lbu {}, {}({})", reg(rd), imm, reg(rs1)),
+            I::Lbd(rd, rs1, imm) => format!("lbd {}, {}({})", reg(rd), imm, reg(rs1)),
+            I::Lbud(rd, rs1, imm) => format!("lbud {}, {}({})", reg(rd), imm, reg(rs1)),
+            I::Lh(rd, rs1, imm) => format!("lh {}, {}({})", reg(rd), imm, reg(rs1)),
+            I::Lhu(rd, rs1, imm) => format!("lhu {}, {}({})", reg(rd), imm, reg(rs1)),
+            I::Lhd(rd, rs1, imm) => format!("lhd {}, {}({})", reg(rd), imm, reg(rs1)),
+            I::Lhud(rd, rs1, imm) => format!("lhud {}, {}({})", reg(rd), imm, reg(rs1)),
+            I::Lw(rd, rs1, imm) => format!("lw {}, {}({})", reg(rd), imm, reg(rs1)),
+            I::Lwd(rd, rs1, imm) => format!("lwd {}, {}({})", reg(rd), imm, reg(rs1)),
+
+            I::Muli(rd, rs1, imm) => format!("muli {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::Mulih(rd, rs1, imm) => format!("mulih {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::Idivi(rd, rs1, imm) => format!("idivi {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::Addi(rd, rs1, imm) => format!("addi {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::Subi(rd, rs1, imm) => format!("subi {}, {}, {}", reg(rd), reg(rs1), imm),
+
+            I::Ori(rd, rs1, imm) => format!("ori {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::Andi(rd, rs1, imm) => format!("andi {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::Xori(rd, rs1, imm) => format!("xori {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::ShiRa(rd, rs1, imm) => format!("shira {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::ShiRl(rd, rs1, imm) => format!("shirl {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::ShiLl(rd, rs1, imm) => format!("shill {}, {}, {}", reg(rd), reg(rs1), imm),
+            I::Slti(rd, rs1, imm) => format!("slti {}, {}, {}", reg(rd), reg(rs1), imm),
+            // sltiu takes an unsigned immediate in the assembler
+            I::Sltiu(rd, rs1, imm) => format!("sltiu {}, {}, {}", reg(rd), reg(rs1), imm as u32 & 0x7fff),
+        },
+        Instruction::R(instruction) => match instruction {
+            R::Add(rd, rs1, rs2) => format!("add {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            R::Sub(rd, rs1, rs2) => format!("sub {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            R::Idiv(rd, rd2, rs1, rs2) => format!("idiv {}, {}, {}, {}", reg(rd), reg(rd2), reg(rs1), reg(rs2)),
+            R::Mul(rd, rd2, rs1, rs2) => format!("mul {}, {}, {}, {}", reg(rd), reg(rd2), reg(rs1), reg(rs2)),
+
+            R::Or(rd, rs1, rs2) => format!("or {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            R::And(rd, rs1, rs2) => format!("and {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            R::Xor(rd, rs1, rs2) => format!("xor {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+
+            R::Not(rd, rs1) => format!("not {}, {}", reg(rd), reg(rs1)),
+            R::Ctz(rd, rs1) => format!("ctz {}, {}", reg(rd), reg(rs1)),
+            R::Clz(rd, rs1) => format!("clz {}, {}", reg(rd), reg(rs1)),
+            R::Popcount(rd, rs1) => format!("pcount {}, {}", reg(rd), reg(rs1)),
+
+            R::ShRa(rd, rs1, rs2) => format!("shra {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            R::ShRl(rd, rs1, rs2) => format!("shrl {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            R::ShLl(rd, rs1, rs2) => format!("shll {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            R::Ror(rd, rs1, rs2) => format!("ror {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            R::Rol(rd, rs1, rs2) => format!("rol {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+        },
+        Instruction::S(instruction) => {
+            let (mnemonic, rs2, rs1, imm) = match instruction {
+                S::Sb(rs2, rs1, imm) => ("sb", rs2, rs1, imm),
+                S::Sbd(rs2, rs1, imm) => ("sbd", rs2, rs1, imm),
+                S::Sh(rs2, rs1, imm) => ("sh", rs2, rs1, imm),
+                S::Shd(rs2, rs1, imm) => ("shd", rs2, rs1, imm),
+                S::Sw(rs2, rs1, imm) => ("sw", rs2, rs1, imm),
+                S::Swd(rs2, rs1, imm) => ("swd", rs2, rs1, imm),
+            };
+            format!("{} {}, {}({})", mnemonic, reg(rs2), imm, reg(rs1))
+        },
+        Instruction::M(instruction) => match instruction {
+            M::Copy(rd, rs1, rs2) => format!("copy {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            M::Swap(rd, rs1, rs2) => format!("swap {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            M::Fill(rd, rs1, rs2) => format!("fill {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            M::Through(rd, rs1) => format!("thro {}, {}", reg(rd), reg(rs1)),
+            M::From(rd, rs1) => format!("from {}, {}", reg(rd), reg(rs1)),
+
+            M::Popb(rd, rs1) => format!("popb {}, {}", reg(rd), reg(rs1)),
+            M::Poph(rd, rs1) => format!("poph {}, {}", reg(rd), reg(rs1)),
+            M::Pop(rd, rs1) => format!("pop {}, {}", reg(rd), reg(rs1)),
+            M::Pushb(rd, rs1) => format!("pushb {}, {}", reg(rd), reg(rs1)),
+            M::Pushh(rd, rs1) => format!("pushh {}, {}", reg(rd), reg(rs1)),
+            M::Push(rd, rs1) => format!("push {}, {}", reg(rd), reg(rs1)),
+
+            M::Save(rd, rs1, rs2) => format!("save {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            M::Restore(rd, rs1, rs2) => format!("restore {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            M::Exch(rd, rs1) => format!("exch {}, {}", reg(rd), reg(rs1)),
+            M::Slt(rd, rs1, rs2) => format!("slt {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+            M::Sltu(rd, rs1, rs2) => format!("sltu {}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+        },
+        Instruction::T(instruction) => match instruction {
+            T::Syscall(rd, vector) => format!("syscall {}, {:#04x}", reg(rd), vector),
+            T::GsReg(rd) => format!("gsreg {}", reg(rd)),
+            T::SsReg(rs1) => format!("ssreg {}", reg(rs1)),
+            T::Sysret => "sysret".to_string(),
+            T::TlbFlush => "tlbflush".to_string(),
+            T::TlbInvalidate(rs1) => format!("tlbinv {}", reg(rs1)),
+        },
+    }
+}
+
+pub fn disassemble_range(memory: &mut dyn Addressable, at: Uptr, count: usize, symbols: &SymbolTable) -> Result<Vec<String>, Error> {
+    let mut decoder = Decoder::new();
+    let mut lines = Vec::with_capacity(count);
+    let mut at = at;
+
+    for _ in 0..count {
+        let word = memory.read_beu32(at as Address)?;
+        decoder.decode_at(memory, at)?;
+        if let Some((label, 0)) = symbols.lookup(at as Address) {
+            lines.push(format!("{}:", label));
+        }
+        lines.push(format!("{:#010x}: {:08x}  {}", at, word, disassemble(&decoder.instruction, at, symbols)));
+        at = at.wrapping_add(4);
+    }
+    Ok(lines)
+}
@@ -0,0 +1,173 @@
+// encode.rs turns instructions back into words, the inverse of `decode_word`. Offsets and immediates
+// are taken as the decoder gives them, the caller makes sure they fit their fields
+use crate::components::cpu::instructions::*;
+use crate::components::cpu::state::Register as Reg;
+use crate::components::Word;
+
+#[inline(always)]
+fn op(group: u8, opcode: u8) -> Word {
+    ((group | opcode) as Word) << GROUP_SHIFT
+}
+
+#[inline(always)]
+fn rd(reg: Reg) -> Word {
+    (reg as Word) << RD_SHIFT
+}
+
+#[inline(always)]
+fn rs1(reg: Reg) -> Word {
+    (reg as Word) << RS1_SHIFT
+}
+
+#[inline(always)]
+fn rs2(reg: Reg) -> Word {
+    (reg as Word) << RS2_SHIFT
+}
+
+#[inline(always)]
+fn rs3(reg: Reg) -> Word {
+    (reg as Word) << RS3_SHIFT
+}
+
+#[inline(always)]
+fn imm15(imm: i32) -> Word {
+    imm as Word & IMM15_MASK
+}
+
+fn encode_u(instruction: &U) -> Word {
+    match *instruction {
+        U::Lui(d, imm) => op(LUI, 0) | rd(d) | (imm >> 12),
+        U::Auipc(d, imm) => op(AUIPC, 0) | rd(d) | (imm >> 12),
+    }
+}
+
+fn encode_b(instruction: &B) -> Word {
+    let (opcode, a, b, offset) = match *instruction {
+        B::Beq(a, b, offset) => (0x0, a, b, offset),
+        B::Bne(a, b, offset) => (0x1, a, b, offset),
+        B::Blt(a, b, offset) => (0x2, a, b, offset),
+        B::Bge(a, b, offset) => (0x3, a, b, offset),
+        B::Bltu(a, b, offset) => (0x4, a, b, offset),
+        B::Bgeu(a, b, offset) => (0x5, a, b, offset),
+    };
+    op(BRANCH, opcode) | rd(a) | rs1(b) | imm15(offset >> 2)
+}
+
+fn encode_i(instruction: &I) -> Word {
+    let (group, opcode, d, s, imm) = match *instruction {
+        I::Jalr(d, s, imm) => (LOAD, 0x1, d, s, imm),
+
+        I::Lb(d, s, imm) => (LOAD, 0x2, d, s, imm),
+        I::Lbu(d, s, imm) => (LOAD, 0x3, d, s, imm),
+        I::Lbd(d, s, imm) => (LOAD, 0x4, d, s, imm),
+        I::Lbud(d, s, imm) => (LOAD, 0x5, d, s, imm),
+        I::Lh(d, s, imm) => (LOAD, 0x6, d, s, imm),
+        I::Lhu(d, s, imm) => (LOAD, 0x7, d, s, imm),
+        I::Lhd(d, s, imm) => (LOAD, 0x8, d, s, imm),
+        I::Lhud(d, s, imm) => (LOAD, 0x9, d, s, imm),
+        I::Lw(d, s, imm) => (LOAD, 0xa, d, s, imm),
+        I::Lwd(d, s, imm) => (LOAD, 0xb, d, s, imm),
+
+        I::Muli(d, s, imm) => (ALUI, 0x0, d, s, imm),
+        I::Mulih(d, s, imm) => (ALUI, 0x1, d, s, imm),
+        I::Idivi(d, s, imm) => (ALUI, 0x2, d, s, imm),
+        I::Addi(d, s, imm) => (ALUI, 0x3, d, s, imm),
+        I::Subi(d, s, imm) => (ALUI, 0x4, d, s, imm),
+
+        I::Ori(d, s, imm) => (ALUI, 0x5, d, s, imm),
+        I::Andi(d, s, imm) => (ALUI, 0x6, d, s, imm),
+        I::Xori(d, s, imm) => (ALUI, 0x7, d, s, imm),
+        I::ShiRa(d, s, imm) => (ALUI, 0x8, d, s, imm),
+        I::ShiRl(d, s, imm) => (ALUI, 0x9, d, s, imm),
+        I::ShiLl(d, s, imm) => (ALUI, 0xa, d, s, imm),
+        I::Slti(d, s, imm) => (ALUI, 0xb, d, s, imm),
+        I::Sltiu(d, s, imm) => (ALUI, 0xc, d, s, imm),
+    };
+    op(group, opcode) | rd(d) | rs1(s) | imm15(imm)
+}
+
+fn encode_r(instruction: &R) -> Word {
+    let (opcode, fields) = match *instruction {
+        R::Add(d, a, b) => (0x0, rd(d) | rs1(a) | rs2(b)),
+        R::Sub(d, a, b) => (0x1, rd(d) | rs1(a) | rs2(b)),
+        R::Idiv(d, d2, a, b) => (0x2, rd(d) | rs1(d2) | rs2(a) | rs3(b)),
+        R::Mul(d, d2, a, b) => (0x3, rd(d) | rs1(d2) | rs2(a) | rs3(b)),
+
+        R::Or(d, a, b) => (0x4, rd(d) | rs1(a) | rs2(b)),
+        R::And(d, a, b) => (0x5, rd(d) | rs1(a) | rs2(b)),
+        R::Xor(d, a, b) => (0x6, rd(d) | rs1(a) | rs2(b)),
+
+        R::Not(d, a) => (0x7, rd(d) | rs1(a)),
+        R::Ctz(d, a) => (0x8, rd(d) | rs1(a)),
+        R::Clz(d, a) => (0x9, rd(d) | rs1(a)),
+        R::Popcount(d, a) => (0xa, rd(d) | rs1(a)),
+
+        R::ShRa(d, a, b) => (0xb, rd(d) | rs1(a) | rs2(b)),
+        R::ShRl(d, a, b) => (0xc, rd(d) | rs1(a) | rs2(b)),
+        R::ShLl(d, a, b) => (0xd, rd(d) | rs1(a) | rs2(b)),
+        R::Ror(d, a, b) => (0xe, rd(d) | rs1(a) | rs2(b)),
+        R::Rol(d, a, b) => (0xf, rd(d) | rs1(a) | rs2(b)),
+    };
+    op(ALUR, opcode) | fields
+}
+
+fn encode_s(instruction: &S) -> Word {
+    let (opcode, value, base, imm) = match *instruction {
+        S::Sb(value, base, imm) => (0x0, value, base, imm),
+        S::Sbd(value, base, imm) => (0x1, value, base, imm),
+        S::Sh(value, base, imm) => (0x2, value, base, imm),
+        S::Shd(value, base, imm) => (0x3, value, base, imm),
+        S::Sw(value, base, imm) => (0x4, value, base, imm),
+        S::Swd(value, base, imm) => (0x5, value, base, imm),
+    };
+    op(STORE, opcode) | rd(value) | rs1(base) | imm15(imm)
+}
+
+fn encode_m(instruction: &M) -> Word {
+    let (opcode, fields) = match *instruction {
+        M::Copy(d, a, b) => (0x0, rd(d) | rs1(a) | rs2(b)),
+        M::Swap(d, a, b) => (0x1, rd(d) | rs1(a) | rs2(b)),
+        M::Fill(d, a, b) => (0x2, rd(d) | rs1(a) | rs2(b)),
+        M::Through(d, a) => (0x3, rd(d) | rs1(a)),
+        M::From(d, a) => (0x4, rd(d) | rs1(a)),
+
+        M::Popb(d, a) => (0x5, rd(d) | rs1(a)),
+        M::Poph(d, a) => (0x6, rd(d) | rs1(a)),
+        M::Pop(d, a) => (0x7, rd(d) | rs1(a)),
+        M::Pushb(d, a) => (0x8, rd(d) | rs1(a)),
+        M::Pushh(d, a) => (0x9, rd(d) | rs1(a)),
+        M::Push(d, a) => (0xa, rd(d) | rs1(a)),
+
+        M::Save(d, a, b) => (0xb, rd(d) | rs1(a) | rs2(b)),
+        M::Restore(d, a, b) => (0xc, rd(d) | rs1(a) | rs2(b)),
+        M::Exch(d, a) => (0xd, rd(d) | rs1(a)),
+        M::Slt(d, a, b) => (0xe, rd(d) | rs1(a) | rs2(b)),
+        M::Sltu(d, a, b) => (0xf, rd(d) | rs1(a) | rs2(b)),
+    };
+    op(MEM, opcode) | fields
+}
+
+fn encode_t(instruction: &T) -> Word {
+    match *instruction {
+        T::Syscall(d, vector) => op(SYS, 0x2) | rd(d) | vector as Word,
+        T::GsReg(d) => op(SYS, 0x3) | rd(d),
+        T::SsReg(s) => op(SYS, 0x4) | rd(s),
+        T::Sysret => op(SYS, 0x6),
+        T::TlbFlush => op(SYS, 0x7),
+        T::TlbInvalidate(s) => op(SYS, 0x8) | rd(s),
+    }
+}
+
+pub fn encode(instruction: &Instruction) -> Word {
+    match instruction {
+        Instruction::Undefined(word) => *word,
+        Instruction::U(instruction) => encode_u(instruction),
+        Instruction::J(J::Jal(d, offset)) => op(JAL, 0) | rd(*d) | ((*offset >> 2) as Word & IMM20_MASK),
+        Instruction::B(instruction) => encode_b(instruction),
+        Instruction::I(instruction) => encode_i(instruction),
+        Instruction::R(instruction) => encode_r(instruction),
+        Instruction::S(instruction) => encode_s(instruction),
+        Instruction::M(instruction) => encode_m(instruction),
+        Instruction::T(instruction) => encode_t(instruction),
+    }
+}
@@ -1,6 +1,6 @@
 use core::panic;
 
-use organum::core::{Address, Addressable, ClockElapsed, Debuggable, Steppable, Transmutable};
+use organum::core::{Address, Addressable, ClockElapsed, Debuggable, Snapshotable, Steppable, Transmutable};
 use organum::{
     error::{Error, ErrorType},
     sys::System,
@@ -15,9 +15,12 @@ S,
 M,
 T,};
 use crate::components::cpu::state::{
-    Exceptions, InterruptPriority, Register, Sirius, Status, StatusReg,
+    Access, Exceptions, InterruptPriority, Register, Sirius, State, Status, StatusReg,
 };
-use crate::components::{Word, IVT_SIZE};
+use crate::components::{TaleaCpuType, Word, IVT_SIZE};
+use crate::components::cpu::mmu::PT_SHIFT;
+use crate::components::cpu::trace::{TraceBus, Tracer};
+use organum::premade::bus::BusPort;
 
 const DEV_NAME: &'static str = "Sirius-cpu";
 
@@ -26,8 +29,20 @@ impl Steppable for Sirius {
         self.step_internal(system)
     }
 
-    fn on_error(&mut self, _system: &System) {
-        self.dump_state();
+    fn on_error(&mut self, system: &System) {
+        self.dump_state(&system.symbols);
+        // The end of the trace is usually the interesting part
+        if let Some(trace) = self.trace.as_mut() {
+            let _ = trace.flush();
+        }
+    }
+
+    fn on_shutdown(&mut self, _system: &System) {
+        if let Some(trace) = self.trace.as_mut() {
+            if let Err(err) = trace.flush() {
+                println!("{}", err.msg);
+            }
+        }
     }
 }
 
@@ -43,6 +58,10 @@ impl Transmutable for Sirius {
     fn as_interruptable(&mut self) -> Option<&mut dyn organum::core::Interruptable> {
         Some(self)
     }
+
+    fn as_snapshotable(&mut self) -> Option<&mut dyn Snapshotable> {
+        Some(self)
+    }
 }
 
 impl Sirius {
@@ -63,13 +82,15 @@ impl Sirius {
                         native,
                         ..
                     }) => {
-                        // TODO match arm conditional is temporary: illegal instructions generate a top level error in order to debug and fix issues with decode
-                        if native != Exceptions::IllegalInstruction as u32 {
-                        self.exception(native as u8, false)?;
-                        } else {
-                            println!("{:?} @ {:x}", self.decoder.instruction, self.state.pc);
-                            panic!("Illegal Instruction",)
+                        // Strict mode aborts instead of trapping, in order to debug and fix issues with decode
+                        if self.strict && native == Exceptions::IllegalInstruction as u32 {
+                            println!("{:?} @ {:x}", self.decoder.instruction, self.decoder.decoded_at());
+                            panic!("Illegal Instruction")
+                        } else if self.strict && native == Exceptions::PrivilegeViolation as u32 {
+                            println!("{:?} @ {:x}", self.decoder.instruction, self.decoder.decoded_at());
+                            panic!("Privilege Violation")
                         }
+                        self.exception(native as u8, false)?;
                         Ok(1)
                     }
                     Err(err) => Err(err),
@@ -89,13 +110,35 @@ impl Sirius {
 
     pub fn cycle_one(&mut self, system: &System) -> Result<ClockElapsed, Error> {
         self.decode_next()?;
-        self.execute_current()?;
+        if self.trace.is_some() {
+            self.trace_current(system)?;
+        } else {
+            self.execute_current()?;
+        }
 
         self.check_pending_interrupts(system)?;
         self.check_breakpoints(system);
         Ok((1_000_000_000 / self.frequency as u64) * 5 as ClockElapsed )
     }
 
+    // Executes the current instruction while the tracer looks at what it changes
+    fn trace_current(&mut self, system: &System) -> Result<(), Error> {
+        let (pc, vpc, word, supervisor) = (self.decoder.decoded_at(), self.state.instruction_pc, self.decoder.instruction_word, self.is_supervisor());
+        let trace = self.trace.as_mut().unwrap();
+        trace.begin(system.clock, pc, vpc, word, supervisor);
+        if !trace.is_recording() {
+            return self.execute_current();
+        }
+
+        let before = self.get_registers();
+        self.execute_current()?;
+        let after = self.get_registers();
+
+        let trace = self.trace.as_mut().unwrap();
+        trace.record_registers(&before, &after);
+        trace.end()
+    }
+
     pub fn check_pending_interrupts(&mut self, system: &System) -> Result<(), Error> {
         self.state.pending_ipl = match system.get_interrupt_controller().check() {
             (true, priority) => InterruptPriority::from_u8(priority),
@@ -130,35 +173,60 @@ impl Sirius {
     }
 
     pub fn exception(&mut self, number: u8, is_interrupt: bool) -> Result<(), Error> {
-        println!("{}: raising exception {}", DEV_NAME, number);
+        if self.debugger.enabled {
+            println!("{}: raising exception {}", DEV_NAME, number);
+        }
 
         // IMPORTANT: La diferencia entre excepción y fault es que la última intenta corregir el problema y VUELVE A EJECUTAR la instrucción que la causó
 
-        if number == Exceptions::BusError as u8 || number == Exceptions::AddressError as u8 {
-            let result = self.setup_fault(number);
-            // Double Fault
-            if let Err(err) = result {
-                self.state.status = Status::Stopped;
-                return Err(err);
-            }
-        } else if number == Exceptions::PrivilegeViolation as u8 {
-            panic!();
+        let result = if number == Exceptions::BusError as u8
+            || number == Exceptions::AddressError as u8
+            || number == Exceptions::IllegalInstruction as u8
+            || number == Exceptions::PrivilegeViolation as u8
+            || number == Exceptions::PageFault as u8
+            || number == Exceptions::AccessViolation as u8
+        {
+            self.setup_fault(number)
         } else {
-            self.setup_normal_exception(number, is_interrupt)?;
+            self.setup_normal_exception(number, is_interrupt)
+        };
+
+        // Double Fault: the frame or the vector couldn't be reached, so there's nothing left to run
+        if let Err(err) = result {
+            self.state.status = Status::Stopped;
+            return Err(match err.err {
+                ErrorType::Processor => Error::new(&format!(
+                    "{}: double fault, exception {} at {:#010x} while raising exception {}",
+                    DEV_NAME, err.native, self.state.fault_address, number
+                )),
+                _ => err,
+            });
         }
 
         Ok(())
     }
 
+    /* The fault frame, from the top of the supervisor stack:
+        ╭─────┬──────────────────────────────────────────╮
+        │sp+0 │instruction word                          │
+        │sp+4 │access: 0 none, 1 read, 2 write, 3 fetch  │
+        │sp+8 │faulting linear address                   │
+        │sp+12│psr                                       │
+        │sp+16│pc of the faulting instruction            │
+        ╰─────┴──────────────────────────────────────────╯
+    */
     pub fn setup_fault(&mut self, number: u8) -> Result<(), Error> {
         let ins_word = self.decoder.instruction_word;
-
-        self.push_long(Word::from(self.state.pc))?; // Retrocede antes de la excepcion
-        self.push_long(Word::from_le_bytes(self.state.psr.into_bytes()))?;
-        self.push_long(ins_word)?; // Guarda la instrucción en el stack
+        let psr = Word::from_le_bytes(self.state.psr.into_bytes());
 
         self.state.psr.set_supervisor(true);
 
+        self.push_long(self.state.instruction_pc)?; // Retrocede antes de la excepcion
+        self.push_long(psr)?;
+        self.push_long(self.state.fault_address)?;
+        self.push_long(self.state.fault_access as Word)?;
+        self.push_long(ins_word)?; // Guarda la instrucción en el stack
+
         let offset = (number as u16) << 2;
         let ivt =  self.state.psr.ivt() as u32 * IVT_SIZE as u32;
         let vector = ivt + offset as u32;
@@ -170,11 +238,14 @@ impl Sirius {
 
     pub fn setup_normal_exception(&mut self, number: u8, is_interrupt: bool) -> Result<(), Error> {
         //self.state.request.i_n_bit = true; // no entiendo esto
-        self.push_long(self.get_pc())?;
-        self.push_long(Word::from_le_bytes(self.state.psr.into_bytes()))?;
+        let psr = Word::from_le_bytes(self.state.psr.into_bytes());
 
+        // The frame always goes to the supervisor stack, where sysret will look for it
         self.state.psr.set_supervisor(true);
 
+        self.push_long(self.get_pc())?;
+        self.push_long(psr)?;
+
         if is_interrupt && self.state.psr.interrupt_enabled() {
             self.state
                 .psr
@@ -196,10 +267,17 @@ impl Sirius {
     }
 
     fn sysret(&mut self) -> Result<(), Error> {
+        let sp = *self.get_stack_pointer_mut();
         let psr = self.pop_long()?;
         let pc = self.pop_long()?;
 
-        self.state.psr = StatusReg::from_bytes(Word::to_le_bytes(psr));
+        // The frame is left on the stack, so the fault can be handled like any other
+        let psr = StatusReg::from_bytes(Word::to_le_bytes(psr));
+        if let Err(err) = self.check_psr(&psr) {
+            *self.get_stack_pointer_mut() = sp;
+            return Err(err);
+        }
+        self.update_psr(psr);
         self.set_pc(pc)?;
 
         Ok(())
@@ -207,8 +285,20 @@ impl Sirius {
 
     pub fn decode_next(&mut self) -> Result<(), Error> {
         let pc = self.get_pc();
-        self.decoder.decode_at(&mut self.port, pc)?;
-        self.set_pc(self.decoder.at)?;//TODO: I don't know how this behaves with paging
+        self.state.instruction_pc = pc;
+        self.state.fault_address = pc;
+        self.state.fault_access = Access::None;
+        self.decoder.instruction_word = 0;
+
+        // The pc is translated when fetching, so a fault in the target of a jump restarts at the target
+        let real = self.translate(pc as Address, Access::Fetch)?;
+        if self.state.psr.mmu_enabled() {
+            self.state.pc = real as Word;
+        }
+        let mask = self.port.address_mask();
+        self.decoder.decode_at(&mut self.port, real as Word)
+            .map_err(|err| bus_fault(&mut self.state, err, pc as Address, real & mask, Access::Fetch))?;
+        self.set_pc(pc.wrapping_add(4))?;
 
         Ok(())
     }
@@ -295,7 +385,7 @@ impl Sirius {
 
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
                         let value = self.get_reg(rd) as u8;
-                        self.port_d.write_u8(addr as Address, value)?;
+                        self.data_bus().write_u8(addr as Address, value)?;
                     },
                     S::Sh(rd, rs1, imm) => {
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
@@ -307,7 +397,7 @@ impl Sirius {
 
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
                         let value = self.get_reg(rd) as u16;
-                        self.port_d.write_beu16(addr as Address, value)?;
+                        self.data_bus().write_beu16(addr as Address, value)?;
                     },
                     S::Sw(rd, rs1, imm) => {
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
@@ -319,16 +409,17 @@ impl Sirius {
 
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
                         let value = self.get_reg(rd);
-                        self.port_d.write_beu32(addr as Address, value)?;
+                        self.data_bus().write_beu32(addr as Address, value)?;
                     },
                 }
             },
             Instruction::I(instruction) => {
                 match instruction {
                     I::Jalr(rd, rs1, imm) => {
-                        *self.get_reg_mut(rd) = self.get_pc();
+                        let link = self.get_pc();
                         let jump = self.get_reg(rs1).wrapping_add(imm as u32);
                         self.set_pc(jump)?;
+                        *self.get_reg_mut(rd) = link;
                     },
 
                     I::Lb(rd, rs1, imm) => {
@@ -346,14 +437,14 @@ impl Sirius {
                         self.require_supervisor()?;
 
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
-                        let value = sign_extend(self.port_d.read_u8(addr as Address)? as u32, 8);
+                        let value = sign_extend(self.data_bus().read_u8(addr as Address)? as u32, 8);
                         *self.get_reg_mut(rd) = value as u32;
                     },
                     I::Lbud(rd, rs1, imm) => {
                         self.require_supervisor()?;
 
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
-                        let value = self.port_d.read_u8(addr as Address)? as u32;
+                        let value = self.data_bus().read_u8(addr as Address)? as u32;
                         *self.get_reg_mut(rd) = value as u32;
                     },
                     I::Lh(rd, rs1, imm) => {
@@ -371,14 +462,14 @@ impl Sirius {
                         self.require_supervisor()?;
 
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
-                        let value = sign_extend(self.port_d.read_beu16(addr as Address)? as u32, 16);
+                        let value = sign_extend(self.data_bus().read_beu16(addr as Address)? as u32, 16);
                         *self.get_reg_mut(rd) = value as u32;
                     },
                     I::Lhud(rd, rs1, imm) => {
                         self.require_supervisor()?;
 
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
-                        let value = self.port_d.read_beu16(addr as Address)? as u32;
+                        let value = self.data_bus().read_beu16(addr as Address)? as u32;
                         *self.get_reg_mut(rd) = value as u32;
                     },
                     I::Lw(rd, rs1, imm) => {
@@ -390,7 +481,7 @@ impl Sirius {
                         self.require_supervisor()?;
 
                         let addr = self.get_reg(rs1).wrapping_add(imm as u32);
-                        let value = self.port_d.read_beu32(addr as Address)?;
+                        let value = self.data_bus().read_beu32(addr as Address)?;
                         *self.get_reg_mut(rd) = value as u32;
                     },
                 
@@ -601,24 +692,24 @@ impl Sirius {
                         *self.get_reg_mut(rd) = self.read_beu32(addr as Address)?;
                         *self.get_reg_mut(sp) = self.get_reg(sp).wrapping_add(4);
                     },
+                    // The stack pointer moves after the store, so a faulting push can be restarted
                     M::Pushb(rd, sp) => {
                         let new_sp = self.get_reg(sp).wrapping_sub(1);
-                        *self.get_reg_mut(sp) = new_sp;
-                        let addr = self.get_reg(sp);
                         let value = self.get_reg(rd);
-                        self.write_u8(addr as Address, value as u8)?;
+                        self.write_u8(new_sp as Address, value as u8)?;
+                        *self.get_reg_mut(sp) = new_sp;
                     },
                     M::Pushh(rd, sp) => {
-                        *self.get_reg_mut(sp) = self.get_reg(sp).wrapping_sub(2);
-                        let addr = self.get_reg(sp);
+                        let new_sp = self.get_reg(sp).wrapping_sub(2);
                         let value = self.get_reg(rd);
-                        self.write_beu16(addr as Address, value as u16)?;
+                        self.write_beu16(new_sp as Address, value as u16)?;
+                        *self.get_reg_mut(sp) = new_sp;
                     },
                     M::Push(rd, sp) => {
-                        *self.get_reg_mut(sp) = self.get_reg(sp).wrapping_sub(4);
-                        let addr = self.get_reg(sp);
+                        let new_sp = self.get_reg(sp).wrapping_sub(4);
                         let value = self.get_reg(rd);
-                        self.write_beu32(addr as Address, value)?;
+                        self.write_beu32(new_sp as Address, value)?;
+                        *self.get_reg_mut(sp) = new_sp;
                     },
                 
                     M::Save(start, end, rs1) => {
@@ -665,12 +756,22 @@ impl Sirius {
                     },
                     T::SsReg(rs1) => {
                         self.require_supervisor()?;
-                        self.set_psr(self.get_reg(rs1));
+                        self.set_psr(self.get_reg(rs1))?;
                     },
                     T::Sysret => {
                         self.require_supervisor()?;
                         self.sysret()?;
                     },
+                    T::TlbFlush => {
+                        self.require_supervisor()?;
+                        self.require_mmu()?;
+                        self.mmu.tlb.clear();
+                    },
+                    T::TlbInvalidate(rs1) => {
+                        self.require_supervisor()?;
+                        self.require_mmu()?;
+                        self.mmu.tlb.invalidate(self.get_reg(rs1) >> PT_SHIFT);
+                    },
                 }
             },
         }
@@ -690,7 +791,7 @@ impl Sirius {
         Ok(value)
     }
 
-    fn get_pc(&self) -> Word {
+    pub fn get_pc(&self) -> Word {
         if self.state.psr.mmu_enabled() {
             self.state.virtual_pc
         } else {
@@ -698,24 +799,34 @@ impl Sirius {
         }
     }
 
-    fn set_pc(&mut self, value: Word) -> Result<(), Error> {
+    // With the mmu enabled the new pc is only translated when the next instruction is fetched
+    pub fn set_pc(&mut self, value: Word) -> Result<(), Error> {
         if self.state.psr.mmu_enabled() {
-            let (real, _, x) = self.translate(value as Address)?;
-            if x {
-                self.state.pc = real as Word;
-                self.state.virtual_pc = value;
-                Ok(())
-            } else {
-                Err(Error::processor(Exceptions::AccessViolation as u32))
-            }
+            self.state.virtual_pc = value;
         } else {
             self.state.pc = value; 
-            Ok(())
+        }
+        Ok(())
+    }
+
+    // Physical address of the next instruction to be fetched
+    pub fn physical_pc(&mut self) -> Result<Word, Error> {
+        let real = self.translate(self.get_pc() as Address, Access::Fetch)?;
+        Ok(real as Word)
+    }
+
+    // Instructions go through here to reach the data bus, so the accesses can be traced
+    #[inline(always)]
+    fn data_bus(&mut self) -> DataBus<'_> {
+        DataBus {
+            port: &mut self.port_d,
+            state: &mut self.state,
+            trace: self.trace.as_mut(),
         }
     }
 
     #[inline(always)]
-    fn get_stack_pointer_mut(&mut self) -> &mut u32 {
+    pub fn get_stack_pointer_mut(&mut self) -> &mut u32 {
         if self.is_supervisor() {
             &mut self.state.ssp
         } else {
@@ -724,7 +835,7 @@ impl Sirius {
     }
 
     #[inline(always)]
-    fn get_reg(&self, reg: Register) -> Word {
+    pub fn get_reg(&self, reg: Register) -> Word {
 
         match reg {
             Register::Zero => 0,
@@ -753,12 +864,22 @@ impl Sirius {
     }
 
     #[inline(always)]
-    fn translate(&mut self, addr: Address) -> Result<(Address, bool, bool), Error> {
+    fn translate(&mut self, addr: Address, access: Access) -> Result<Address, Error> {
         if self.state.psr.mmu_enabled() {
             let pdt = self.state.psr.pdt() as Address * 256;
-            self.mmu.translate(addr as u32, &mut self.port_d, &mut self.port, pdt)
+            let supervisor = self.is_supervisor();
+            // Records what faulted for the fault frame
+            // A page walk that reads from nowhere is a bus error at the address being translated
+            self.mmu.translate(addr as u32, access, supervisor, &mut self.port_d, &mut self.port, pdt).map_err(|err| {
+                self.state.fault_address = addr as Word;
+                self.state.fault_access = access;
+                match err.err {
+                    ErrorType::Bus => Error::processor(Exceptions::BusError as u32),
+                    _ => err,
+                }
+            })
         } else {
-            Ok((addr, true, true))
+            Ok(addr)
         }
     }
 
@@ -776,8 +897,41 @@ impl Sirius {
         }
     }
 
-    fn set_psr(&mut self, value: Word) {
-        self.state.psr = StatusReg::from_bytes(value.to_ne_bytes()); //TODO figure out the endianness of the modular bitfield crate
+    // The Codex has no MMU, so it has no use for its instructions or for turning it on
+    #[inline(always)]
+    fn require_mmu(&self) -> Result<(), Error> {
+        match self.cputype {
+            TaleaCpuType::CodexType => Err(Error::processor(Exceptions::IllegalInstruction as u32)),
+            TaleaCpuType::SiriusType => Ok(()),
+        }
+    }
+
+    fn check_psr(&self, psr: &StatusReg) -> Result<(), Error> {
+        if psr.mmu_enabled() {
+            self.require_mmu()?;
+        }
+        Ok(())
+    }
+
+    fn set_psr(&mut self, value: Word) -> Result<(), Error> {
+        let psr = StatusReg::from_bytes(value.to_ne_bytes()); //TODO figure out the endianness of the modular bitfield crate
+        self.replace_psr(psr)
+    }
+
+    // Execution continues at the same linear address when the mmu is switched on or off
+    pub fn replace_psr(&mut self, psr: StatusReg) -> Result<(), Error> {
+        let pc = self.get_pc();
+        self.check_psr(&psr)?;
+        self.update_psr(psr);
+        self.set_pc(pc)
+    }
+
+    // Cached translations belong to a page directory, so they are flushed when it changes
+    fn update_psr(&mut self, psr: StatusReg) {
+        if psr.pdt() != self.state.psr.pdt() || psr.mmu_enabled() != self.state.psr.mmu_enabled() {
+            self.mmu.tlb.clear();
+        }
+        self.state.psr = psr;
     }
 
     fn get_psr(&self) -> u32 {
@@ -813,17 +967,64 @@ impl Addressable for Sirius {
     }
 
     fn read(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error> {
-        let (real, _, _) = self.translate(addr)?;
-        self.port.read(real, data)
+        let real = self.translate(addr, Access::Read)?;
+        let mask = self.port.address_mask();
+        self.port.read(real, data).map_err(|err| bus_fault(&mut self.state, err, addr, real & mask, Access::Read))?;
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record_access(TraceBus::Main, false, addr, data);
+        }
+        Ok(())
     }
     
     fn write(&mut self, addr: Address, data: &[u8]) -> Result<(), Error> {
-        let (real, w, _) = self.translate(addr)?;
+        let real = self.translate(addr, Access::Write)?;
+        let mask = self.port.address_mask();
+        self.port.write(real, data).map_err(|err| bus_fault(&mut self.state, err, addr, real & mask, Access::Write))?;
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record_access(TraceBus::Main, true, addr, data);
+        }
+        Ok(())
+    }
+}
 
-        if w {
-            self.port.write(real, data)
-        } else {
-            Err(Error::processor(Exceptions::AccessViolation as u32))
+// Turns nothing answering on the bus into a guest bus error. The fault frame gets the address of the access,
+// plus however far into the transfer the bus got before it failed
+fn bus_fault(state: &mut State, err: Error, addr: Address, bus_addr: Address, access: Access) -> Error {
+    if err.err != ErrorType::Bus {
+        return err;
+    }
+    let progress = err.native.wrapping_sub(bus_addr as u32);
+    state.fault_address = (addr as Word).wrapping_add(progress);
+    state.fault_access = access;
+    Error::processor(Exceptions::BusError as u32)
+}
+
+struct DataBus<'a> {
+    port: &'a mut BusPort,
+    state: &'a mut State,
+    trace: Option<&'a mut Tracer>,
+}
+
+impl Addressable for DataBus<'_> {
+    fn len(&self) -> usize {
+        self.port.len()
+    }
+
+    fn read(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error> {
+        let bus_addr = addr & self.port.address_mask();
+        self.port.read(addr, data).map_err(|err| bus_fault(self.state, err, addr, bus_addr, Access::Read))?;
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record_access(TraceBus::Data, false, addr, data);
         }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn write(&mut self, addr: Address, data: &[u8]) -> Result<(), Error> {
+        let bus_addr = addr & self.port.address_mask();
+        self.port.write(addr, data).map_err(|err| bus_fault(self.state, err, addr, bus_addr, Access::Write))?;
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record_access(TraceBus::Data, true, addr, data);
+        }
+        Ok(())
+    }
+}
@@ -138,6 +138,8 @@ pub enum T {
     GsReg(Reg),
     SsReg(Reg),
     Sysret,
+    TlbFlush,
+    TlbInvalidate(Reg),
 }
 pub const SYS: u8 = 0x00;
 
@@ -433,6 +435,8 @@ impl TryFrom<u32> for T {
             0x3 => Ok(T::GsReg(rd)),
             0x4 => Ok(T::SsReg(rd)),
             0x6 => Ok(T::Sysret),
+            0x7 => Ok(T::TlbFlush),
+            0x8 => Ok(T::TlbInvalidate(rd)),
             _ => Err(Undefined(value)),
         }
     }
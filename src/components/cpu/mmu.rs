@@ -6,15 +6,21 @@ use std::collections::HashMap;
     ╭──────────────┬──────────┬────────┬──────────╮
     │Linear Address│ device: 8│page: 12│offset: 12│
     ╰──────────────┴──────────┴────────┴──────────╯
+    The page field is split into an index into the page directory (2 bits) and an index into the page table (10 bits)
     ╭────────────────┬─────────────────────────────────────────────╮
     │Physical Address│PageTable[page * sizeof(Entry)] * 4K + offset│
     ╰────────────────┴─────────────────────────────────────────────╯
 
-    Entries in the page table must be 2 bytes long, (a halfword) and contain this fields:
-    ╭─────┬────────────────────────┬───┬───┬───────┬─────────╮
-    │Entry│Physical page address:12│w:1│x:1│dirty:1│present:1│
-    ╰─────┴────────────────────────┴───┴───┴───────┴─────────╯
-    Pages are allways readable in user mode, and no restrictions aplly in supervisor mode.
+    Entries in the page table must be 4 bytes long, (a word) and contain this fields:
+    ╭─────┬────────────────────────┬───┬───┬───────┬─────────┬──────────┬────────────┬───────────╮
+    │Entry│Physical page address:12│w:1│x:1│dirty:1│present:1│accessed:1│supervisor:1│reserved:14│
+    ╰─────┴────────────────────────┴───┴───┴───────┴─────────┴──────────┴────────────┴───────────╯
+    Entries in the page directory are 2 bytes long, (a halfword):
+    ╭─────┬─────────────────────────────────┬───┬──────────┬─────────╮
+    │Entry│Physical address of Page Table:12│w:1│reserved:2│present:1│
+    ╰─────┴─────────────────────────────────┴───┴──────────┴─────────╯
+    Pages are allways readable in user mode, and no restrictions aplly in supervisor mode except present.
+    The accessed and dirty bits are set by the hardware when a page is first used and written.
 
 
     Device field is used for memory mapping: (UNUSED //TODO: Think if this is interesting)
@@ -32,20 +38,22 @@ use std::collections::HashMap;
 */
 
 use crate::components::MEMSIZE;
-use crate::components::cpu::state::Exceptions;
+use crate::components::cpu::state::{Access, Exceptions};
 use modular_bitfield_msb::prelude::*;
 use organum::{core::{Addressable, Address}, error::Error};
+use organum::snapshot::{SnapshotReader, SnapshotWriter};
 
-pub const ENTRY_SIZE: usize = 2;
+pub const PDE_SIZE: usize = 2;
+pub const PTE_SIZE: usize = 4;
 
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_TABLE_ENTRIES: usize = 1024;
-pub const PAGE_TABLE_SIZE: usize = PAGE_TABLE_ENTRIES * ENTRY_SIZE;
+pub const PAGE_TABLE_SIZE: usize = PAGE_TABLE_ENTRIES * PTE_SIZE;
 pub const PAGE_DIRECTORY_ENTRIES: usize = (MEMSIZE/PAGE_SIZE) / PAGE_TABLE_ENTRIES;
 
-pub const PT_SHIFT: u32 = PAGE_TABLE_ENTRIES.count_ones();
-pub const PT_MASK: u32 = (PAGE_DIRECTORY_ENTRIES as u32) << PT_SHIFT;
-pub const PD_SHIFT: u32 = PAGE_DIRECTORY_ENTRIES.count_ones() + PT_SHIFT;
+pub const PT_SHIFT: u32 = PAGE_SIZE.trailing_zeros();
+pub const PT_MASK: u32 = PAGE_TABLE_ENTRIES as u32 - 1;
+pub const PD_SHIFT: u32 = PAGE_TABLE_ENTRIES.trailing_zeros() + PT_SHIFT;
 pub const PD_MASK: u32 = PAGE_DIRECTORY_ENTRIES as u32 - 1;
 pub const OFFSET_MASK: u32 = PAGE_SIZE as u32 - 1;
 
@@ -54,7 +62,9 @@ pub const OFFSET_MASK: u32 = PAGE_SIZE as u32 - 1;
 pub struct PageDirectoryEntry {
      // Page directory sits in Data Memory
      pub physical_addr: B12,
-     #[skip] __: B4, //TODO: which flags should I include here
+     pub w: bool,
+     #[skip] __: B2,
+     pub present: bool,
 }
 
 #[modular_bitfield_msb::bitfield]
@@ -65,36 +75,80 @@ pub struct PageTableEntry {
      pub w: bool,
      pub x: bool,
      pub dirty: bool,
-     pub present: bool
+     pub present: bool,
+     pub accessed: bool,
+     pub supervisor: bool,
+     #[skip] __: B14,
+}
+
+// A cached translation, w already accounts for the directory entry
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TlbEntry {
+     pub frame: u16,
+     pub w: bool,
+     pub x: bool,
+     pub supervisor: bool,
+     pub dirty: bool,
 }
 
 pub struct Tlb {
-     pub tlb: HashMap<u16, (u16, bool, bool)>,
+     pub tlb: HashMap<u32, TlbEntry>,
 }
 
 impl Tlb{
-     //due to the nature of the hash map, the tlb need not be invalidated even in context switches
-     // this is not real, but it's handy (//TODO: check cost of insertion between the other)
+     // Entries are not tagged with the page directory, so the tlb is flushed whenever the pdt changes
      pub fn new() -> Self {
           Self {
                tlb: HashMap::new(),
           }
      }
 
-     pub fn record(&mut self, linear: u16, (physical, w, x): (u16, bool, bool)) {
-          self.tlb.insert(linear, (physical, w, x));
+     pub fn record(&mut self, page: u32, entry: TlbEntry) {
+          self.tlb.insert(page, entry);
      }
 
-     pub fn invalidate(&mut self, entry: u16) {
-          self.tlb.remove(&entry);
+     pub fn invalidate(&mut self, page: u32) {
+          self.tlb.remove(&page);
      }
 
      pub fn clear(&mut self) {
           self.tlb.clear();
      }
 
-     pub fn get(&self, linear: u16) -> Option<(u16, bool, bool)> {
-          self.tlb.get(&linear).cloned()
+     pub fn get(&self, page: u32) -> Option<TlbEntry> {
+          self.tlb.get(&page).cloned()
+     }
+
+     pub fn save_state(&self, snapshot: &mut SnapshotWriter) {
+          // In page order, so the same machine always makes the same snapshot
+          let mut pages: Vec<&u32> = self.tlb.keys().collect();
+          pages.sort();
+          snapshot.write_beu32(pages.len() as u32);
+          for page in pages {
+               let entry = &self.tlb[page];
+               snapshot.write_beu32(*page);
+               snapshot.write_beu16(entry.frame);
+               snapshot.write_bool(entry.w);
+               snapshot.write_bool(entry.x);
+               snapshot.write_bool(entry.supervisor);
+               snapshot.write_bool(entry.dirty);
+          }
+     }
+
+     pub fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+          self.tlb.clear();
+          for _ in 0..snapshot.read_beu32()? {
+               let page = snapshot.read_beu32()?;
+               let entry = TlbEntry {
+                    frame: snapshot.read_beu16()?,
+                    w: snapshot.read_bool()?,
+                    x: snapshot.read_bool()?,
+                    supervisor: snapshot.read_bool()?,
+                    dirty: snapshot.read_bool()?,
+               };
+               self.tlb.insert(page, entry);
+          }
+          Ok(())
      }
 }
 
@@ -109,29 +163,71 @@ impl Mmu {
           }
      }
 
-     pub fn translate(&mut self, linear: u32, directory: &mut dyn Addressable, table: &mut dyn Addressable, directory_pointer: Address) -> Result<(Address, bool, bool), Error> {
-          match self.tlb.get((&linear >> PT_SHIFT) as u16) {
-               Some(entry) => Ok((((entry.0 as Address) << PT_SHIFT | (linear as Address) & OFFSET_MASK as Address), entry.1, entry.2)),
-               None => {
-                    let table_entry = (linear as Address >> PD_SHIFT) & PD_MASK as Address;
-
-                    let table_entry = PageDirectoryEntry::from_bytes(u16::to_be_bytes(directory.read_beu16(directory_pointer + table_entry)?));
-                    let table_entry = (table_entry.physical_addr() as Address) << PT_SHIFT; 
-          
-                    let page_offset = (linear as Address >> PT_SHIFT) & PT_MASK as Address;
-                    let page = PageTableEntry::from_bytes(u16::to_be_bytes(table.read_beu16(table_entry | page_offset)?));
-                    let w = page.w();
-                    let x = page.x();
-                    if !page.present() {
-                         println!("{:?}, {:?}", page, page.into_bytes());
-                         return Err(Error::processor(Exceptions::PageFault as u32));
-                    }
-                    let page = (page.physical_addr() as Address) << PT_SHIFT as Address;
-                    let physical = page | (linear as Address & OFFSET_MASK as Address);
-                    self.tlb.record((linear >> PT_MASK as Address) as u16, (((page >> PT_MASK as Address) as u16), w, x));
-                    Ok((physical, w, x))
+     pub fn translate(&mut self, linear: u32, access: Access, supervisor: bool, directory: &mut dyn Addressable, table: &mut dyn Addressable, directory_pointer: Address) -> Result<Address, Error> {
+          let page = linear >> PT_SHIFT;
+          let entry = match self.tlb.get(page) {
+               // The first write to a page has to go through the table to set the dirty bit
+               Some(entry) if access != Access::Write || entry.dirty => {
+                    check_access(&entry, access, supervisor)?;
+                    entry
+               },
+               _ => {
+                    let entry = self.walk(linear, access, supervisor, directory, table, directory_pointer)?;
+                    self.tlb.record(page, entry);
+                    entry
                }
+          };
+
+          Ok((entry.frame as Address) << PT_SHIFT | (linear & OFFSET_MASK) as Address)
+     }
+
+     // Reads the tables for a linear address, and writes the accessed and dirty bits back
+     fn walk(&mut self, linear: u32, access: Access, supervisor: bool, directory: &mut dyn Addressable, table: &mut dyn Addressable, directory_pointer: Address) -> Result<TlbEntry, Error> {
+          let directory_index = ((linear >> PD_SHIFT) & PD_MASK) as Address;
+          let directory_entry = PageDirectoryEntry::from_bytes(u16::to_be_bytes(directory.read_beu16(directory_pointer + directory_index * PDE_SIZE as Address)?));
+          if !directory_entry.present() {
+               return Err(Error::processor(Exceptions::PageFault as u32));
+          }
+
+          let table_index = ((linear >> PT_SHIFT) & PT_MASK) as Address;
+          let table_entry = ((directory_entry.physical_addr() as Address) << PT_SHIFT) + table_index * PTE_SIZE as Address;
+          let mut page = PageTableEntry::from_bytes(u32::to_be_bytes(table.read_beu32(table_entry)?));
+          if !page.present() {
+               return Err(Error::processor(Exceptions::PageFault as u32));
+          }
+
+          let mut entry = TlbEntry {
+               frame: page.physical_addr(),
+               w: page.w() && directory_entry.w(),
+               x: page.x(),
+               supervisor: page.supervisor(),
+               dirty: page.dirty(),
+          };
+          check_access(&entry, access, supervisor)?;
+
+          entry.dirty |= access == Access::Write;
+          if !page.accessed() || entry.dirty != page.dirty() {
+               page.set_accessed(true);
+               page.set_dirty(entry.dirty);
+               table.write_beu32(table_entry, u32::from_be_bytes(page.into_bytes()))?;
           }
-          // TODO: account for the flags and those things Implement dirty bit
+          Ok(entry)
      }
-}
\ No newline at end of file
+}
+
+// Only the present bit applies in supervisor mode
+fn check_access(entry: &TlbEntry, access: Access, supervisor: bool) -> Result<(), Error> {
+     if supervisor {
+          return Ok(());
+     }
+
+     let allowed = match access {
+          Access::Write => entry.w,
+          Access::Fetch => entry.x,
+          Access::Read | Access::None => true,
+     };
+     if entry.supervisor || !allowed {
+          return Err(Error::processor(Exceptions::AccessViolation as u32));
+     }
+     Ok(())
+}
@@ -1,11 +1,16 @@
 // state.rs provides a model for the Sirius cpu
-use organum::core::{Address, Interruptable};
+use organum::core::{Address, Interruptable, Snapshotable};
+use organum::error::Error;
 use organum::premade::bus::BusPort;
+use organum::snapshot::{SnapshotReader, SnapshotWriter};
+use organum::symbols::SymbolTable;
 
 use super::debugger::Debugger;
 use super::decode::Decoder;
+use super::disassemble::disassemble;
 use crate::components::{TaleaCpuType, Uptr, Word};
 use crate::components::cpu::mmu::Mmu;
+use crate::components::cpu::trace::Tracer;
 use modular_bitfield_msb::specifiers::*;
 
 const RGCOUNT: usize = 32;
@@ -32,6 +37,16 @@ pub enum Exceptions {
     //ChkInstruction = 6, this could be interesting, hardware bounds checking
 }
 
+// Kind of memory access that caused a fault, as pushed in the fault frame
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    None = 0,
+    Read = 1,
+    Write = 2,
+    Fetch = 3,
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum InterruptPriority {
@@ -82,6 +97,14 @@ pub enum Register {
     T6 = 31,
 }
 
+// Register names as written in assembly, indexed by register number
+pub const REGISTER_NAMES: [&str; RGCOUNT] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "fp", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
 impl From<usize> for Register {
     fn from(value: usize) -> Self {
         let value = value & 0x1f;
@@ -186,18 +209,27 @@ pub struct State {
     pub ssp: Word,
     pub usp: Word,
     pub virtual_pc : Word,
-    pub blackhole: Word
+    pub blackhole: Word,
+
+    // Linear address of the instruction being executed and of the last access that faulted
+    pub instruction_pc: Word,
+    pub fault_address: Word,
+    pub fault_access: Access,
 }
 
 pub struct Sirius {
     pub cputype: TaleaCpuType,
     pub frequency: u32,
+    // Abort the emulator on illegal instructions and privilege violations instead of trapping
+    pub strict: bool,
     pub state: State,
     pub mmu: Mmu,
     pub decoder: Decoder,
     pub debugger: Debugger,
     pub port: BusPort,
     pub port_d: BusPort,
+    // Records retired instructions when running with --trace
+    pub trace: Option<Tracer>,
     pub cycles: u128
 }
 
@@ -224,7 +256,11 @@ impl State {
             ssp: 0,
             usp: 0,
             virtual_pc: 0,
-            blackhole: 0
+            blackhole: 0,
+
+            instruction_pc: 0,
+            fault_address: 0,
+            fault_access: Access::None,
 
         }
     }
@@ -235,12 +271,14 @@ impl Sirius {
         Self {
             cputype,
             frequency,
+            strict: false,
             state: State::new(),
             decoder: Decoder::new(),
             mmu: Mmu::new(),
             debugger: Debugger::new(),
             port: port,
             port_d: port_d,
+            trace: None,
             cycles: 0
         }
     }
@@ -253,8 +291,8 @@ impl Sirius {
         self.mmu = Mmu::new();
     }
 
-    pub fn dump_state(&mut self) {
-        println!("PC: {:#08x}   PSR: {:?}", self.state.pc, self.state.psr);
+    pub fn dump_state(&mut self, symbols: &SymbolTable) {
+        println!("PC: {}   PSR: {:?}", symbols.format_address(self.get_pc() as Address), self.state.psr);
         println!("SSP: {:#08x}  USP: {:#08x}", self.state.ssp, self.state.usp);
         println!("Status: {:?}", self.state.status);
 
@@ -278,20 +316,20 @@ impl Sirius {
         }
 
         println!(
-            "Current Instruction: {} {:?}",
+            "Current Instruction: {} {}",
             self.decoder.format_instruction_bytes(&mut self.port),
-            self.decoder.instruction
+            disassemble(&self.decoder.instruction, self.decoder.decoded_at(), symbols)
         );
         
         if self.state.psr.supervisor() {
             println!("Supervisor Stack:");
             self.port
-                .dump_memory(self.state.ssp as Address, 0x40);
+                .dump_memory_symbols(self.state.ssp as Address, 0x40, symbols);
             println!("");
         } else {
             println!("User Stack:");
             self.port
-                .dump_memory(self.state.usp as Address, 0x40);
+                .dump_memory_symbols(self.state.usp as Address, 0x40, symbols);
             println!("");
         }
 
@@ -304,6 +342,54 @@ impl Sirius {
 
 impl Interruptable for Sirius {}
 
+impl Snapshotable for Sirius {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        snapshot.write_u8(self.state.status as u8);
+        snapshot.write_u8(self.state.current_ipl as u8);
+        snapshot.write_u8(self.state.pending_ipl as u8);
+
+        snapshot.write_beu32(self.state.pc);
+        snapshot.write_bytes(&self.state.psr.into_bytes());
+        for reg in self.state.reg {
+            snapshot.write_beu32(reg);
+        }
+        snapshot.write_beu32(self.state.ssp);
+        snapshot.write_beu32(self.state.usp);
+        snapshot.write_beu32(self.state.virtual_pc);
+
+        self.mmu.tlb.save_state(snapshot);
+        snapshot.write_bytes(&self.cycles.to_be_bytes());
+        Ok(())
+    }
+
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        self.state.status = match snapshot.read_u8()? {
+            0 => Status::Init,
+            1 => Status::Running,
+            _ => Status::Stopped,
+        };
+        self.state.current_ipl = InterruptPriority::from_u8(snapshot.read_u8()?);
+        self.state.pending_ipl = InterruptPriority::from_u8(snapshot.read_u8()?);
+
+        self.state.pc = snapshot.read_beu32()?;
+        let mut psr = [0u8; 4];
+        snapshot.read_into(&mut psr)?;
+        self.state.psr = StatusReg::from_bytes(psr);
+        for reg in self.state.reg.iter_mut() {
+            *reg = snapshot.read_beu32()?;
+        }
+        self.state.ssp = snapshot.read_beu32()?;
+        self.state.usp = snapshot.read_beu32()?;
+        self.state.virtual_pc = snapshot.read_beu32()?;
+
+        self.mmu.tlb.load_state(snapshot)?;
+        let mut cycles = [0u8; 16];
+        snapshot.read_into(&mut cycles)?;
+        self.cycles = u128::from_be_bytes(cycles);
+        Ok(())
+    }
+}
+
 impl InterruptPriority {
     pub fn from_u8(priority: u8) -> InterruptPriority {
         match priority {
@@ -0,0 +1,364 @@
+// trace.rs records the instructions retired by Sirius to a compact binary file, and turns it back into text or json
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use organum::core::Address;
+use organum::error::Error;
+use organum::symbols::SymbolTable;
+
+use crate::components::cpu::decode::decode_word;
+use crate::components::cpu::disassemble::disassemble;
+use crate::components::cpu::state::REGISTER_NAMES;
+use crate::components::Word;
+
+const MAGIC: &[u8; 4] = b"STRC";
+const VERSION: u8 = 2;
+const WORD_BYTES: usize = 4;
+
+// Registers are numbered as in the gdb stub: the 32 general purpose registers, then pc, psr, ssp and usp
+pub const TRACE_PC: usize = 32;
+const EXTRA_REGISTER_NAMES: [&str; 4] = ["pc", "psr", "ssp", "usp"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceMode {
+    Supervisor,
+    User,
+}
+
+// Which instructions end up in the trace. Triggers are compared with the linear pc
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    pub range: Option<Range<Word>>,
+    pub mode: Option<TraceMode>,
+    pub start: Option<Word>,
+    pub stop: Option<Word>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceBus {
+    Main = 0,
+    Data = 1,
+}
+
+// Bulk transfers, such as the ones of copy and fill, are wider than a word and keep no value
+#[derive(Clone, Debug)]
+pub struct MemoryAccess {
+    pub bus: TraceBus,
+    pub write: bool,
+    pub addr: Word,
+    pub width: u32,
+    pub value: Option<Word>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RegisterWrite {
+    pub index: u8,
+    pub old: Word,
+    pub new: Word,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TraceRecord {
+    pub clock: u64,
+    pub pc: Word,
+    pub virtual_pc: Word,
+    pub word: Word,
+    pub supervisor: bool,
+    pub registers: Vec<RegisterWrite>,
+    pub accesses: Vec<MemoryAccess>,
+}
+
+pub struct Tracer {
+    writer: BufWriter<File>,
+    filter: TraceFilter,
+    triggered: bool,
+    finished: bool,
+    current: Option<TraceRecord>,
+}
+
+impl Tracer {
+    pub fn create(path: &Path, filter: TraceFilter) -> Result<Self, Error> {
+        let file = File::create(path)
+            .map_err(|e| Error::new(&format!("trace: unable to create {}: {}", path.display(), e)))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC).and_then(|_| writer.write_all(&[VERSION])).map_err(io_error)?;
+
+        Ok(Self {
+            writer,
+            triggered: filter.start.is_none(),
+            filter,
+            finished: false,
+            current: None,
+        })
+    }
+
+    // Whether the instruction being executed is being recorded
+    pub fn is_recording(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // Called once the instruction has been fetched, decides if it passes the filter
+    pub fn begin(&mut self, clock: u64, pc: Word, virtual_pc: Word, word: Word, supervisor: bool) {
+        self.current = None;
+        if self.finished {
+            return;
+        }
+        if !self.triggered {
+            if Some(virtual_pc) != self.filter.start {
+                return;
+            }
+            self.triggered = true;
+        }
+        if self.filter.stop == Some(virtual_pc) {
+            self.finished = true;
+        }
+
+        if let Some(range) = &self.filter.range {
+            if !range.contains(&virtual_pc) {
+                return;
+            }
+        }
+        match self.filter.mode {
+            Some(TraceMode::Supervisor) if !supervisor => return,
+            Some(TraceMode::User) if supervisor => return,
+            _ => {},
+        }
+
+        self.current = Some(TraceRecord {
+            clock,
+            pc,
+            virtual_pc,
+            word,
+            supervisor,
+            ..Default::default()
+        });
+    }
+
+    pub fn record_access(&mut self, bus: TraceBus, write: bool, addr: Address, data: &[u8]) {
+        if let Some(record) = self.current.as_mut() {
+            let value = (data.len() <= WORD_BYTES).then(|| data.iter().fold(0, |value, byte| (value << 8) | *byte as Word));
+            record.accesses.push(MemoryAccess { bus, write, addr: addr as Word, width: data.len() as u32, value });
+        }
+    }
+
+    // Compares the registers from before and after the instruction, the pc always changes and is left out
+    pub fn record_registers(&mut self, before: &[Word], after: &[Word]) {
+        if let Some(record) = self.current.as_mut() {
+            for (index, (old, new)) in before.iter().zip(after.iter()).enumerate() {
+                if old != new && index != TRACE_PC {
+                    record.registers.push(RegisterWrite { index: index as u8, old: *old, new: *new });
+                }
+            }
+        }
+    }
+
+    // Writes the instruction out once it has retired. Instructions that fault never get here
+    pub fn end(&mut self) -> Result<(), Error> {
+        if let Some(record) = self.current.take() {
+            write_record(&mut self.writer, &record).map_err(io_error)?;
+            if self.finished {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().map_err(io_error)
+    }
+}
+
+/* Every record is laid out as follows, in big endian:
+    ╭────────────────────────────────────────────────────────────────╮
+    │clock: 8, pc: 4, virtual pc: 4, word: 4, flags: 1               │
+    │register count: 1, access count: 1                              │
+    │registers: index: 1, old: 4, new: 4                             │
+    │accesses: flags: 1, width: 4, address: 4, value: 4              │
+    ╰────────────────────────────────────────────────────────────────╯
+   Record flags: bit 0 supervisor. Access flags: bit 0 write, bit 1 data bus. The value of an access
+   wider than a word is left as 0
+*/
+fn write_record(writer: &mut impl Write, record: &TraceRecord) -> std::io::Result<()> {
+    writer.write_all(&record.clock.to_be_bytes())?;
+    writer.write_all(&record.pc.to_be_bytes())?;
+    writer.write_all(&record.virtual_pc.to_be_bytes())?;
+    writer.write_all(&record.word.to_be_bytes())?;
+    writer.write_all(&[record.supervisor as u8, record.registers.len() as u8, record.accesses.len() as u8])?;
+
+    for reg in record.registers.iter() {
+        writer.write_all(&[reg.index])?;
+        writer.write_all(&reg.old.to_be_bytes())?;
+        writer.write_all(&reg.new.to_be_bytes())?;
+    }
+    for access in record.accesses.iter() {
+        let flags = access.write as u8 | ((access.bus as u8) << 1);
+        writer.write_all(&[flags])?;
+        writer.write_all(&access.width.to_be_bytes())?;
+        writer.write_all(&access.addr.to_be_bytes())?;
+        writer.write_all(&access.value.unwrap_or(0).to_be_bytes())?;
+    }
+    Ok(())
+}
+
+pub struct TraceReader {
+    reader: BufReader<File>,
+    truncated: bool,
+}
+
+impl TraceReader {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path)
+            .map_err(|e| Error::new(&format!("trace: unable to open {}: {}", path.display(), e)))?;
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header).map_err(io_error)?;
+        if &header[0..4] != MAGIC || header[4] != VERSION {
+            return Err(Error::new(&format!("trace: {} is not a trace file", path.display())));
+        }
+        Ok(Self { reader, truncated: false })
+    }
+
+    // Whether the last record was incomplete, known once the records are all read
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    fn read_u8(&mut self) -> std::io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_beu32(&mut self) -> std::io::Result<u32> {
+        let mut buf = [0u8; 4];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn read_beu64(&mut self) -> std::io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn read_record(&mut self, clock: u64) -> std::io::Result<TraceRecord> {
+        let mut record = TraceRecord {
+            clock,
+            pc: self.read_beu32()?,
+            virtual_pc: self.read_beu32()?,
+            word: self.read_beu32()?,
+            supervisor: self.read_u8()? & 1 != 0,
+            ..Default::default()
+        };
+        let registers = self.read_u8()?;
+        let accesses = self.read_u8()?;
+
+        for _ in 0..registers {
+            record.registers.push(RegisterWrite {
+                index: self.read_u8()?,
+                old: self.read_beu32()?,
+                new: self.read_beu32()?,
+            });
+        }
+        for _ in 0..accesses {
+            let flags = self.read_u8()?;
+            let width = self.read_beu32()?;
+            let addr = self.read_beu32()?;
+            let value = self.read_beu32()?;
+            record.accesses.push(MemoryAccess {
+                bus: if flags & 2 != 0 { TraceBus::Data } else { TraceBus::Main },
+                write: flags & 1 != 0,
+                width,
+                addr,
+                value: (width as usize <= WORD_BYTES).then_some(value),
+            });
+        }
+        Ok(record)
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = Result<TraceRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The end of the file is only expected between records
+        let clock = match self.read_beu64() {
+            Ok(clock) => clock,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(io_error(e))),
+        };
+        // A run that was cut short may leave half a record at the end, it is left out
+        match self.read_record(clock) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.truncated = true;
+                None
+            },
+            record => Some(record.map_err(io_error)),
+        }
+    }
+}
+
+pub fn register_name(index: u8) -> &'static str {
+    let index = index as usize;
+    REGISTER_NAMES.get(index).copied()
+        .or_else(|| EXTRA_REGISTER_NAMES.get(index - REGISTER_NAMES.len()).copied())
+        .unwrap_or("?")
+}
+
+fn access_kind(access: &MemoryAccess) -> &'static str {
+    match (access.bus, access.write) {
+        (TraceBus::Main, false) => "read",
+        (TraceBus::Main, true) => "write",
+        (TraceBus::Data, false) => "read.d",
+        (TraceBus::Data, true) => "write.d",
+    }
+}
+
+// One line for the instruction, followed by indented lines for its effects
+pub fn format_text(record: &TraceRecord, symbols: &SymbolTable) -> String {
+    let instruction = disassemble(&decode_word(record.word), record.virtual_pc, symbols);
+    let mode = if record.supervisor { 'S' } else { 'U' };
+    let mut text = format!(
+        "{:>12} {} {} [{:#010x}] {:08x}  {}",
+        record.clock, mode, symbols.format_address(record.virtual_pc as Address), record.pc, record.word, instruction
+    );
+
+    for reg in record.registers.iter() {
+        text.push_str(&format!("\n\t{}: {:#010x} -> {:#010x}", register_name(reg.index), reg.old, reg.new));
+    }
+    for access in record.accesses.iter() {
+        text.push_str(&format!("\n\t{} {} {:#010x}", access_kind(access), access.width, access.addr));
+        if let Some(value) = access.value {
+            text.push_str(&format!(" = {:#0width$x}", value, width = access.width as usize * 2 + 2));
+        }
+    }
+    text
+}
+
+// One json object per record, so the output can be streamed line by line
+pub fn format_json(record: &TraceRecord) -> String {
+    let instruction = disassemble(&decode_word(record.word), record.virtual_pc, &SymbolTable::new());
+    let registers: Vec<String> = record.registers.iter()
+        .map(|reg| format!("{{\"reg\":\"{}\",\"old\":{},\"new\":{}}}", register_name(reg.index), reg.old, reg.new))
+        .collect();
+    let accesses: Vec<String> = record.accesses.iter()
+        .map(|access| format!(
+            "{{\"bus\":\"{}\",\"write\":{},\"addr\":{},\"width\":{},\"value\":{}}}",
+            if access.bus == TraceBus::Data { "data" } else { "main" }, access.write, access.addr, access.width,
+            access.value.map_or("null".to_string(), |value| value.to_string())
+        ))
+        .collect();
+
+    format!(
+        "{{\"clock\":{},\"pc\":{},\"vpc\":{},\"word\":{},\"supervisor\":{},\"instruction\":\"{}\",\"registers\":[{}],\"accesses\":[{}]}}",
+        record.clock, record.pc, record.virtual_pc, record.word, record.supervisor,
+        instruction.replace('\\', "\\\\").replace('"', "\\\""), registers.join(","), accesses.join(",")
+    )
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::new(&format!("trace: {}", e))
+}
@@ -0,0 +1,291 @@
+// machine.rs reads the description of a Taleä machine: how much memory it has, and which devices sit
+// where on its buses. build_talea puts the machine together from it
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use organum::core::Address;
+use organum::error::Error;
+use organum::{interrupts::{self, Trigger}, premade::serial};
+
+use crate::components::{storage::{drive, tps, Timing}, timer, tty, video, TaleaCpuType, ADDR_BUS_MAIN_SIZE, DATSIZE};
+
+// Machines that can be picked by name instead of giving a file, the first one is the default
+pub const PRESETS: [(&str, &str); 2] = [
+    ("talea", include_str!("../../machines/talea.toml")),
+    ("codex", include_str!("../../machines/codex.toml")),
+];
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Machine {
+    pub name: String,
+    pub cpu: CpuConfig,
+    pub memory: MemoryConfig,
+    #[serde(rename = "device", default)]
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuConfig {
+    #[serde(rename = "type", default)]
+    pub kind: TaleaCpuType,
+    pub frequency: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryConfig {
+    #[serde(default = "default_main_bits")]
+    pub main_bits: u8,
+    pub main_size: usize,
+    pub data_base: Address,
+    pub data_size: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Tty,
+    Video,
+    Tps,
+    Disk,
+    Timer,
+    Interrupts,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusKind {
+    Main,
+    #[default]
+    Data,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    #[serde(rename = "type")]
+    pub kind: DeviceKind,
+    pub name: String,
+    #[serde(default)]
+    pub bus: BusKind,
+    pub base: Address,
+    pub frequency: Option<u64>,
+    // Bit rate of a tty's line
+    pub baud: Option<u32>,
+    // Backing files, relative to the root of the emulator
+    pub path: Option<String>,
+    // Latency of a storage device, it does everything at once without it
+    pub timing: Option<Timing>,
+    pub interrupts: Option<Vec<InterruptConfig>>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterruptConfig {
+    pub vector: u8,
+    pub priority: u8,
+    // Line in the interrupt controller, the next free one if not given
+    pub line: Option<usize>,
+}
+
+fn default_main_bits() -> u8 {
+    ADDR_BUS_MAIN_SIZE as u8
+}
+
+impl DeviceKind {
+    pub fn register_count(&self) -> usize {
+        match self {
+            DeviceKind::Tty => serial::REGISTER_COUNT,
+            DeviceKind::Video => video::REGISTER_COUNT,
+            DeviceKind::Tps => tps::REGISTER_COUNT,
+            DeviceKind::Disk => drive::REGISTER_COUNT,
+            DeviceKind::Timer => timer::REGISTER_COUNT,
+            DeviceKind::Interrupts => interrupts::REGISTER_COUNT,
+        }
+    }
+
+    // Vectors and priorities used when the machine doesn't give any
+    pub fn default_interrupts(&self) -> Vec<InterruptConfig> {
+        let interrupts: &[(u8, u8, Option<usize>)] = match self {
+            DeviceKind::Tty => &[(tty::INTERRUPT_RECEIVED, 4, None), (tty::INTERRUPT_TX_EMPTY, 4, Some(tty::TX_EMPTY_LINE))],
+            DeviceKind::Video => &[(video::INTERRUPT_KBD_CHAR, 4, None), (video::INTERRUPT_KBD_KCODE, 4, None)],
+            DeviceKind::Tps => &[(tps::INTERRUPT_DONE, 5, None)],
+            DeviceKind::Disk => &[(drive::INTERRUPT_DONE, 5, None)],
+            DeviceKind::Timer => &[(timer::INTERRUPT_TIMEOUT, 6, None), (timer::INTERRUPT_INTERVAL, 6, None)],
+            DeviceKind::Interrupts => &[],
+        };
+        interrupts.iter().map(|(vector, priority, line)| InterruptConfig { vector: *vector, priority: *priority, line: *line }).collect()
+    }
+
+    // Only the device knows when to drop a level triggered line, so the machine can't change it
+    pub fn trigger(&self, index: usize) -> Trigger {
+        match (self, index) {
+            (DeviceKind::Tty, 0) => Trigger::Level,
+            _ => Trigger::Edge,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            DeviceKind::Tty => "Teletype",
+            DeviceKind::Video => "Video",
+            DeviceKind::Tps => "TPS",
+            DeviceKind::Disk => "Disk",
+            DeviceKind::Timer => "Timer",
+            DeviceKind::Interrupts => "IRQ Ctrl",
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn interrupts(&self) -> Vec<InterruptConfig> {
+        self.interrupts.clone().unwrap_or_else(|| self.kind.default_interrupts())
+    }
+
+    // The vector of each of the device's interrupts, in the order the device expects them
+    pub fn vectors(&self) -> Vec<u8> {
+        self.interrupts().iter().map(|interrupt| interrupt.vector).collect()
+    }
+
+    pub fn end(&self) -> Address {
+        self.base + self.kind.register_count() as Address
+    }
+}
+
+impl Machine {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::new(&format!("machine: unable to read {}: {}", path.display(), e)))?;
+        Self::parse(&text).map_err(|e| Error::new(&format!("machine: {}: {}", path.display(), e.msg)))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let machine: Machine = toml::from_str(text).map_err(|e| Error::new(&format!("{}", e)))?;
+        machine.validate()?;
+        Ok(machine)
+    }
+
+    pub fn default_machine() -> Self {
+        Self::parse(PRESETS[0].1).expect("the default machine description is valid")
+    }
+
+    // Either the name of a preset or the path to a description
+    pub fn from_arg(arg: &str) -> Result<Self, Error> {
+        match PRESETS.iter().find(|(name, _)| *name == arg) {
+            Some((name, text)) => Self::parse(text).map_err(|e| Error::new(&format!("machine: {}: {}", name, e.msg))),
+            None => Self::load(Path::new(arg)),
+        }
+    }
+
+    pub fn main_bus_size(&self) -> usize {
+        1 << self.memory.main_bits
+    }
+
+    pub fn device(&self, kind: DeviceKind) -> Option<&DeviceConfig> {
+        self.devices.iter().find(|device| device.kind == kind)
+    }
+
+    // Regions of a bus as (start, end, label), in address order
+    fn regions(&self, bus: BusKind) -> Vec<(Address, Address, String)> {
+        let mut regions: Vec<(Address, Address, String)> = self.devices.iter()
+            .filter(|device| device.bus == bus)
+            .map(|device| (device.base, device.end(), device.name.clone()))
+            .collect();
+        if bus == BusKind::Data {
+            let memory = &self.memory;
+            regions.push((memory.data_base, memory.data_base + memory.data_size as Address, "data memory".to_string()));
+        } else {
+            regions.push((0, self.memory.main_size as Address, "main memory".to_string()));
+        }
+        regions.sort_by_key(|(start, _, _)| *start);
+        regions
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.cpu.frequency == 0 {
+            return Err(Error::new("the cpu frequency can't be 0"));
+        }
+        if self.memory.main_bits == 0 || self.memory.main_bits as usize > ADDR_BUS_MAIN_SIZE {
+            return Err(Error::new(&format!("the main bus is at most {} bits wide", ADDR_BUS_MAIN_SIZE)));
+        }
+        if self.memory.main_size > self.main_bus_size() {
+            return Err(Error::new(&format!("main memory can't be larger than {:#x} bytes", self.main_bus_size())));
+        }
+
+        for (i, device) in self.devices.iter().enumerate() {
+            if self.devices[..i].iter().any(|other| other.name == device.name) {
+                return Err(Error::new(&format!("there is more than one device named {}", device.name)));
+            }
+            if device.frequency == Some(0) {
+                return Err(Error::new(&format!("{}: the frequency can't be 0", device.name)));
+            }
+            match (device.kind, device.baud) {
+                (DeviceKind::Tty, Some(0)) => return Err(Error::new(&format!("{}: the baud rate can't be 0", device.name))),
+                (DeviceKind::Tty, _) if device.frequency.is_some() => {
+                    return Err(Error::new(&format!("{}: a tty is paced by its baud rate, not a frequency", device.name)));
+                },
+                (DeviceKind::Tty, _) | (_, None) => { },
+                (_, Some(_)) => return Err(Error::new(&format!("{}: only a tty has a baud rate", device.name))),
+            }
+            if device.timing.is_some() && !matches!(device.kind, DeviceKind::Tps | DeviceKind::Disk) {
+                return Err(Error::new(&format!("{}: only storage devices have timing", device.name)));
+            }
+            let expected = device.kind.default_interrupts().len();
+            if device.interrupts().len() != expected {
+                return Err(Error::new(&format!("{}: a {:?} device raises {} interrupts", device.name, device.kind, expected)));
+            }
+            if device.path.is_none() && matches!(device.kind, DeviceKind::Video | DeviceKind::Tps | DeviceKind::Disk) {
+                return Err(Error::new(&format!("{}: the device needs a path to its files", device.name)));
+            }
+        }
+        let controllers = self.devices.iter().filter(|device| device.kind == DeviceKind::Interrupts).count();
+        if controllers > 1 || self.devices.iter().filter(|device| device.kind == DeviceKind::Video).count() > 1 {
+            return Err(Error::new("a machine has at most one video device and one interrupt controller"));
+        }
+
+        for (bus, size) in [(BusKind::Main, self.main_bus_size()), (BusKind::Data, DATSIZE)] {
+            let regions = self.regions(bus);
+            for pair in regions.windows(2) {
+                if pair[1].0 < pair[0].1 {
+                    return Err(Error::new(&format!("{} at {:#06x} overlaps {}", pair[1].2, pair[1].0, pair[0].2)));
+                }
+            }
+            if let Some((_, end, name)) = regions.last() {
+                if *end as usize > size {
+                    return Err(Error::new(&format!("{} goes past the end of the {:?} bus", name, bus)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Draws the map of a bus like the one in the manual
+    pub fn memory_map(&self, bus: BusKind) -> String {
+        let rows: Vec<(String, Address)> = self.regions(bus).into_iter()
+            .map(|(start, _, name)| {
+                let label = self.devices.iter().find(|device| device.bus == bus && device.name == name)
+                    .map(|device| device.kind.label().to_string())
+                    .unwrap_or_else(|| "Memory".to_string());
+                (label, start)
+            })
+            .collect();
+        let digits = if bus == BusKind::Main { 6 } else { 4 };
+        let label_width = rows.iter().map(|(label, _)| label.chars().count()).max().unwrap_or(0).max(8);
+        let address_width = digits + 3;
+
+        let inner = label_width + address_width + 4;
+        let mut map = format!("{} MEMORY MAP\n", if bus == BusKind::Main { "MAIN" } else { "DATA" });
+        map += &format!("╭{}╮\n", "─".repeat(inner));
+        for (label, start) in rows {
+            map += &format!("│╭{}╮╭{}╮│\n", "─".repeat(label_width), "─".repeat(address_width));
+            map += &format!("││{:<lw$}││ {:#0aw$x}││\n", label, start, lw = label_width, aw = digits + 2);
+            map += &format!("│╰{}╯╰{}╯│\n", "─".repeat(label_width), "─".repeat(address_width));
+        }
+        map += &format!("╰{}╯\n", "─".repeat(inner));
+        map
+    }
+}
@@ -1,2 +1,217 @@
 pub mod drive;
-pub mod tps;
\ No newline at end of file
+pub mod filesystem;
+pub mod tps;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use organum::core::ClockElapsed;
+use organum::error::Error;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Both controllers report on their last command with a register of flags and another one with
+// the fault that ended it, if any
+#[repr(u8)]
+pub enum Flag {
+    BUSY = 1<<0,
+    DONE = 1<<1,
+    ERROR = 1<<2,
+    PRESENT = 1<<3,
+    PROTECTED = 1<<4,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    NONE,
+    COMMAND,    // Unknown command
+    DRIVE,      // No such drive
+    SECTOR,     // Past the end of the media
+    ADDRESS,    // The transfer doesn't fit in memory
+    IO,         // The host failed to read or write the media
+    MEDIA,      // No media in the drive
+    PROTECTED,  // The media is write protected
+}
+
+// How long a drive takes to reach a sector and move it, a drive without timing does it all at once
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Timing {
+    // ns to move the head across one sector
+    #[serde(default)]
+    pub seek: u64,
+    // ns for the sector to come under the head once it is there
+    #[serde(default)]
+    pub rotation: u64,
+    // Bytes moved each second between the media and memory, 0 is instantaneous
+    #[serde(default)]
+    pub transfer: u64,
+}
+
+impl Timing {
+    // Time to go from the sector under the head to `sector` and move `bytes` from there
+    pub fn latency(&self, head: usize, sector: usize, bytes: usize) -> ClockElapsed {
+        let seek = self.seek * head.abs_diff(sector) as u64;
+        let transfer = match self.transfer {
+            0 => 0,
+            rate => bytes as u64 * 1_000_000_000 / rate,
+        };
+        seek + self.rotation + transfer
+    }
+}
+
+// What happens to the writes kept in memory with --snapshot-disks when the run ends
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotMode {
+    Discard,
+    Commit,
+}
+
+// Sectors written to an image while the disks are snapshotted, they are read back from here
+#[derive(Default)]
+pub struct Overlay {
+    sectors: HashMap<usize, Box<[u8]>>,
+}
+
+// How the drives treat their images. The overlays are kept here too, so that they can be committed
+// once the system is done with them
+#[derive(Default)]
+pub struct MediaConfig {
+    pub snapshot: Option<SnapshotMode>,
+    // Images the guest can't write to, by path or by their last components, as in `disk_1`
+    pub read_only: Vec<PathBuf>,
+    overlays: RefCell<Vec<(PathBuf, Rc<RefCell<Overlay>>)>>,
+}
+
+impl MediaConfig {
+    pub fn new(snapshot: Option<SnapshotMode>, read_only: Vec<PathBuf>) -> Self {
+        Self {
+            snapshot,
+            read_only,
+            overlays: RefCell::new(vec![]),
+        }
+    }
+
+    fn is_read_only(&self, path: &Path) -> bool {
+        self.read_only.iter().any(|image| path.ends_with(image))
+    }
+
+    fn overlay(&self, path: &Path) -> Option<Rc<RefCell<Overlay>>> {
+        self.snapshot?;
+        let overlay = Rc::new(RefCell::new(Overlay::default()));
+        self.overlays.borrow_mut().push((path.to_path_buf(), overlay.clone()));
+        Some(overlay)
+    }
+
+    // Ends the run for the images, their overlays are written to them or dropped as asked
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.snapshot != Some(SnapshotMode::Commit) {
+            return Ok(());
+        }
+        for (path, overlay) in self.overlays.borrow().iter() {
+            let overlay = overlay.borrow();
+            if overlay.sectors.is_empty() {
+                continue;
+            }
+            let io_error = |e: std::io::Error| Error::new(&format!("{}: unable to commit the changes: {}", path.display(), e));
+            // Disks that were missing at the start only get a file now
+            let mut file = OpenOptions::new().write(true).create(true).open(path).map_err(io_error)?;
+            for (sector, data) in overlay.sectors.iter() {
+                file.seek(SeekFrom::Start((*sector * SECTOR_SIZE) as u64)).map_err(io_error)?;
+                file.write_all(data).map_err(io_error)?;
+            }
+            println!("{}: committed {} sectors", path.display(), overlay.sectors.len());
+        }
+        Ok(())
+    }
+}
+
+// The image behind a drive. Reads past the end of the file give zeros, and writes go to the overlay
+// when there is one. It stays with its drive while the image is taken out and put back
+pub struct Media {
+    path: PathBuf,
+    file: Option<File>,
+    overlay: Option<Rc<RefCell<Overlay>>>,
+    read_only: bool,
+}
+
+impl Media {
+    pub fn new(path: &Path, config: &MediaConfig) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            file: None,
+            overlay: config.overlay(path),
+            read_only: config.is_read_only(path),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn open(&mut self) -> Result<(), std::io::Error> {
+        let file = OpenOptions::new().read(true).write(!self.read_only).open(&self.path)?;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    pub fn close(&mut self) {
+        self.file = None;
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn read_sector(&mut self, sector: usize, data: &mut [u8]) -> Result<(), std::io::Error> {
+        if let Some(written) = self.overlay.as_ref().and_then(|overlay| overlay.borrow().sectors.get(&sector).cloned()) {
+            data.copy_from_slice(&written);
+            return Ok(());
+        }
+
+        // Media kept only in memory has no file behind its overlay
+        let file = match (self.file.as_mut(), self.overlay.is_some()) {
+            (Some(file), _) => file,
+            (None, true) => {
+                data.fill(0);
+                return Ok(());
+            },
+            (None, false) => return Err(ErrorKind::NotFound.into()),
+        };
+        file.seek(SeekFrom::Start((sector * SECTOR_SIZE) as u64))?;
+        let mut read = 0;
+        while read < data.len() {
+            match file.read(&mut data[read..])? {
+                0 => break,
+                count => read += count,
+            }
+        }
+        data[read..].fill(0);
+        Ok(())
+    }
+
+    pub fn write_sector(&mut self, sector: usize, data: &[u8]) -> Result<(), std::io::Error> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        if let Some(overlay) = self.overlay.as_ref() {
+            overlay.borrow_mut().sectors.insert(sector, data.into());
+            return Ok(());
+        }
+
+        let file = self.file.as_mut().ok_or(ErrorKind::NotFound)?;
+        file.seek(SeekFrom::Start((sector * SECTOR_SIZE) as u64))?;
+        file.write_all(data)
+    }
+}
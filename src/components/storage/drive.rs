@@ -1,20 +1,25 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Seek, Write};
+use std::fs::OpenOptions;
 use std::path::Path;
-use std::io::SeekFrom;
-use std::io::Read;
 
-use organum::core::{TransmutableBox, Transmutable, Steppable, Addressable, Address, ClockElapsed};
+use organum::core::{TransmutableBox, Transmutable, Steppable, Addressable, Address, ClockElapsed, Snapshotable, SLEEP};
+use organum::scheduler::Waker;
 use organum::sys::System;
 use organum::error::Error;
+use organum::snapshot::{SnapshotReader, SnapshotWriter};
 
-pub const INTERRUPT_LOADED: u8 = 0xe;
+use super::{Flag, Fault, Media, MediaConfig, Timing};
+pub use super::SECTOR_SIZE;
+
+pub const INTERRUPT_DONE: u8 = 0xe;
 
 pub enum Command {
     Nop,
     StoreSector,
     LoadSector,
-    //Query
+    Identify,
+    StoreList,
+    LoadList,
+    Unknown,
 }
 
 impl From<u8> for Command {
@@ -23,7 +28,10 @@ impl From<u8> for Command {
             0 => Command::Nop,
             1 => Command::StoreSector,
             2 => Command::LoadSector,
-            _ => Command::Nop,
+            3 => Command::Identify,
+            4 => Command::StoreList,
+            5 => Command::LoadList,
+            _ => Command::Unknown,
         }
     }
 }
@@ -41,90 +49,89 @@ pub enum Register {
     POINTH,
     POINTL,
 
-    STATUS0,
-    STATUS1
+    STATUS0,    // Flags
+    STATUS1     // Fault when read, COUNT when written
 }
 
+// Sectors to move, or entries in a transfer list. It is write only, and shares its address with STATUS1
+pub const COUNT: Address = Register::STATUS1 as Address;
+
 pub const REGISTER_COUNT: usize = 8;
+// A transfer list entry is made of halfwords: first sector, memory block, sectors, and a reserved one
+pub const ENTRY_SIZE: usize = 8;
 
 struct Sector {
     data: [u8; 512]
 }
 
-pub struct  Disk {
-    filename: String,
-    descriptor: File,
-    sectors: u16
+pub struct Disk {
+    media: Media,
+    sectors: u16,
+    // Sector under the head, where the last transfer ended
+    head: u16,
 }
 
 impl Disk {
-    pub fn new(filename: String) -> Option<Self> {
-        println!("PATH: {filename}");
+    // Makes a blank disk in `filename`, or only in memory while the disks are snapshotted
+    pub fn new(filename: String, config: &MediaConfig) -> Option<Self> {
+        if config.snapshot.is_some() {
+            return Some(Self {
+                media: Media::new(Path::new(&filename), config),
+                sectors: u16::MAX,
+                head: 0,
+            });
+        }
         let descriptor = OpenOptions::new()
-                .create(true)
-                .read(true)
+                .create_new(true)
                 .write(true)
-                .open(Path::new(&filename));
-        if descriptor.is_err() { return None }
-        let descriptor = descriptor.unwrap();
-        descriptor.set_len(u16::MAX as u64 * 512);
-        Some(Self {
-            filename,
-            descriptor,
-            sectors: u16::MAX
-        })
+                .open(Path::new(&filename)).ok()?;
+        descriptor.set_len(u16::MAX as u64 * 512).ok()?;
+        Self::open(filename, config)
     }
 
-    pub fn open(filename: String) -> Option<Self> {
-        let descriptor = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(Path::new(&filename));
-        if descriptor.is_err() { return None }
-        let descriptor = descriptor.unwrap();
-
-       Some(Self {
-            filename,
-            descriptor,
-            sectors: u16::MAX
+    // A disk is used as it is found, the sectors past the end of its file read as zeros
+    pub fn open(filename: String, config: &MediaConfig) -> Option<Self> {
+        let mut media = Media::new(Path::new(&filename), config);
+        media.open().ok()?;
+        Some(Self {
+            media,
+            sectors: u16::MAX,
+            head: 0,
         })
     }
 
-
     pub fn store_sector(&mut self, sector: u16, data: &Sector) -> Result<(), std::io::Error> {
-        self.descriptor.seek(SeekFrom::Start(sector as u64 * data.data.len() as u64))?;
-        self.descriptor.write_all(&data.data)?;
-        Ok(())
+        self.media.write_sector(sector as usize, &data.data)
     }
 
     pub fn load_sector(&mut self, sector: u16, data: &mut Sector) -> Result<(), std::io::Error> {
-        self.descriptor.seek(SeekFrom::Start(sector as u64 * data.data.len() as u64))?;
-        self.descriptor.read(&mut data.data);
-        Ok(())
+        self.media.read_sector(sector as usize, &mut data.data)
     }
 }
 
 pub struct Drive {
-    disk: Vec<Disk>,
+    disk: Vec<Option<Disk>>,
     current: usize,
 }
 
 
 impl Drive {
-    pub fn new(path: &str) -> Self {
+    pub fn new(path: &str, config: &MediaConfig) -> Self {
     	let name = "disk";
         let mut disk = Vec::new();
         for i in 0..4 {
-            let mut d = Disk::open(format!("{path}/{name}_{i}"));
+            let mut d = Disk::open(format!("{path}/{name}_{i}"), config);
             if d.is_none() {
-            	d = Disk::new(format!("{path}/{name}_{i}"));
+            	d = Disk::new(format!("{path}/{name}_{i}"), config);
             	if d.is_none() {
             		use std::fs;
             		fs::create_dir_all(path).expect("Unable to create directory for disk devices");
-            		d = Disk::new(format!("{path}/{name}_{i}"));	
+            		d = Disk::new(format!("{path}/{name}_{i}"), config);
             	}
             }
-            let d = d.unwrap();
+            if d.is_none() {
+                println!("[DISK] unable to open {path}/{name}_{i}, the drive is left empty");
+            }
             disk.push(d);
         }
 
@@ -133,23 +140,68 @@ impl Drive {
             current: 0
         }
     }
+
+    // Picks the drive in `data` as the current one, checking that the `count` sectors from `sector` on
+    // are on its disk
+    fn select(&mut self, data: u8, sector: u16, count: u16) -> Result<&mut Disk, Fault> {
+        let current = data as usize;
+        if current >= self.disk.len() {
+            return Err(Fault::DRIVE);
+        }
+        self.current = current;
+
+        let disk = self.disk[current].as_mut().ok_or(Fault::MEDIA)?;
+        if sector as usize + count as usize > disk.sectors as usize {
+            return Err(Fault::SECTOR);
+        }
+        Ok(disk)
+    }
+}
+
+// `count` sectors from `sector` on, moved to or from the 512 byte block `point` of main memory onwards
+#[derive(Clone, Copy)]
+struct Transfer {
+    sector: u16,
+    point: u16,
+    count: u16,
+}
+
+// A command taken from the registers when it was written, it runs once the drive gets to it
+#[derive(Clone, Copy)]
+struct Request {
+    command: u8,
+    data: u8,
+    sector: u16,
+    point: u16,
+    count: u8,
 }
 
 pub struct Controller {
-    frequency: ClockElapsed,
+    waker: Waker,
     dev: TransmutableBox,
+    // Where the registers are on the data bus, a transfer list can't be read from them
+    base: Address,
     drive: Drive,
+    vector: u8,
+    timing: Timing,
+    count: u8,
+    pending: Option<Request>,
 
     incoming: Sector,
     outcoming: Sector,
 }
 
 impl Controller {
-    pub fn new(drive: Drive, dev: TransmutableBox, frequency: ClockElapsed) -> Self {
+    pub fn new(drive: Drive, dev: TransmutableBox, base: Address, vector: u8, timing: Timing) -> Self {
         Self {
-            frequency,
+            waker: Waker::new(),
             dev,
+            base,
             drive,
+            vector,
+            timing,
+            count: 0,
+            pending: None,
             incoming: Sector {
                 data: [0; 512]
             },
@@ -159,43 +211,156 @@ impl Controller {
         }
     }
 
-    pub fn execute(&mut self, system: &System, command: Command, (data, sector, point) : (u8, u16, u16)) -> Result<(), Error> {
-        match command {
-            Command::Nop => Ok(()),
-            Command::StoreSector => {
-                self.drive.current = self.u8_to_drive(data);
+    fn request(&mut self) -> Result<Request, Error> {
+        let mut command = [0u8; 6];
+        self.read(Register::COMMAND as Address, &mut command)?;
+        Ok(Request {
+            command: command[0],
+            data: command[1],
+            sector: (command[2] as u16) << 8 | command[3] as u16,
+            point: (command[4] as u16) << 8 | command[5] as u16,
+            count: self.count,
+        })
+    }
 
-                system.get_bus().read(point as Address * 512, &mut self.incoming.data)?;
+    // The transfers a request makes, the sectors in the registers or the ones listed in data memory.
+    // A count of 0 moves a single sector, like 1 does
+    fn transfers(&self, system: &System, request: &Request) -> Result<Vec<Transfer>, Fault> {
+        match Command::from(request.command) {
+            Command::StoreSector | Command::LoadSector => Ok(vec![Transfer {
+                sector: request.sector,
+                point: request.point,
+                count: request.count.max(1) as u16,
+            }]),
+            Command::StoreList | Command::LoadList => {
+                let table = request.point as Address;
+                let mut entries = vec![0u8; request.count as usize * ENTRY_SIZE];
+                if table < self.base + REGISTER_COUNT as Address && self.base < table + entries.len() as Address {
+                    return Err(Fault::ADDRESS);
+                }
+                system.get_data().read(table, &mut entries).map_err(|_| Fault::ADDRESS)?;
+                Ok(entries.chunks(ENTRY_SIZE).map(|entry| Transfer {
+                    sector: (entry[0] as u16) << 8 | entry[1] as u16,
+                    point: (entry[2] as u16) << 8 | entry[3] as u16,
+                    count: (entry[4] as u16) << 8 | entry[5] as u16,
+                }).collect())
+            },
+            _ => Ok(vec![]),
+        }
+    }
 
-                self.drive.disk[self.drive.current].store_sector(sector, &mut self.incoming)
-                    .or_else(|e| {Err(Error::new(&format!("{}", e)))})?;
+    // Time until the request is done. Faults are found when it runs, so a request that will fail takes none
+    fn latency(&self, system: &System, request: &Request) -> ClockElapsed {
+        let (disk, transfers) = match (self.drive.disk.get(request.data as usize), self.transfers(system, request)) {
+            (Some(Some(disk)), Ok(transfers)) => (disk, transfers),
+            _ => return 0,
+        };
+        let mut head = disk.head as usize;
+        transfers.iter().filter(|transfer| transfer.count > 0).map(|transfer| {
+            let latency = self.timing.latency(head, transfer.sector as usize, transfer.count as usize * SECTOR_SIZE);
+            head = transfer.sector as usize + transfer.count as usize - 1;
+            latency
+        }).sum()
+    }
+
+    // Runs the pending request, and interrupts to tell it's over
+    fn complete(&mut self, system: &System) -> Result<ClockElapsed, Error> {
+        if let Some(request) = self.pending.take() {
+            let result = self.execute(system, &request);
+            self.expose(result)?;
+            system.get_interrupt_controller().set(true, self.vector)?;
+        }
+        Ok(SLEEP)
+    }
+
+    fn execute(&mut self, system: &System, request: &Request) -> Result<(), Fault> {
+        let data = request.data;
+        match Command::from(request.command) {
+            Command::Nop => Ok(()),
+            Command::StoreSector | Command::StoreList => {
+                for transfer in self.transfers(system, request)? {
+                    self.store(system, data, transfer)?;
+                }
                 Ok(())
             },
-            Command::LoadSector => {
-                self.drive.current = self.u8_to_drive(data);
-
-                self.drive.disk[self.drive.current].load_sector(sector, &mut self.outcoming)
-                    .or_else(|e| {Err(Error::new(&format!("{}", e)))})?;
-                system.get_bus().write(point as Address * 512, &self.outcoming.data)?;
-                system.get_interrupt_controller().set(true, 5, INTERRUPT_LOADED)?;
+            Command::LoadSector | Command::LoadList => {
+                for transfer in self.transfers(system, request)? {
+                    self.load(system, data, transfer)?;
+                }
+                Ok(())
+            },
+            // Leaves the sectors on the disk in SECTOR, none if the drive is empty, and their size in POINT
+            Command::Identify => {
+                let sectors = match self.drive.select(data, 0, 0) {
+                    Ok(disk) => disk.sectors,
+                    Err(Fault::MEDIA) => 0,
+                    Err(fault) => return Err(fault),
+                };
+                self.write_beu16(Register::SECTORH as Address, sectors).map_err(|_| Fault::IO)?;
+                self.write_beu16(Register::POINTH as Address, SECTOR_SIZE as u16).map_err(|_| Fault::IO)?;
                 Ok(())
             },
+            Command::Unknown => Err(Fault::COMMAND),
         }
     }
 
-    pub fn expose(&self) {
-        // TODO: Do something with status
+    // Sectors are moved one at a time, so a transfer that fails halfway leaves the ones before it done
+    fn store(&mut self, system: &System, data: u8, transfer: Transfer) -> Result<(), Fault> {
+        let disk = self.drive.select(data, transfer.sector, transfer.count)?;
+        if disk.media.is_read_only() {
+            return Err(Fault::PROTECTED);
+        }
+        for i in 0..transfer.count {
+            system.get_bus().read((transfer.point as Address + i as Address) * 512, &mut self.incoming.data)
+                .map_err(|_| Fault::ADDRESS)?;
+
+            disk.head = transfer.sector + i;
+            disk.store_sector(transfer.sector + i, &self.incoming)
+                .map_err(|e| io_fault(disk, e))?;
+        }
+        Ok(())
     }
 
-    fn u8_to_drive(&mut self, d: u8) -> usize {
-        match d {
-            0 => 0,
-            1 => 1,
-            2 => 2,
-            3 => 3,
-            _ => 0,
+    fn load(&mut self, system: &System, data: u8, transfer: Transfer) -> Result<(), Fault> {
+        let disk = self.drive.select(data, transfer.sector, transfer.count)?;
+        for i in 0..transfer.count {
+            disk.head = transfer.sector + i;
+            disk.load_sector(transfer.sector + i, &mut self.outcoming)
+                .map_err(|e| io_fault(disk, e))?;
+
+            system.get_bus().write((transfer.point as Address + i as Address) * 512, &self.outcoming.data)
+                .map_err(|_| Fault::ADDRESS)?;
         }
+        Ok(())
     }
+
+    // Leaves the outcome of the last command in the status registers
+    fn expose(&mut self, result: Result<(), Fault>) -> Result<(), Error> {
+        let mut flags = Flag::DONE as u8;
+        if let Some(disk) = &self.drive.disk[self.drive.current] {
+            flags |= Flag::PRESENT as u8;
+            if disk.media.is_read_only() {
+                flags |= Flag::PROTECTED as u8;
+            }
+        }
+        let fault = match result {
+            Ok(()) => Fault::NONE,
+            Err(fault) => {
+                flags |= Flag::ERROR as u8;
+                fault
+            },
+        };
+        let mut dev = self.dev.borrow_mut();
+        let dev = dev.as_addressable().unwrap();
+        dev.write_u8(Register::STATUS0 as Address, flags)?;
+        dev.write_u8(Register::STATUS1 as Address, fault as u8)
+    }
+}
+
+// Host errors are reported to the guest, but the user would like to know what went wrong
+fn io_fault(disk: &Disk, e: std::io::Error) -> Fault {
+    println!("[DISK] {}: {}", disk.media.path().display(), e);
+    Fault::IO
 }
 
 
@@ -208,25 +373,96 @@ impl Addressable for Controller {
         self.dev.borrow_mut().as_addressable().unwrap().read(addr, data)
     }
 
+    // Writing the command register wakes the controller up to run it, unless it is still busy with
+    // the last one, then the write is lost. The status can't be written, and COUNT is kept apart
     fn write(&mut self, addr: Address, data: &[u8]) -> Result<(), Error> {
-        self.dev.borrow_mut().as_addressable().unwrap().write(addr, data)
+        let mut dev = self.dev.borrow_mut();
+        let dev = dev.as_addressable().unwrap();
+        if addr == Register::COMMAND as Address && dev.read_u8(Register::STATUS0 as Address)? & Flag::BUSY as u8 != 0 {
+            return Ok(());
+        }
+        for (i, byte) in data.iter().enumerate() {
+            match addr + i as Address {
+                COUNT => self.count = *byte,
+                addr if addr == Register::STATUS0 as Address => { },
+                addr => dev.write_u8(addr, *byte)?,
+            }
+        }
+        if addr == Register::COMMAND as Address {
+            dev.write_u8(Register::STATUS0 as Address, Flag::BUSY as u8)?;
+            self.waker.wake();
+        }
+        Ok(())
     }
 }
 
 impl Steppable for Controller {
+    // Woken by a command, the controller takes it and sleeps until it is done. One that takes no time
+    // is done right away. The system also steps it once when it starts, with no command to run
     fn step(&mut self, system: &System) -> Result<ClockElapsed, Error> {
-        let mut command = [0u8; 6];
-        self.read(Register::COMMAND as Address, &mut command)?;
-        let (data, sector, point) = (
-            command[1],
-            (command[2] as u16) << 8 | command[3] as u16,
-            (command[4] as u16) << 8 | command[5] as u16,
-        );
+        if self.pending.is_some() {
+            return self.complete(system);
+        }
+        if self.read_u8(Register::STATUS0 as Address)? & Flag::BUSY as u8 == 0 {
+            return Ok(SLEEP);
+        }
 
-        self.execute(system, Command::from(command[0]), (data, sector, point))?;
-        self.expose();
+        let request = self.request()?;
+        let latency = self.latency(system, &request);
+        self.pending = Some(request);
+        if latency > 0 {
+            return Ok(latency);
+        }
+        self.complete(system)
+    }
 
-        Ok(1_000_000_000 / self.frequency)
+    fn waker(&self) -> Option<Waker> {
+        Some(self.waker.clone())
+    }
+}
+
+// The media lives in its own files, only the controller registers, the heads and the command in
+// flight are saved
+impl Snapshotable for Controller {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        self.dev.borrow_mut().as_snapshotable().unwrap().save_state(snapshot)?;
+        snapshot.write_u8(self.drive.current as u8);
+        snapshot.write_u8(self.count);
+        for disk in self.drive.disk.iter() {
+            snapshot.write_beu16(disk.as_ref().map_or(0, |disk| disk.head));
+        }
+        snapshot.write_bool(self.pending.is_some());
+        if let Some(request) = self.pending {
+            snapshot.write_u8(request.command);
+            snapshot.write_u8(request.data);
+            snapshot.write_beu16(request.sector);
+            snapshot.write_beu16(request.point);
+            snapshot.write_u8(request.count);
+        }
+        Ok(())
+    }
+
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        self.dev.borrow_mut().as_snapshotable().unwrap().load_state(snapshot)?;
+        self.drive.current = snapshot.read_u8()? as usize;
+        self.count = snapshot.read_u8()?;
+        for disk in self.drive.disk.iter_mut() {
+            let head = snapshot.read_beu16()?;
+            if let Some(disk) = disk {
+                disk.head = head;
+            }
+        }
+        self.pending = match snapshot.read_bool()? {
+            true => Some(Request {
+                command: snapshot.read_u8()?,
+                data: snapshot.read_u8()?,
+                sector: snapshot.read_beu16()?,
+                point: snapshot.read_beu16()?,
+                count: snapshot.read_u8()?,
+            }),
+            false => None,
+        };
+        Ok(())
     }
 }
 
@@ -238,4 +474,8 @@ impl Transmutable for Controller {
     fn as_steppable(&mut self) -> Option<&mut dyn Steppable> {
         Some(self)
     }
+
+    fn as_snapshotable(&mut self) -> Option<&mut dyn Snapshotable> {
+        Some(self)
+    }
 }
@@ -0,0 +1,358 @@
+// filesystem.rs defines the Taleä filesystem, shared by disks and tps media, and the host side tools
+// that read and write it. Files are kept in one run of sectors each, so a guest program loads a file
+// with a single sequence of sector reads. Sector 0 is left alone, it is where a tps keeps its boot code.
+// Numbers are big endian, as the machine sees them
+//   sector 1                 superblock
+//   bitmap_start..           allocation bitmap, one bit per sector of the volume, most significant first
+//   dir_start..              directory, ENTRY_SIZE bytes per entry
+//   data_start..             file contents
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use organum::error::Error;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const MAGIC: &[u8; 8] = b"TALEAFS\0";
+pub const VERSION: u16 = 1;
+pub const SUPERBLOCK: u16 = 1;
+
+/* The superblock:
+    ╭──┬────────────────────────────╮
+    │ 0│magic, 8 bytes              │
+    │ 8│version                     │
+    │10│sectors in the volume       │
+    │12│first bitmap sector         │
+    │14│bitmap sectors              │
+    │16│first directory sector      │
+    │18│directory sectors           │
+    │20│first data sector           │
+    ╰──┴────────────────────────────╯
+   Every field is a halfword
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Superblock {
+    pub sectors: u16,
+    pub bitmap_start: u16,
+    pub bitmap_sectors: u16,
+    pub dir_start: u16,
+    pub dir_sectors: u16,
+    pub data_start: u16,
+}
+
+impl Superblock {
+    // The areas have to follow each other in order within the volume, and the bitmap has to cover it
+    fn check(&self) -> Result<(), &'static str> {
+        let bitmap_end = self.bitmap_start as usize + self.bitmap_sectors as usize;
+        let dir_end = self.dir_start as usize + self.dir_sectors as usize;
+        if self.bitmap_start <= SUPERBLOCK || bitmap_end > self.dir_start as usize || dir_end > self.data_start as usize {
+            return Err("its areas overlap");
+        }
+        if self.data_start > self.sectors {
+            return Err("its areas run past the volume");
+        }
+        if self.bitmap_sectors as usize * SECTOR_SIZE * 8 < self.sectors as usize {
+            return Err("its bitmap doesn't cover the volume");
+        }
+        Ok(())
+    }
+}
+
+/* A directory entry, unused when the name starts with a 0:
+    ╭──┬────────────────────────────╮
+    │ 0│name, padded with 0s        │
+    │24│first sector, halfword      │
+    │26│size in bytes, word         │
+    │30│reserved                    │
+    ╰──┴────────────────────────────╯
+*/
+pub const ENTRY_SIZE: usize = 32;
+pub const NAME_SIZE: usize = 24;
+pub const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_SIZE;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub start: u16,
+    pub size: u32,
+}
+
+impl Entry {
+    pub fn sectors(&self) -> usize {
+        (self.size as usize + SECTOR_SIZE - 1) / SECTOR_SIZE
+    }
+
+    // Files live in the data area, empty ones take no sectors at all
+    fn check(&self, superblock: &Superblock) -> Result<(), &'static str> {
+        let sectors = self.sectors();
+        if sectors > 0 && (self.start < superblock.data_start || self.start as usize + sectors > superblock.sectors as usize) {
+            return Err("its sectors are outside of the data area");
+        }
+        Ok(())
+    }
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data[0] == 0 {
+            return None;
+        }
+        let name = data[..NAME_SIZE].iter().take_while(|byte| **byte != 0).map(|byte| *byte as char).collect();
+        Some(Self {
+            name,
+            start: u16::from_be_bytes([data[24], data[25]]),
+            size: u32::from_be_bytes([data[26], data[27], data[28], data[29]]),
+        })
+    }
+
+    fn serialize(entry: Option<&Entry>, data: &mut [u8]) {
+        data.fill(0);
+        if let Some(entry) = entry {
+            data[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+            data[24..26].copy_from_slice(&entry.start.to_be_bytes());
+            data[26..30].copy_from_slice(&entry.size.to_be_bytes());
+        }
+    }
+}
+
+// Printable ascii that fits the entry, without the / so names can't be taken for paths
+pub fn check_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.len() > NAME_SIZE {
+        return Err(Error::new(&format!("fs: names take 1 to {} characters, {:?} doesn't fit", NAME_SIZE, name)));
+    }
+    if !name.bytes().all(|byte| byte.is_ascii_graphic() && byte != b'/') {
+        return Err(Error::new(&format!("fs: {:?} has characters a name can't have", name)));
+    }
+    Ok(())
+}
+
+pub struct FileSystem {
+    path: PathBuf,
+    file: File,
+    pub superblock: Superblock,
+    bitmap: Vec<u8>,
+    entries: Vec<Option<Entry>>,
+}
+
+impl FileSystem {
+    // Lays out an empty filesystem over the whole image, which has to exist already
+    pub fn format(path: &Path) -> Result<Self, Error> {
+        let file = open(path)?;
+        let length = file.metadata().map_err(|e| io_error(path, e))?.len();
+        let sectors = (length / SECTOR_SIZE as u64).min(u16::MAX as u64) as usize;
+
+        // Small volumes like a tps get a couple of directory sectors, a disk gets 16
+        let entries = (sectors / 8).clamp(ENTRIES_PER_SECTOR, 256);
+        let bitmap_start = SUPERBLOCK as usize + 1;
+        let bitmap_sectors = (sectors + SECTOR_SIZE * 8 - 1) / (SECTOR_SIZE * 8);
+        let dir_start = bitmap_start + bitmap_sectors;
+        let dir_sectors = (entries + ENTRIES_PER_SECTOR - 1) / ENTRIES_PER_SECTOR;
+        let data_start = dir_start + dir_sectors;
+        if data_start >= sectors {
+            return Err(Error::new(&format!("fs: {} has {} sectors, too few for a filesystem", path.display(), sectors)));
+        }
+
+        let superblock = Superblock {
+            sectors: sectors as u16,
+            bitmap_start: bitmap_start as u16,
+            bitmap_sectors: bitmap_sectors as u16,
+            dir_start: dir_start as u16,
+            dir_sectors: dir_sectors as u16,
+            data_start: data_start as u16,
+        };
+        let mut fs = Self {
+            path: path.to_path_buf(),
+            file,
+            superblock,
+            bitmap: vec![0; bitmap_sectors * SECTOR_SIZE],
+            entries: vec![None; dir_sectors * ENTRIES_PER_SECTOR],
+        };
+        for sector in 0..data_start {
+            fs.set_used(sector, true);
+        }
+        fs.write_superblock()?;
+        fs.flush()?;
+        Ok(fs)
+    }
+
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut file = open(path)?;
+        let data = read_sectors(&mut file, path, SUPERBLOCK as usize, 1)?;
+        if &data[..8] != MAGIC {
+            return Err(Error::new(&format!("fs: {} has no Taleä filesystem, make one with `fs mkfs`", path.display())));
+        }
+        let field = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+        if field(8) != VERSION {
+            return Err(Error::new(&format!("fs: {} has a filesystem of version {}, only {} is known", path.display(), field(8), VERSION)));
+        }
+        let superblock = Superblock {
+            sectors: field(10),
+            bitmap_start: field(12),
+            bitmap_sectors: field(14),
+            dir_start: field(16),
+            dir_sectors: field(18),
+            data_start: field(20),
+        };
+        superblock.check().map_err(|problem| Error::new(&format!("fs: {} has a damaged superblock, {}", path.display(), problem)))?;
+
+        let bitmap = read_sectors(&mut file, path, superblock.bitmap_start as usize, superblock.bitmap_sectors as usize)?;
+        let entries = read_sectors(&mut file, path, superblock.dir_start as usize, superblock.dir_sectors as usize)?
+            .chunks(ENTRY_SIZE)
+            .map(Entry::parse)
+            .collect::<Vec<_>>();
+        for entry in entries.iter().flatten() {
+            entry.check(&superblock).map_err(|problem| Error::new(&format!("fs: {} has a damaged entry {:?}, {}", path.display(), entry.name, problem)))?;
+        }
+        Ok(Self { path: path.to_path_buf(), file, superblock, bitmap, entries })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().flatten()
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries().find(|entry| entry.name == name)
+    }
+
+    pub fn free_sectors(&self) -> usize {
+        (0..self.superblock.sectors as usize).filter(|sector| !self.is_used(*sector)).count()
+    }
+
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>, Error> {
+        let entry = self.find(name).cloned()
+            .ok_or_else(|| Error::new(&format!("fs: no {} in {}", name, self.path.display())))?;
+        let mut data = read_sectors(&mut self.file, &self.path, entry.start as usize, entry.sectors())?;
+        data.truncate(entry.size as usize);
+        Ok(data)
+    }
+
+    // A file that already has the name is replaced, and only once the new one is sure to fit, so a
+    // failed write leaves the old one in place. Its sectors count as free for the new one
+    pub fn write(&mut self, name: &str, data: &[u8]) -> Result<(), Error> {
+        check_name(name)?;
+        let old = self.slot(name);
+        let slot = old.or_else(|| self.entries.iter().position(Option::is_none))
+            .ok_or_else(|| Error::new(&format!("fs: the directory of {} is full", self.path.display())))?;
+
+        let size = u32::try_from(data.len()).map_err(|_| Error::new(&format!("fs: {} is too large", name)))?;
+        let mut entry = Entry { name: name.to_string(), start: 0, size };
+        let sectors = entry.sectors();
+
+        let previous = self.bitmap.clone();
+        if let Some(old) = old {
+            self.release(old);
+        }
+        if sectors > 0 {
+            let start = match self.allocate(sectors) {
+                Some(start) => start,
+                None => {
+                    self.bitmap = previous;
+                    return Err(Error::new(&format!("fs: no run of {} free sectors in {} for {}", sectors, self.path.display(), name)));
+                },
+            };
+            entry.start = start as u16;
+
+            let mut padded = data.to_vec();
+            padded.resize(sectors * SECTOR_SIZE, 0);
+            write_sectors(&mut self.file, &self.path, start, &padded)?;
+            for sector in start..start + sectors {
+                self.set_used(sector, true);
+            }
+        }
+        self.entries[slot] = Some(entry);
+        self.flush()
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), Error> {
+        let slot = self.slot(name)
+            .ok_or_else(|| Error::new(&format!("fs: no {} in {}", name, self.path.display())))?;
+        self.release(slot);
+        self.entries[slot] = None;
+        self.flush()
+    }
+
+    fn slot(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| matches!(entry, Some(entry) if entry.name == name))
+    }
+
+    // Frees the sectors of the file in the slot, the entry itself is left to the caller
+    fn release(&mut self, slot: usize) {
+        if let Some(entry) = self.entries[slot].clone() {
+            for sector in entry.start as usize..entry.start as usize + entry.sectors() {
+                self.set_used(sector, false);
+            }
+        }
+    }
+
+    fn is_used(&self, sector: usize) -> bool {
+        self.bitmap[sector / 8] & (0x80 >> (sector % 8)) != 0
+    }
+
+    fn set_used(&mut self, sector: usize, used: bool) {
+        if used {
+            self.bitmap[sector / 8] |= 0x80 >> (sector % 8);
+        } else {
+            self.bitmap[sector / 8] &= !(0x80 >> (sector % 8));
+        }
+    }
+
+    // First run of free sectors long enough
+    fn allocate(&self, count: usize) -> Option<usize> {
+        let mut start = self.superblock.data_start as usize;
+        let end = self.superblock.sectors as usize;
+        while start + count <= end {
+            match (start..start + count).find(|sector| self.is_used(*sector)) {
+                Some(used) => start = used + 1,
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    fn write_superblock(&mut self) -> Result<(), Error> {
+        let superblock = self.superblock;
+        let mut data = vec![0; SECTOR_SIZE];
+        data[..8].copy_from_slice(MAGIC);
+        let fields = [VERSION, superblock.sectors, superblock.bitmap_start, superblock.bitmap_sectors,
+            superblock.dir_start, superblock.dir_sectors, superblock.data_start];
+        for (i, field) in fields.iter().enumerate() {
+            data[8 + i * 2..10 + i * 2].copy_from_slice(&field.to_be_bytes());
+        }
+        write_sectors(&mut self.file, &self.path, SUPERBLOCK as usize, &data)
+    }
+
+    // Writes back the bitmap and the directory
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut directory = vec![0; self.entries.len() * ENTRY_SIZE];
+        for (entry, data) in self.entries.iter().zip(directory.chunks_mut(ENTRY_SIZE)) {
+            Entry::serialize(entry.as_ref(), data);
+        }
+        write_sectors(&mut self.file, &self.path, self.superblock.bitmap_start as usize, &self.bitmap)?;
+        write_sectors(&mut self.file, &self.path, self.superblock.dir_start as usize, &directory)
+    }
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> Error {
+    Error::new(&format!("fs: {}: {}", path.display(), e))
+}
+
+fn open(path: &Path) -> Result<File, Error> {
+    OpenOptions::new().read(true).write(true).open(path).map_err(|e| io_error(path, e))
+}
+
+// Past the end of the file reads as zeros
+fn read_sectors(file: &mut File, path: &Path, sector: usize, count: usize) -> Result<Vec<u8>, Error> {
+    let mut data = vec![0; count * SECTOR_SIZE];
+    file.seek(SeekFrom::Start((sector * SECTOR_SIZE) as u64)).map_err(|e| io_error(path, e))?;
+    let mut read = 0;
+    while read < data.len() {
+        match file.read(&mut data[read..]).map_err(|e| io_error(path, e))? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(data)
+}
+
+fn write_sectors(file: &mut File, path: &Path, sector: usize, data: &[u8]) -> Result<(), Error> {
+    file.seek(SeekFrom::Start((sector * SECTOR_SIZE) as u64)).map_err(|e| io_error(path, e))?;
+    file.write_all(data).map_err(|e| io_error(path, e))
+}
@@ -1,19 +1,29 @@
 use std::fs::{File, OpenOptions};
 use std::io::{Seek, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io::SeekFrom;
 use std::io::Read;
 
-use organum::core::{TransmutableBox, Transmutable, Steppable, Addressable, Address, ClockElapsed};
+use organum::core::{TransmutableBox, Transmutable, Steppable, Addressable, Address, ClockElapsed, Snapshotable, SLEEP};
+use organum::scheduler::Waker;
 use organum::sys::System;
 use organum::error::Error;
+use organum::snapshot::{SnapshotReader, SnapshotWriter};
 
-pub const INTERRUPT_LOADED: u8 = 0xd;
+use super::{Flag, Fault, Media, MediaConfig, Timing};
+pub use super::SECTOR_SIZE;
+
+pub const INTERRUPT_DONE: u8 = 0xd;
+
+pub const SECTORS: usize = u8::MAX as usize;
+// The last two bytes of a sector 0 that can be booted from
+pub const BOOT_SIGNATURE: [u8; 2] = [0xA1, 0xEA];
 
 pub struct Tps {
-    filename: String,
-    descriptor: File,
-    sectors: u8
+    media: Media,
+    sectors: u8,
+    // Sector under the head, where the last transfer ended
+    head: u8,
 }
 
 struct Sector {
@@ -26,10 +36,10 @@ pub struct Drive {
 }
 
 impl Drive {
-    pub fn new(path: &str) -> Self {
+    pub fn new(path: &str, config: &MediaConfig) -> Self {
         let mut tps = Vec::new();
         for i in 0..2 {
-            tps.push(Tps::new(format!("{}_{}", path, i)));
+            tps.push(Tps::new(format!("{}_{}", path, i), config));
         }
 
         Self {
@@ -46,7 +56,9 @@ pub enum Command {
     Open,
     Close,
     StoreSector,
-    LoadSector
+    LoadSector,
+    Identify,
+    Unknown,
 }
 
 impl From<u8> for Command {
@@ -60,7 +72,8 @@ impl From<u8> for Command {
             4 => Command::Close,
             5 => Command::StoreSector,
             6 => Command::LoadSector,
-            _ => Command::Nop,
+            7 => Command::Identify,
+            _ => Command::Unknown,
         }
     }
 }
@@ -72,67 +85,97 @@ pub enum Register {
     POINTH,
     POINTL,
 
-    STATUSH,
-    STATUSL,
+    STATUSH,    // Flags
+    STATUSL,    // Result, or the fault
 }
 pub const REGISTER_COUNT: usize = 6;
 
 impl Tps {
-    pub fn new(filename: String) -> Self {
-        let descriptor = OpenOptions::new()
-                .create(false)
-                .read(true)
-                .write(true)
-                .open(Path::new(&filename)).unwrap();
-        descriptor.set_len(u8::MAX as u64 * 512);
+    // A drive whose media can't be opened is left empty
+    pub fn new(filename: String, config: &MediaConfig) -> Self {
+        let mut media = Media::new(Path::new(&filename), config);
+        let _ = media.open();
         Self {
-            filename,
-            descriptor,
-            sectors: u8::MAX
+            media,
+            sectors: u8::MAX,
+            head: 0,
         }
     }
 
-    pub fn open(filename: &str) -> Self {
-        let descriptor = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(Path::new(&filename)).unwrap();
+    pub fn open(&mut self) -> Result<(), std::io::Error> {
+        self.media.open()
+    }
 
-        Self {
-            filename: filename.to_owned(),
-            descriptor,
-            sectors: u8::MAX
-        }
+    pub fn close(&mut self) {
+        self.media.close();
     }
 
-    pub fn store_sector(&mut self, sector: u8, data: &Sector) -> Result<(), std::io::Error> {
-        self.descriptor.seek(SeekFrom::Start(sector as u64 * data.data.len() as u64))?;
-        self.descriptor.write_all(&data.data)?;
-        Ok(())
+    pub fn is_open(&self) -> bool {
+        self.media.is_open()
     }
 
-    pub fn load_sector(&mut self, sector: u8, data: &mut Sector) -> Result<(), std::io::Error> {
-        self.descriptor.seek(SeekFrom::Start(sector as u64 * data.data.len() as u64))?;
-        self.descriptor.read(&mut data.data);
+    // Checks there is media in the drive and `sector` on it, and moves the head there
+    fn check(&mut self, sector: u8) -> Result<(), Fault> {
+        if sector >= self.sectors {
+            return Err(Fault::SECTOR);
+        }
+        if !self.media.is_open() {
+            return Err(Fault::MEDIA);
+        }
+        self.head = sector;
         Ok(())
     }
+
+    fn io_fault(&self, e: std::io::Error) -> Fault {
+        println!("[TPS] {}: {}", self.media.path().display(), e);
+        Fault::IO
+    }
+
+    fn store_sector(&mut self, sector: u8, data: &Sector) -> Result<(), Fault> {
+        self.check(sector)?;
+        if self.media.is_read_only() {
+            return Err(Fault::PROTECTED);
+        }
+        self.media.write_sector(sector as usize, &data.data)
+            .map_err(|e| self.io_fault(e))
+    }
+
+    fn load_sector(&mut self, sector: u8, data: &mut Sector) -> Result<(), Fault> {
+        self.check(sector)?;
+        self.media.read_sector(sector as usize, &mut data.data)
+            .map_err(|e| self.io_fault(e))
+    }
+}
+
+// A command taken from the registers when it was written, it runs once the drive gets to it
+#[derive(Clone, Copy)]
+struct Request {
+    command: u8,
+    data: u8,
+    point: u16,
 }
 
 pub struct Controller {
-    frequency: ClockElapsed,
+    waker: Waker,
     dev: TransmutableBox,
     drive: Drive,
+    vector: u8,
+    timing: Timing,
+    pending: Option<Request>,
 
     incoming: Sector,
     outcoming: Sector,
 }
 
 impl Controller {
-    pub fn new(drive: Drive, dev: TransmutableBox, frequency: ClockElapsed) -> Self {
+    pub fn new(drive: Drive, dev: TransmutableBox, vector: u8, timing: Timing) -> Self {
         Self {
-            frequency,
+            waker: Waker::new(),
             dev,
             drive,
+            vector,
+            timing,
+            pending: None,
             incoming: Sector {
                 data: [0; 512]
             },
@@ -142,65 +185,104 @@ impl Controller {
         }
     }
 
-    pub fn execute(&mut self, system: &System, command: u8, (data, point) : (u8, u16)) -> Result<(), Error> {
-        let b = command & 0x80 == 0;
-        self.drive.current = if b { 0 } else { 1 };
-        let command = Command::from(command);
+    fn request(&mut self) -> Result<Request, Error> {
+        let mut command = [0u8; 4];
+        self.read(Register::COMMAND as Address, &mut command)?;
+        Ok(Request {
+            command: command[0],
+            data: command[1],
+            point: (command[2] as u16) << 8 | command[3] as u16,
+        })
+    }
+
+    // Time until the request is done. Faults are found when it runs, so a request that will fail takes none
+    fn latency(&self, request: &Request) -> ClockElapsed {
+        let tps = &self.drive.tps[drive(request.command)];
+        match Command::from(request.command) {
+            Command::StoreSector | Command::LoadSector | Command::IsBootable if tps.is_open() && request.data < tps.sectors => {
+                self.timing.latency(tps.head as usize, request.data as usize, SECTOR_SIZE)
+            },
+            _ => 0,
+        }
+    }
+
+    // Runs the pending request, and interrupts to tell it's over
+    fn complete(&mut self, system: &System) -> Result<ClockElapsed, Error> {
+        if let Some(request) = self.pending.take() {
+            self.drive.current = drive(request.command);
+            let result = self.execute(system, &Command::from(request.command), (request.data, request.point));
+            self.expose(result)?;
+            system.get_interrupt_controller().set(true, self.vector)?;
+        }
+        Ok(SLEEP)
+    }
+
+    // Runs a command, giving back the value left in STATUSL
+    pub fn execute(&mut self, system: &System, command: &Command, (data, point) : (u8, u16)) -> Result<u8, Fault> {
+        let tps = &mut self.drive.tps[self.drive.current];
 
         match command {
-            Command::Nop => Ok(()),
+            Command::Nop => Ok(0),
             Command::StoreSector => {
-                system.get_bus().read(point as Address * 512, &mut self.incoming.data)?;
-
-                self.drive.tps[self.drive.current].store_sector(data, &mut self.incoming)
-                    .or_else(|e| {Err(Error::new(&format!("{}", e)))})?;
-                Ok(())
+                system.get_bus().read(point as Address * 512, &mut self.incoming.data)
+                    .map_err(|_| Fault::ADDRESS)?;
+                tps.store_sector(data, &self.incoming)?;
+                Ok(0)
             },
             Command::LoadSector => {
-                self.drive.tps[self.drive.current].load_sector(data, &mut self.outcoming)
-                    .or_else(|e| {Err(Error::new(&format!("{}", e)))})?;
-                system.get_bus().write(point as Address * 512, &self.outcoming.data)?;
-                system.get_interrupt_controller().set(true, 5, INTERRUPT_LOADED)?;
-                Ok(())
+                tps.load_sector(data, &mut self.outcoming)?;
+                system.get_bus().write(point as Address * 512, &self.outcoming.data)
+                    .map_err(|_| Fault::ADDRESS)?;
+                Ok(0)
             },
             Command::IsBootable => {
-                self.drive.tps[self.drive.current].load_sector(data, &mut self.outcoming)
-                    .or_else(|e| {Err(Error::new(&format!("{}", e)))})?;
-                
-                if self.outcoming.data[510..511] == [0xA1, 0xEA] {
-                    self.write_u8(Register::STATUSL as Address, 1)?;
-                } else {
-                    self.write_u8(Register::STATUSL as Address, 0)?;
-                }
-
-                Ok(())
+                tps.load_sector(data, &mut self.outcoming)?;
+                Ok(is_boot_sector(&self.outcoming.data) as u8)
             },
             Command::IsPresent => {
-                let exists = Path::new(&self.drive.tps[self.drive.current].filename).exists();
-
-                if exists {
-                    self.write_u8(Register::STATUSL as Address, 1)?;
-                } else {
-                    self.write_u8(Register::STATUSL as Address, 0)?;
-                }
-
-                Ok(())
+                Ok(tps.media.path().exists() as u8)
             },
             Command::Open => {
-                let filename = &self.drive.tps[self.drive.current].filename;
-                self.drive.tps[self.drive.current] = Tps::open(filename);
-                Ok(())
+                tps.open().map_err(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => Fault::MEDIA,
+                    _ => tps.io_fault(e),
+                })?;
+                Ok(0)
             },
             Command::Close => {
-                Ok(())
-            }
+                tps.close();
+                Ok(0)
+            },
+            // Leaves the sectors on the media in POINT, none if the drive is empty
+            Command::Identify => {
+                let sectors = if tps.is_open() { tps.sectors } else { 0 };
+                self.write_beu16(Register::POINTH as Address, sectors as u16).map_err(|_| Fault::IO)?;
+                Ok(0)
+            },
+            Command::Unknown => Err(Fault::COMMAND),
         }
     }
 
-    pub fn expose(&self) {
-        // TODO: Do something with status
+    // Leaves the outcome of the last command in the status registers
+    fn expose(&mut self, result: Result<u8, Fault>) -> Result<(), Error> {
+        let mut flags = Flag::DONE as u8;
+        let tps = &self.drive.tps[self.drive.current];
+        if tps.is_open() {
+            flags |= Flag::PRESENT as u8;
+            if tps.media.is_read_only() {
+                flags |= Flag::PROTECTED as u8;
+            }
+        }
+        let value = match result {
+            Ok(value) => value,
+            Err(fault) => {
+                flags |= Flag::ERROR as u8;
+                fault as u8
+            },
+        };
+        self.write_u8(Register::STATUSH as Address, flags)?;
+        self.write_u8(Register::STATUSL as Address, value)
     }
-
 }
 
 
@@ -213,24 +295,81 @@ impl Addressable for Controller {
         self.dev.borrow_mut().as_addressable().unwrap().read(addr, data)
     }
 
+    // Writing the command register wakes the controller up to run it, unless it is still busy with
+    // the last one, then the write is lost
     fn write(&mut self, addr: Address, data: &[u8]) -> Result<(), Error> {
-        self.dev.borrow_mut().as_addressable().unwrap().write(addr, data)
+        let mut dev = self.dev.borrow_mut();
+        let dev = dev.as_addressable().unwrap();
+        if addr == Register::COMMAND as Address && dev.read_u8(Register::STATUSH as Address)? & Flag::BUSY as u8 != 0 {
+            return Ok(());
+        }
+        dev.write(addr, data)?;
+        if addr == Register::COMMAND as Address {
+            dev.write_u8(Register::STATUSH as Address, Flag::BUSY as u8)?;
+            self.waker.wake();
+        }
+        Ok(())
     }
 }
 
 impl Steppable for Controller {
+    // Woken by a command, the controller takes it and sleeps until it is done. One that takes no time
+    // is done right away. The system also steps it once when it starts, with no command to run
     fn step(&mut self, system: &System) -> Result<ClockElapsed, Error> {
-        let mut command = [0u8; 4];
-        self.read(Register::COMMAND as Address, &mut command);
-        let (data, point) = (
-            command[1],
-            (command[2] as u16) << 8 | command[3] as u16,
-        );
+        if self.pending.is_some() {
+            return self.complete(system);
+        }
+        if self.read_u8(Register::STATUSH as Address)? & Flag::BUSY as u8 == 0 {
+            return Ok(SLEEP);
+        }
+
+        let request = self.request()?;
+        let latency = self.latency(&request);
+        self.pending = Some(request);
+        if latency > 0 {
+            return Ok(latency);
+        }
+        self.complete(system)
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        Some(self.waker.clone())
+    }
+}
 
-        self.execute(system, command[0], (data, point))?;
-        self.expose();
+// The media lives in its own files, only the controller registers, the heads and the command in
+// flight are saved
+impl Snapshotable for Controller {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        self.dev.borrow_mut().as_snapshotable().unwrap().save_state(snapshot)?;
+        snapshot.write_u8(self.drive.current as u8);
+        for tps in self.drive.tps.iter() {
+            snapshot.write_u8(tps.head);
+        }
+        snapshot.write_bool(self.pending.is_some());
+        if let Some(request) = self.pending {
+            snapshot.write_u8(request.command);
+            snapshot.write_u8(request.data);
+            snapshot.write_beu16(request.point);
+        }
+        Ok(())
+    }
 
-        Ok(1_000_000_000 / self.frequency)
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        self.dev.borrow_mut().as_snapshotable().unwrap().load_state(snapshot)?;
+        self.drive.current = snapshot.read_u8()? as usize;
+        for tps in self.drive.tps.iter_mut() {
+            tps.head = snapshot.read_u8()?;
+        }
+        self.pending = match snapshot.read_bool()? {
+            true => Some(Request {
+                command: snapshot.read_u8()?,
+                data: snapshot.read_u8()?,
+                point: snapshot.read_beu16()?,
+            }),
+            false => None,
+        };
+        Ok(())
     }
 }
 
@@ -242,4 +381,105 @@ impl Transmutable for Controller {
     fn as_steppable(&mut self) -> Option<&mut dyn Steppable> {
         Some(self)
     }
-}
\ No newline at end of file
+
+    fn as_snapshotable(&mut self) -> Option<&mut dyn Snapshotable> {
+        Some(self)
+    }
+}
+
+// The high bit of a command picks drive 1
+fn drive(command: u8) -> usize {
+    (command >> 7) as usize
+}
+
+pub fn is_boot_sector(sector: &[u8]) -> bool {
+    sector[SECTOR_SIZE - BOOT_SIGNATURE.len()..SECTOR_SIZE] == BOOT_SIGNATURE
+}
+
+// Host side handling of tps images, for the tps subcommand. An image is a plain file of SECTORS
+// sectors, shorter files read as if they were padded with zeros
+pub struct Image {
+    path: PathBuf,
+    file: File,
+}
+
+impl Image {
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(path)
+            .map_err(|e| Error::new(&format!("{}: unable to create the image: {}", path.display(), e)))?;
+        file.set_len((SECTORS * SECTOR_SIZE) as u64)
+            .map_err(|e| Error::new(&format!("{}: unable to create the image: {}", path.display(), e)))?;
+        Ok(Self { path: path.to_path_buf(), file })
+    }
+
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)
+            .map_err(|e| Error::new(&format!("{}: unable to open the image: {}", path.display(), e)))?;
+        Ok(Self { path: path.to_path_buf(), file })
+    }
+
+    fn check(&self, sector: usize, count: usize) -> Result<(), Error> {
+        if sector + count > SECTORS {
+            return Err(Error::new(&format!("{}: sectors {}..{} are past the end of the image, it has {}", self.path.display(), sector, sector + count, SECTORS)));
+        }
+        Ok(())
+    }
+
+    fn io_error(&self, e: std::io::Error) -> Error {
+        Error::new(&format!("{}: {}", self.path.display(), e))
+    }
+
+    pub fn read_sectors(&mut self, sector: usize, count: usize) -> Result<Vec<u8>, Error> {
+        self.check(sector, count)?;
+        let mut data = vec![0; count * SECTOR_SIZE];
+        self.file.seek(SeekFrom::Start((sector * SECTOR_SIZE) as u64)).map_err(|e| self.io_error(e))?;
+        let mut read = 0;
+        while read < data.len() {
+            match self.file.read(&mut data[read..]).map_err(|e| self.io_error(e))? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(data)
+    }
+
+    // The data is padded with zeros to whole sectors, returns how many it took
+    pub fn write_sectors(&mut self, sector: usize, data: &[u8]) -> Result<usize, Error> {
+        let count = (data.len() + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        self.check(sector, count)?;
+        let mut padded = data.to_vec();
+        padded.resize(count * SECTOR_SIZE, 0);
+        self.file.seek(SeekFrom::Start((sector * SECTOR_SIZE) as u64)).map_err(|e| self.io_error(e))?;
+        self.file.write_all(&padded).map_err(|e| self.io_error(e))?;
+        Ok(count)
+    }
+
+    // Puts the boot code in sector 0 and signs it
+    pub fn write_boot(&mut self, code: &[u8]) -> Result<(), Error> {
+        let room = SECTOR_SIZE - BOOT_SIGNATURE.len();
+        if code.len() > room {
+            return Err(Error::new(&format!("the boot code takes {} bytes, only {} fit in the boot sector", code.len(), room)));
+        }
+        let mut sector = code.to_vec();
+        sector.resize(room, 0);
+        sector.extend_from_slice(&BOOT_SIGNATURE);
+        self.write_sectors(0, &sector)?;
+        Ok(())
+    }
+
+    pub fn info(&mut self) -> Result<String, Error> {
+        let size = self.file.metadata().map_err(|e| self.io_error(e))?.len();
+        let data = self.read_sectors(0, SECTORS)?;
+        let used = data.chunks(SECTOR_SIZE).filter(|sector| sector.iter().any(|byte| *byte != 0)).count();
+        let boot = if is_boot_sector(&data[..SECTOR_SIZE]) { "present" } else { "absent" };
+
+        let mut info = format!("{}\n", self.path.display());
+        info += &format!("  size:           {} bytes, {} sectors of {}\n", size, SECTORS, SECTOR_SIZE);
+        if size != (SECTORS * SECTOR_SIZE) as u64 {
+            info += &format!("  warning:        the image should be {} bytes\n", SECTORS * SECTOR_SIZE);
+        }
+        info += &format!("  sectors in use: {}\n", used);
+        info += &format!("  boot signature: {}\n", boot);
+        Ok(info)
+    }
+}
@@ -1,6 +1,7 @@
-use organum::core::{Steppable, Transmutable, ClockElapsed, Clock, Addressable, Address, wrap_transmutable};
+use organum::core::{Steppable, Transmutable, ClockElapsed, Clock, Addressable, Address, Snapshotable, wrap_transmutable};
 use organum::error::Error;
 use organum::premade::{bus::Block, memory::MemoryBlock};
+use organum::snapshot::{SnapshotReader, SnapshotWriter};
 use organum::sys::System;
 
 pub const INTERRUPT_TIMEOUT: u8 = 0x0f;
@@ -14,6 +15,8 @@ pub struct Timer {
     frequency: u64,
     timeout_enable: bool,
     interval_enable: bool,
+    timeout_vector: u8,
+    interval_vector: u8,
 }
 
 pub enum Register {
@@ -24,13 +27,15 @@ pub enum Register {
 pub const REGISTER_COUNT: usize = 4;
 
 impl Timer {
-    pub fn new(base: Address, frequency: u64) -> Self {
+    pub fn new(base: Address, frequency: u64, timeout_vector: u8, interval_vector: u8) -> Self {
         let dev = MemoryBlock::new(vec![0u8;  REGISTER_COUNT]);
         Self {
             now: 0,
             timeout_enable: false,
             interval_enable: false,
             frequency,
+            timeout_vector,
+            interval_vector,
             mem: Block {
                 base,
                 length: dev.len(),
@@ -62,6 +67,28 @@ impl Transmutable for Timer {
     fn as_addressable(&mut self) -> Option<&mut dyn Addressable> {
         Some(self)
     }
+
+    fn as_snapshotable(&mut self) -> Option<&mut dyn Snapshotable> {
+        Some(self)
+    }
+}
+
+impl Snapshotable for Timer {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        self.mem.dev.borrow_mut().as_snapshotable().unwrap().save_state(snapshot)?;
+        snapshot.write_beu64(self.now);
+        snapshot.write_bool(self.timeout_enable);
+        snapshot.write_bool(self.interval_enable);
+        Ok(())
+    }
+
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        self.mem.dev.borrow_mut().as_snapshotable().unwrap().load_state(snapshot)?;
+        self.now = snapshot.read_beu64()?;
+        self.timeout_enable = snapshot.read_bool()?;
+        self.interval_enable = snapshot.read_bool()?;
+        Ok(())
+    }
 }
 
 impl Steppable for Timer {
@@ -69,12 +96,12 @@ impl Steppable for Timer {
         self.now = self.now.wrapping_add(1);
 
         if self.timeout_enable && self.check_timeout()? {
-            system.get_interrupt_controller().set(true,  6, INTERRUPT_TIMEOUT)?;
+            system.get_interrupt_controller().set(true, self.timeout_vector)?;
             self.timeout_enable = false;
         }
 
         if self.interval_enable && self.check_interval()? {
-            system.get_interrupt_controller().set(true,  6, INTERRUPT_INTERVAL)?;
+            system.get_interrupt_controller().set(true, self.interval_vector)?;
         }
 
         Ok(1_000_000_000 / self.frequency)
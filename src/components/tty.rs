@@ -1,76 +1,120 @@
-use std::io::Write;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
 
-use organum::core::{Steppable, Transmutable, ClockElapsed, Addressable, Address};
+use organum::backend::{FilePair, Loopback, Pty, SerialBackend, Stdio};
+use organum::core::{Steppable, Transmutable, ClockElapsed, Addressable, Address, Snapshotable};
 use organum::error::Error;
-use organum::server::Server;
+use organum::interrupts::InterruptController;
+use organum::server::{Disconnected, Server};
+use organum::snapshot::{SnapshotReader, SnapshotWriter};
 use organum::sys::System;
-use organum::premade::serial::{Serial, Flag};
+use organum::premade::serial::{Ctrl, Serial};
+
+pub const INTERRUPT_RECEIVED: u8 = 0x0a;
+pub const INTERRUPT_TX_EMPTY: u8 = 0x11;
+// The lines before it were taken when the tty had a single interrupt
+pub const TX_EMPTY_LINE: usize = 7;
+
+// Where the tty's line goes, as given to --serial
+#[derive(Clone, Debug)]
+pub enum SerialConfig {
+    Tcp(SocketAddr, Disconnected),
+    Stdio,
+    Pty,
+    File(PathBuf, PathBuf),
+    Loopback(Loopback),
+}
+
+impl SerialConfig {
+    // tcp[:ADDR], stdio, pty, file:IN,OUT or loopback. The tcp server listens on `addr` unless given another
+    pub fn parse(arg: &str, addr: SocketAddr, disconnected: Disconnected) -> Result<Self, Error> {
+        let (kind, rest) = match arg.split_once(':') {
+            Some((kind, rest)) => (kind, Some(rest)),
+            None => (arg, None),
+        };
+        match (kind, rest) {
+            ("tcp", None) => Ok(SerialConfig::Tcp(addr, disconnected)),
+            ("tcp", Some(addr)) => {
+                let addr = addr.parse().map_err(|e| Error::new(&format!("serial: invalid address {}: {}", addr, e)))?;
+                Ok(SerialConfig::Tcp(addr, disconnected))
+            },
+            ("stdio", None) => Ok(SerialConfig::Stdio),
+            ("pty", None) => Ok(SerialConfig::Pty),
+            ("file", Some(paths)) => match paths.split_once(',') {
+                Some((input, output)) if !input.is_empty() && !output.is_empty() => Ok(SerialConfig::File(input.into(), output.into())),
+                _ => Err(Error::new("serial: files are given as file:IN,OUT")),
+            },
+            ("loopback", None) => Ok(SerialConfig::Loopback(Loopback::new())),
+            _ => Err(Error::new(&format!("serial: unknown backend {}, expected tcp[:ADDR], stdio, pty, file:IN,OUT or loopback", arg))),
+        }
+    }
 
-pub const INTERRUPT_TRANSMIT: u8 = 0x0a;
+    pub fn open(&self) -> Result<Box<dyn SerialBackend>, Error> {
+        Ok(match self {
+            SerialConfig::Tcp(addr, disconnected) => {
+                let server = Server::new(addr.ip(), addr.port(), *disconnected)
+                    .map_err(|e| Error::new(&format!("tty: unable to listen on {}: {}", addr, e)))?;
+                Box::new(server)
+            },
+            SerialConfig::Stdio => Box::new(Stdio::new()?),
+            SerialConfig::Pty => Box::new(Pty::new()?),
+            SerialConfig::File(input, output) => Box::new(FilePair::new(input, output)),
+            // Shares its buffers with the config, so tests can reach them
+            SerialConfig::Loopback(loopback) => Box::new(loopback.clone()),
+        })
+    }
+}
 
 pub struct Tty {
-    pub server: Server,
-    pub trigger: char,
+    pub backend: Box<dyn SerialBackend>,
     pub serial: Serial,
-    pub received: bool,
-    pub stop: bool,
+    // The received line is level triggered, it has to drop as soon as the guest empties the fifo
+    interrupts: Rc<RefCell<InterruptController>>,
+    rx_vector: u8,
+    tx_vector: u8,
 }
 
 impl Tty {
-    pub fn new(ip: std::net::IpAddr, port: u16, serial: Serial) -> Self {
-        Self {
-            server: Server::new(ip, port).unwrap(),
-            trigger: '\n',
-            serial: serial,
-            received: false,
-            stop: false
-        }
+    // The machine doesn't wait for the other end of the line, with a tcp backend whatever the guest
+    // sends while no client is connected is buffered or dropped as the config says
+    pub fn new(config: &SerialConfig, serial: Serial, interrupts: Rc<RefCell<InterruptController>>, rx_vector: u8, tx_vector: u8) -> Result<Self, Error> {
+        Ok(Self {
+            backend: config.open()?,
+            serial,
+            interrupts,
+            rx_vector,
+            tx_vector,
+        })
     }
 
-    pub fn get_chars(&mut self, system: &System) -> Result<(), Error> {
-
-        match self.server.receiver.try_recv() {
-            Ok(msg) => {
-                let ch = msg[0];
-
-                if ch as char == self.trigger {
-                    system.get_interrupt_controller().set(true, 4, INTERRUPT_TRANSMIT)?;
-                    return Ok(());
-                }
-
-                self.serial.tx_buffer.push(ch);
-                Ok(())
-            }
-
-            Err(std::sync::mpsc::TryRecvError::Empty) => Ok(()),
-            Err(std::sync::mpsc::TryRecvError::Disconnected) => {
-                println!("Client disconnected");
-                Err(Error::new(&format!(
-                    "Client disconnected: {}",
-                    std::sync::mpsc::TryRecvError::Disconnected
-                )))
-            }
+    // The line carries a byte per byte time, whatever the backend has waiting
+    fn receive(&mut self) -> Result<(), Error> {
+        if let Some(byte) = self.backend.read() {
+            self.serial.receive(byte);
+            self.update_rx_line()?;
         }
+        Ok(())
     }
 
+    // Held up while there are bytes to read and the guest asked for the interrupt
+    fn update_rx_line(&mut self) -> Result<(), Error> {
+        let raised = !self.serial.rx_fifo.is_empty() && self.serial.interrupt_enabled(Ctrl::RXINT);
+        self.interrupts.borrow_mut().set(raised, self.rx_vector)
+    }
 
-    fn receive(&mut self) -> Result<(), Error>{
-        
-
-        if self.serial.rx()? {
-            match self.server.to_client.write(&self.serial.rx_buffer) {
-                Ok(_) => self.serial.rx_buffer.clear(),
-                Err(e) => {
-                    self.stop = true;
-                    return Err(Error::breakpoint(&format!("Error sending data to client: {}", e)))
-                }
+    fn transmit(&mut self, system: &System) -> Result<(), Error> {
+        if let Some(byte) = self.serial.shift() {
+            self.backend.write(&[byte])?;
+            if !self.serial.is_busy() && self.serial.interrupt_enabled(Ctrl::TXINT) {
+                system.get_interrupt_controller().set(true, self.tx_vector)?;
             }
         }
-
+        // A client that just connected gets what was buffered for it
+        self.backend.flush();
         Ok(())
     }
-
-            
 }
 
 impl Transmutable for Tty {
@@ -81,16 +125,29 @@ impl Transmutable for Tty {
     fn as_addressable(&mut self) -> Option<&mut dyn Addressable> {
         Some(self)
     }
+
+    fn as_snapshotable(&mut self) -> Option<&mut dyn Snapshotable> {
+        Some(self)
+    }
+}
+
+// The connection to the client is not part of the machine, only the serial line is saved
+impl Snapshotable for Tty {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        self.serial.save_state(snapshot)
+    }
+
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        self.serial.load_state(snapshot)
+    }
 }
 
 impl Steppable for Tty {
     fn step(&mut self, system: &System) -> Result<ClockElapsed, Error> {
-        if !self.stop {
-            self.get_chars(system)?;
-            self.receive()?;
-        }
+        self.receive()?;
+        self.transmit(system)?;
 
-        Ok(1_000_000_000 / self.serial.frequency)
+        Ok(self.serial.byte_time())
     }
 }
 
@@ -101,11 +158,12 @@ impl Addressable for Tty {
 
     fn read(&mut self, addr: Address, data: &mut [u8]) -> Result<(), Error> {
         self.serial.read(addr, data)?;
-        Ok(())
+        self.update_rx_line()
     }
 
+    // Enabling the interrupt with bytes already waiting raises it
     fn write(&mut self, addr:  Address, data: &[u8]) -> Result<(), Error> {
         self.serial.write(addr, data)?;
-        Ok(())
+        self.update_rx_line()
     }
 }
@@ -8,12 +8,12 @@ use font::Font;
 use organum::core::*;
 use organum::error::Error;
 use organum::premade::{memory};
+use organum::snapshot::{SnapshotReader, SnapshotWriter};
 use organum::sys::System;
 
 use winit::event::{WindowEvent, Event};
 use winit::window::Window;
 use codepage_437::CP437_WINGDINGS as cp437;
-use pixels::Pixels;
 
 
 
@@ -104,22 +104,25 @@ pub struct Video {
     //pub gpu: gpu::gpu,
     pub kbd: kbd::Keyboard,
     pub screen: screen::Screen,
+    vectors: (u8, u8),
 }
 
 impl Video {
-    pub fn new(system: &mut System, base: Address, w: usize, h: usize, pixels: Pixels, fonts: Vec<Font>) -> Result<Self, Error> {
+    // The registers are mapped by whoever builds the machine. The vectors are those of the character and
+    // key code keyboard interrupts
+    pub fn new(w: usize, h: usize, framebuffer: screen::Framebuffer, fonts: Vec<Font>, vectors: (u8, u8)) -> Result<Self, Error> {
         let dev = wrap_transmutable(memory::MemoryBlock::new(vec![0u8; REGISTER_COUNT]));
-        system.add_addressable_device_data(base, dev)?;
 
 
         let kbd = kbd::Keyboard::new();
-        let screen = screen::Screen::new(w, h, pixels, fonts);
+        let screen = screen::Screen::new(w, h, framebuffer, fonts);
         //let gpu = gpu::gpu::new();
 
         Ok(Self {
-            mem: system.get_data().get_device_at(base, REGISTER_COUNT).unwrap().0,
+            mem: dev,
             kbd,
             screen,
+            vectors,
             //gpu,
         })
     }
@@ -130,8 +133,19 @@ impl Video {
         Ok(())
     }
 
+    // Without a window there are no input events, so only the screen commands are run
+    pub fn update_headless(&mut self, system: &System) -> Result<(), Error> {
+        self.execute(system, None)?;
+        self.expose(system)?;
+        Ok(())
+    }
+
     fn process(&mut self, event: &Event<()>, system: &System, window: &Window) -> Result<(), Error> {
         self.poll(event);
+        self.execute(system, Some(window))
+    }
+
+    fn execute(&mut self, system: &System, window: Option<&Window>) -> Result<(), Error> {
         let mut command = [0u8; 4];
         self.read(Register::COMMAND as Address, &mut command)?;
         self.screen.execute(system, window, screen::Command::from(command[0]), (command[1], command[2], command[3]))?;
@@ -148,10 +162,10 @@ impl Video {
 
         self.write(Register::CHARACTER as Address, &data)?;
         if self.kbd.mode & KBD_MODE_CHAR != 0 {
-            system.get_interrupt_controller().set(true, 4, INTERRUPT_KBD_CHAR)?;
+            system.get_interrupt_controller().set(true, self.vectors.0)?;
         }
         if self.kbd.mode & KBD_MODE_KCODE != 0 {
-            system.get_interrupt_controller().set(true, 4, INTERRUPT_KBD_KCODE)?;
+            system.get_interrupt_controller().set(true, self.vectors.1)?;
         }
 
         // Clear Input Registers
@@ -230,11 +244,34 @@ impl Addressable for Video {
     }
 }
 
+// The registers are a device of the system and are saved with it
+impl Snapshotable for Video {
+    fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), Error> {
+        snapshot.write_u8(self.kbd.character);
+        snapshot.write_u8(self.kbd.code);
+        snapshot.write_u8(self.kbd.modifiers);
+        snapshot.write_u8(self.kbd.mode);
+        self.screen.save_state(snapshot)
+    }
+
+    fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), Error> {
+        self.kbd.character = snapshot.read_u8()?;
+        self.kbd.code = snapshot.read_u8()?;
+        self.kbd.modifiers = snapshot.read_u8()?;
+        self.kbd.mode = snapshot.read_u8()?;
+        self.screen.load_state(snapshot)
+    }
+}
+
 // This is not steppable per se, as it runs in the main loop alone
 impl Transmutable for Video {
     fn as_addressable(&mut self) -> Option<&mut dyn Addressable> {
         Some(self)
     }
+
+    fn as_snapshotable(&mut self) -> Option<&mut dyn Snapshotable> {
+        Some(self)
+    }
 }
 
 #[inline(always)]
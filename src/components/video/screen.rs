@@ -1,7 +1,9 @@
 // The screen module provides functions to render the different video modes for Sirius
-use pixels::{Pixels, Error, wgpu::Color};
-use image::{Rgba, Pixel};
+use pixels::{Pixels, Error, TextureError, wgpu::Color};
+use image::{Rgba, RgbaImage, Pixel};
+use std::path::Path;
 use winit::{dpi::LogicalSize, window::Window};
+use organum::snapshot::{SnapshotReader, SnapshotWriter};
 use organum::sys::System;
 
 use crate::components::video::*;
@@ -35,16 +37,85 @@ impl From<u8> for Command {
 }
 
 
+/// The surface the screen renders into: a `pixels` surface tied to a window, or a
+/// plain RGBA buffer in memory when the machine runs headless
+#[derive(Debug)]
+pub enum Framebuffer {
+    Window(Pixels),
+    Memory {
+        width: u32,
+        height: u32,
+        frame: Vec<u8>,
+    },
+}
+
+impl Framebuffer {
+    pub fn headless(width: u32, height: u32) -> Self {
+        Framebuffer::Memory {
+            width,
+            height,
+            frame: vec![0u8; (width * height * 4) as usize],
+        }
+    }
+
+    pub fn get_frame(&self) -> &[u8] {
+        match self {
+            Framebuffer::Window(pixels) => pixels.get_frame(),
+            Framebuffer::Memory { frame, .. } => frame,
+        }
+    }
+
+    pub fn get_frame_mut(&mut self) -> &mut [u8] {
+        match self {
+            Framebuffer::Window(pixels) => pixels.get_frame_mut(),
+            Framebuffer::Memory { frame, .. } => frame,
+        }
+    }
+
+    pub fn render(&self) -> Result<(), Error> {
+        match self {
+            Framebuffer::Window(pixels) => pixels.render(),
+            Framebuffer::Memory { .. } => Ok(()),
+        }
+    }
+
+    pub fn resize_buffer(&mut self, w: u32, h: u32) -> Result<(), TextureError> {
+        match self {
+            Framebuffer::Window(pixels) => pixels.resize_buffer(w, h),
+            Framebuffer::Memory { width, height, frame } => {
+                *width = w;
+                *height = h;
+                frame.resize((w * h * 4) as usize, 0);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn resize_surface(&mut self, w: u32, h: u32) -> Result<(), TextureError> {
+        match self {
+            Framebuffer::Window(pixels) => pixels.resize_surface(w, h),
+            Framebuffer::Memory { .. } => Ok(()),
+        }
+    }
+
+    pub fn set_clear_color(&mut self, color: Color) {
+        if let Framebuffer::Window(pixels) = self {
+            pixels.set_clear_color(color);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Screen {
     mode: Mode,
     pub width: usize,
     pub height: usize,
-    pub framebuffer: Pixels,
+    pub framebuffer: Framebuffer,
 
     pub mtextbuf: [u8; MCHARS.0 * MCHARS.1],
     pub rtextbuf: [u8; RCHARS.0 * RCHARS.1 * 2],
-    pub graphics: [u8; PIXELS],
+    // Boxed, it's too large to be moved around on the stack
+    pub graphics: Box<[u8; PIXELS]>,
 
     pub fonts: Vec<Font>,
     monochrome_font: usize,
@@ -59,16 +130,16 @@ pub struct Screen {
 
 impl Screen {
    
-    pub fn new(width: usize, height: usize, pixels: Pixels, fonts: Vec<Font>) -> Self {
+    pub fn new(width: usize, height: usize, framebuffer: Framebuffer, fonts: Vec<Font>) -> Self {
         Self {
             mode: Mode::MText,
             width,
             height,
-            framebuffer: pixels,
+            framebuffer,
 
             mtextbuf: [0u8; MCHARS.0 * MCHARS.1],
             rtextbuf: [0u8; RCHARS.0 * RCHARS.1 * 2],
-            graphics: [0u8; PIXELS],
+            graphics: vec![0u8; PIXELS].into_boxed_slice().try_into().unwrap(),
 
             fonts,
             monochrome_font: 0,
@@ -80,7 +151,7 @@ impl Screen {
         }
     }
 
-    pub fn execute(&mut self, system: &System, window: &Window, command: Command, (dh, dm, dl) : (u8, u8, u8)) -> Result<(), organum::error::Error> {
+    pub fn execute(&mut self, system: &System, window: Option<&Window>, command: Command, (dh, dm, dl) : (u8, u8, u8)) -> Result<(), organum::error::Error> {
         match command {
             Command::Nop => Ok(()),
             Command::Clear => {
@@ -89,7 +160,10 @@ impl Screen {
             },
             Command::SetMode => {
                 println!("Mode set");
-                set_mode_and_resize(self, Mode::from(dh), &window).or_else(|e| {Err(organum::error::Error::new(&format!("{}", e)))})
+                match window {
+                    Some(window) => set_mode_and_resize(self, Mode::from(dh), window),
+                    None => self.set_mode(Mode::from(dh), Color::TRANSPARENT).map(|_| ()),
+                }.or_else(|e| {Err(organum::error::Error::new(&format!("{}", e)))})
             },
             //Command::SetChar => self.set_char(), //TODO: Maybe it is not necessary fi we provide a pointer to the buffer
             Command::SetFont => {
@@ -294,14 +368,47 @@ impl Screen {
                 self.rtextbuf.copy_from_slice(&mut data);
             },
             Mode::Graphic => {
-                let mut data = [0u8; PIXELS];
-                system.get_bus().read(addr, &mut data)?;
-                self.graphics.copy_from_slice(&mut data);
+                system.get_bus().read(addr, &mut self.graphics[..])?;
             }
         };
 
         Ok(())
     }
+
+    pub fn save_state(&mut self, snapshot: &mut SnapshotWriter) -> Result<(), organum::error::Error> {
+        snapshot.write_u8(self.mode.clone() as u8);
+        snapshot.write_bytes(&self.mtextbuf);
+        snapshot.write_bytes(&self.rtextbuf);
+        snapshot.write_bytes(&self.graphics[..]);
+        snapshot.write_beu32(self.monochrome_font as u32);
+        snapshot.write_beu32(self.color_font as u32);
+        snapshot.write_bool(self.blinking);
+        snapshot.write_bytes(&self.palette);
+        snapshot.write_u8(self.mtextcolor);
+        Ok(())
+    }
+
+    pub fn load_state(&mut self, snapshot: &mut SnapshotReader) -> Result<(), organum::error::Error> {
+        let mode = Mode::from(snapshot.read_u8()?);
+        snapshot.read_into(&mut self.mtextbuf)?;
+        snapshot.read_into(&mut self.rtextbuf)?;
+        snapshot.read_into(&mut self.graphics[..])?;
+        self.monochrome_font = snapshot.read_beu32()? as usize;
+        self.color_font = snapshot.read_beu32()? as usize;
+        self.blinking = snapshot.read_bool()?;
+        snapshot.read_into(&mut self.palette)?;
+        self.mtextcolor = snapshot.read_u8()?;
+
+        // The fonts must be restored first, they set the size of the text modes
+        self.set_mode(mode, Color::TRANSPARENT).or_else(|e| {Err(organum::error::Error::new(&format!("{}", e)))})?;
+        Ok(())
+    }
+
+    pub fn save_frame(&self, path: &Path) -> Result<(), organum::error::Error> {
+        let frame = RgbaImage::from_raw(self.width as u32, self.height as u32, self.framebuffer.get_frame().to_vec())
+            .ok_or_else(|| organum::error::Error::new("Framebuffer does not match the screen size"))?;
+        frame.save(path).or_else(|e| {Err(organum::error::Error::new(&format!("{}", e)))})
+    }
 }
 
 #[inline(always)]
@@ -2,15 +2,26 @@ mod components;
 
 use std::time;
 use std::fs;
-use std::path::PathBuf;
-use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::net::{Ipv4Addr, SocketAddr};
 
 use log::error;
 use winit::{event::Event, event_loop::ControlFlow};
 use clap::{arg, command, value_parser, ArgAction, Command};
 
+use organum::core::{wrap_transmutable, Address, Addressable};
 use organum::error::Error;
-use components::{build_talea, TPS_PATH};
+use organum::premade::{bus::Bus, memory::MemoryBlock};
+use organum::server::Disconnected;
+use organum::symbols::SymbolTable;
+use components::cpu::disassemble::disassemble_range;
+use components::cpu::trace::{format_json, format_text, TraceFilter, TraceMode, TraceReader, Tracer};
+use components::{Uptr, Word};
+use components::machine::{BusKind, DeviceKind, Machine};
+use components::storage::filesystem::{self, FileSystem};
+use components::storage::{tps, MediaConfig, SnapshotMode};
+use components::tty::SerialConfig;
+use components::{attach_gdb, build_talea, build_talea_headless, load_snapshot, save_snapshot, HeadlessTalea};
 use locate_cargo_manifest::locate_manifest;
 
 fn main() -> Result<(), Error> {
@@ -25,39 +36,320 @@ fn main() -> Result<(), Error> {
 
         .arg(
             arg!(
-                -s --server <ADDR> "Sets a serial terminal and waits to connect to the server specified"
+                -s --server <ADDR> "Sets the address the tcp serial backend listens on, clients may connect at any time"
             )
             .required(false)
             .value_parser(value_parser!(String))
         )
+        .arg(arg!(
+            --serial <BACKEND> "Connects the tty to tcp[:ADDR] (the default), stdio, pty, file:IN,OUT or loopback"
+        )
+        .required(false)
+        )
+        .arg(arg!(
+            --"serial-disconnected" <MODE> "What happens to serial output while no client is connected, buffered by default"
+        )
+        .required(false)
+        .value_parser(["buffer", "drop"])
+        )
         .arg(arg!(
             -d --debug ... "Turn the external debugger on"
         )
         .action(ArgAction::SetTrue)
         .required(false)
         )
-        .arg(arg!([bin] "Binary image to bootstrap the system (a BIOS of sorts) If it is not specified, will read from stdin")
+        .arg(arg!(
+            --strict "Aborts on illegal instructions and privilege violations instead of raising them in the guest"
+        )
+        .action(ArgAction::SetTrue)
+        .required(false)
+        )
+        .arg(arg!(
+            --"snapshot-disks" [MODE] "Keeps what the guest writes to disk and tps images in memory, and discards it when the run ends, or commits it to the images with --snapshot-disks=commit"
+        )
+        .required(false)
+        .value_parser(["discard", "commit"])
+        .require_equals(true)
+        .default_missing_value("discard")
+        )
+        .arg(arg!(
+            --"read-only" <IMAGE> "Write protects a disk or tps image, given by its path or its file name as in disk_1, may be repeated"
+        )
+        .required(false)
+        .action(ArgAction::Append)
+        .value_parser(value_parser!(PathBuf))
+        )
+        .arg(arg!(
+            --headless "Runs the system without a window, the screen is rendered to memory"
+        )
+        .action(ArgAction::SetTrue)
+        .required(false)
+        )
+        .arg(arg!(
+            -c --cycles <CYCLES> "Number of cpu cycles to run in headless mode, runs until the cpu stops if not specified"
+        )
+        .required(false)
+        .value_parser(value_parser!(u64))
+        )
+        .arg(arg!(
+            --screenshot <FILE> "Saves the screen to an image file when the headless run finishes"
+        )
+        .required(false)
+        .value_parser(value_parser!(PathBuf))
+        )
+        .arg(arg!(
+            --"load-state" <FILE> "Restores the machine from a snapshot before running"
+        )
+        .required(false)
+        .value_parser(value_parser!(PathBuf))
+        )
+        .arg(arg!(
+            --"save-state" <FILE> "Saves a snapshot of the machine when the run finishes"
+        )
+        .required(false)
+        .value_parser(value_parser!(PathBuf))
+        )
+        .arg(arg!(
+            --gdb <PORT> "Waits for gdb to connect on this localhost port and hands it the debugger"
+        )
+        .required(false)
+        .value_parser(value_parser!(u16))
+        )
+        .arg(arg!(
+            --symbols <FILE> "Loads a symbol file from the assembler so the debugger can use labels"
+        )
+        .required(false)
+        .global(true)
+        .value_parser(value_parser!(PathBuf))
+        )
+        .arg(arg!(
+            --machine <MACHINE> "Machine to build the system from: talea, codex or the path to a description file"
+        )
+        .required(false)
+        .global(true)
+        )
+        .arg(arg!(
+            --trace <FILE> "Records every retired instruction to a binary trace file, see the trace subcommand"
+        )
+        .required(false)
+        .value_parser(value_parser!(PathBuf))
+        )
+        .arg(arg!(
+            --"trace-range" <RANGE> "Only traces instructions whose linear pc is in START:END"
+        )
+        .required(false)
+        )
+        .arg(arg!(
+            --"trace-mode" <MODE> "Only traces instructions run in this privilege mode"
+        )
+        .required(false)
+        .value_parser(["supervisor", "user"])
+        )
+        .arg(arg!(
+            --"trace-start" <ADDR> "Starts tracing when the pc reaches this address"
+        )
+        .required(false)
+        )
+        .arg(arg!(
+            --"trace-stop" <ADDR> "Stops tracing after the instruction at this address"
+        )
+        .required(false)
+        )
+        .arg(arg!([bin] "Binary image to bootstrap the system (a BIOS of sorts), `stdin` reads it from stdin. If it is not specified, the built in monitor is used")
         .required(false)
         .value_parser(value_parser!(PathBuf))
         )  
+        .subcommand(
+            Command::new("disasm")
+                .about("disassembles a binary image")
+                .arg(arg!(<file> "binary image to disassemble").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(-b --base <ADDR> "address the image is loaded at, in hex").required(false))
+        )
+        .subcommand(
+            Command::new("trace")
+                .about("converts a trace recorded with --trace to text or json lines")
+                .arg(arg!(<file> "trace file to convert").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(--json "prints one json object per instruction").action(ArgAction::SetTrue))
+        )
+        .subcommand(
+            Command::new("memory-map")
+                .about("prints the memory map of the machine")
+        )
+        .subcommand(
+            Command::new("fs")
+                .about("manages Taleä filesystems in disk and Tps images, given by path or by the number of the machine's disk")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("mkfs")
+                        .about("makes an empty filesystem, sector 0 is left for boot code")
+                        .arg(arg!(<image> "disk number or image"))
+                )
+                .subcommand(
+                    Command::new("ls")
+                        .about("lists the files")
+                        .arg(arg!(<image> "disk number or image"))
+                )
+                .subcommand(
+                    Command::new("cp-in")
+                        .about("copies a host file in, replacing the one with its name")
+                        .arg(arg!(<image> "disk number or image"))
+                        .arg(arg!(<file> "host file").value_parser(value_parser!(PathBuf)))
+                        .arg(arg!([name] "name in the filesystem, the name of the host file by default"))
+                )
+                .subcommand(
+                    Command::new("cp-out")
+                        .about("copies a file out to the host")
+                        .arg(arg!(<image> "disk number or image"))
+                        .arg(arg!(<name> "name in the filesystem"))
+                        .arg(arg!(<file> "host file").value_parser(value_parser!(PathBuf)))
+                )
+                .subcommand(
+                    Command::new("rm")
+                        .about("removes a file")
+                        .arg(arg!(<image> "disk number or image"))
+                        .arg(arg!(<name> "name in the filesystem"))
+                )
+        )
         .subcommand(
             Command::new("tps")
-                .about("inserts a Tps device into the system")
+                .about("inserts a Tps device into the system, or manages Tps images")
                 .arg(arg!(-l --list "lists the available Tps slots").action(ArgAction::SetTrue))
                 .arg(arg!([path] "inserts the Tps device at the specified slot"))
                 .arg(arg!([slot] "inserts the Tps device at the specified slot"))
+                .subcommand(
+                    Command::new("new")
+                        .about("creates a blank Tps image")
+                        .arg(arg!(<image> "image to create").value_parser(value_parser!(PathBuf)))
+                )
+                .subcommand(
+                    Command::new("info")
+                        .about("shows the size, the sectors in use and the boot signature of a Tps image")
+                        .arg(arg!(<image> "image to inspect").value_parser(value_parser!(PathBuf)))
+                )
+                .subcommand(
+                    Command::new("write-boot")
+                        .about("writes a bootloader to sector 0 of a Tps image and signs it as bootable")
+                        .arg(arg!(<image> "image to write to").value_parser(value_parser!(PathBuf)))
+                        .arg(arg!(<bin> "bootloader, at most 510 bytes").value_parser(value_parser!(PathBuf)))
+                )
+                .subcommand(
+                    Command::new("put")
+                        .about("writes a file to a Tps image, starting at a sector")
+                        .arg(arg!(<image> "image to write to").value_parser(value_parser!(PathBuf)))
+                        .arg(arg!(<sector> "first sector to write").value_parser(value_parser!(u8)))
+                        .arg(arg!(<file> "file to write").value_parser(value_parser!(PathBuf)))
+                )
+                .subcommand(
+                    Command::new("get")
+                        .about("reads sectors from a Tps image into a file")
+                        .arg(arg!(<image> "image to read from").value_parser(value_parser!(PathBuf)))
+                        .arg(arg!(<sector> "first sector to read").value_parser(value_parser!(u8)))
+                        .arg(arg!(<file> "file to write the sectors to").value_parser(value_parser!(PathBuf)))
+                        .arg(arg!(-n --count <COUNT> "number of sectors to read").value_parser(value_parser!(u8)).default_value("1"))
+                )
         )
     .get_matches();
     
-    let default = PathBuf::from("stdin");
-    let bin = matches.get_one::<PathBuf>("bin").unwrap_or(&default);
+    let bin = matches.get_one::<PathBuf>("bin").map(PathBuf::as_path);
     let ip = matches.get_one::<String>("server");
+    let disconnected = match matches.get_one::<String>("serial-disconnected").map(String::as_str) {
+        Some("drop") => Disconnected::Drop,
+        _ => Disconnected::Buffer,
+    };
+    let snapshot = match matches.get_one::<String>("snapshot-disks").map(String::as_str) {
+        Some("commit") => Some(SnapshotMode::Commit),
+        Some(_) => Some(SnapshotMode::Discard),
+        None => None,
+    };
+    let read_only = matches.get_many::<PathBuf>("read-only").map(|images| images.cloned().collect()).unwrap_or_default();
     let debug = matches.get_one::<bool>("debug");
+    let strict = matches.get_one::<bool>("strict");
+    let headless = matches.get_one::<bool>("headless");
+    let cycles = matches.get_one::<u64>("cycles");
+    let screenshot = matches.get_one::<PathBuf>("screenshot");
+    let load_state = matches.get_one::<PathBuf>("load-state");
+    let save_state = matches.get_one::<PathBuf>("save-state").cloned();
+    let gdb = matches.get_one::<u16>("gdb");
+    let machine = match matches.get_one::<String>("machine") {
+        Some(machine) => Machine::from_arg(machine)?,
+        None => Machine::default_machine(),
+    };
+    let symbols = match matches.get_one::<PathBuf>("symbols") {
+        Some(path) => SymbolTable::load(path.to_str().unwrap())?,
+        None => SymbolTable::new(),
+    };
+
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        let file = matches.get_one::<PathBuf>("file").unwrap();
+        let base = match matches.get_one::<String>("base") {
+            Some(base) => Address::from_str_radix(base.trim_start_matches("0x"), 16)
+                .map_err(|_| Error::new("Unable to parse base address"))?,
+            None => 0,
+        };
+
+        let image = MemoryBlock::load(file.to_str().unwrap())?;
+        let count = image.len() / 4;
+        let mut bus = Bus::new();
+        bus.insert(base, wrap_transmutable(image));
+        for line in disassemble_range(&mut bus, base as Uptr, count, &symbols)? {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("trace") {
+        let file = matches.get_one::<PathBuf>("file").unwrap();
+        let json = *matches.get_one::<bool>("json").unwrap();
+        let mut reader = TraceReader::open(file)?;
+        for record in reader.by_ref() {
+            let record = record?;
+            if json {
+                println!("{}", format_json(&record));
+            } else {
+                println!("{}", format_text(&record, &symbols));
+            }
+        }
+        // On stderr, so the json lines stay clean
+        if reader.truncated() {
+            eprintln!("trace: the last record of {} is incomplete and was left out", file.display());
+        }
+        return Ok(());
+    }
+
+    let trace = match matches.get_one::<PathBuf>("trace") {
+        Some(path) => Some(build_tracer(path, &matches, &symbols)?),
+        None => None,
+    };
+
+    if matches.subcommand_matches("memory-map").is_some() {
+        print!("{}", machine.memory_map(BusKind::Main));
+        print!("{}", machine.memory_map(BusKind::Data));
+        return Ok(());
+    }
+
+    if let Some(("fs", matches)) = matches.subcommand() {
+        let (command, matches) = matches.subcommand().unwrap();
+        let image = matches.get_one::<String>("image").unwrap();
+        let image = match image.parse::<usize>() {
+            Ok(number) => {
+                let disk = machine.device(DeviceKind::Disk).ok_or_else(|| Error::new("The machine has no disk drive"))?;
+                ROOT.join(disk.path.as_ref().unwrap()).join(format!("disk_{}", number))
+            },
+            Err(_) => PathBuf::from(image),
+        };
+        return filesystem_command(command, matches, &image);
+    }
 
     if let Some(matches) = matches.subcommand_matches("tps") {
+        if let Some((command, matches)) = matches.subcommand() {
+            return tps_image(command, matches);
+        }
 
         if *matches.get_one::<bool>("list").unwrap() {
-            for file in fs::read_dir(TPS_PATH).unwrap() {
+            // Slots are the files next to the path of the machine's tps drive
+            let tps = machine.device(DeviceKind::Tps).ok_or_else(|| Error::new("The machine has no tps drive"))?;
+            let slots = ROOT.join(tps.path.as_ref().unwrap());
+            for file in fs::read_dir(slots.parent().unwrap()).unwrap() {
                 let file = file.unwrap();
                 println!("{}", file.file_name().into_string().unwrap())
             }
@@ -72,7 +364,39 @@ fn main() -> Result<(), Error> {
     }
 
     let socket: SocketAddr = ip.unwrap_or(&String::from("127.0.0.1:65432")).parse().unwrap();
-    let mut talea = build_talea(&ROOT, bin, socket.ip(), socket.port(), *debug.unwrap())?;
+    let serial = SerialConfig::parse(matches.get_one::<String>("serial").map_or("tcp", String::as_str), socket, disconnected)?;
+
+    let media = MediaConfig::new(snapshot, read_only);
+    if let Some(&true) = headless {
+        let mut talea = build_talea_headless(machine, &ROOT, bin, &serial, &media, *debug.unwrap(), *strict.unwrap(), trace)?;
+        talea.system.symbols = symbols;
+        if let Some(path) = load_state {
+            load_snapshot(path, &mut talea.system, talea.video.as_mut())?;
+        }
+        if let Some(port) = gdb {
+            attach_gdb(&mut talea.system, &talea.machine, Ipv4Addr::LOCALHOST.into(), *port)?;
+        }
+        if let Some(&true) = debug {
+            println!("Debugger enabled.");
+            talea.system.enable_debugging();
+        }
+        // A failed commit is reported without hiding the error that ended the run
+        let result = run_headless(talea, cycles.copied(), screenshot, save_state.as_ref());
+        let committed = media.finish();
+        if let (Err(_), Err(err)) = (&result, &committed) {
+            println!("Error: {}", err.msg);
+        }
+        return result.and(committed);
+    }
+
+    let mut talea = build_talea(machine, &ROOT, bin, &serial, &media, *debug.unwrap(), *strict.unwrap(), trace)?;
+    talea.system.symbols = symbols;
+    if let Some(path) = load_state {
+        load_snapshot(path, &mut talea.system, Some(&mut talea.video))?;
+    }
+    if let Some(port) = gdb {
+        attach_gdb(&mut talea.system, &talea.machine, Ipv4Addr::LOCALHOST.into(), *port)?;
+    }
 
     let mut d = false;
     if let Some(&true) = debug {
@@ -99,6 +423,15 @@ fn main() -> Result<(), Error> {
         if talea.input.update(&event) {
             // Close events
             if talea.input.quit() {
+                talea.system.shutdown();
+                if let Some(path) = &save_state {
+                    if let Err(err) = save_snapshot(path, &talea.system, Some(&mut talea.video)) {
+                        error!("saving snapshot failed: {}", err.msg);
+                    }
+                }
+                if let Err(err) = media.finish() {
+                    error!("{}", err.msg);
+                }
                 *control_flow = ControlFlow::Exit;
                 return;
             }
@@ -125,7 +458,15 @@ fn main() -> Result<(), Error> {
         
         let now = time::Instant::now();
         let ns = 16_000_000; // 16ms
-        talea.system.run_for(ns / 10);
+        // The devices have already been told of the error by the system, as in a headless run
+        if let Err(err) = talea.system.run_for(ns / 10) {
+            println!("System stopped at {} ns: {}", talea.system.clock, err.msg);
+            if let Err(err) = media.finish() {
+                error!("{}", err.msg);
+            }
+            *control_flow = ControlFlow::Exit;
+            return;
+        }
 
         if d {
             let elapsed = now.elapsed().as_millis();
@@ -134,5 +475,139 @@ fn main() -> Result<(), Error> {
     });
 }
 
+fn filesystem_command(command: &str, matches: &clap::ArgMatches, image: &Path) -> Result<(), Error> {
+    match command {
+        "mkfs" => {
+            let fs = FileSystem::format(image)?;
+            println!("{}: {} sectors, {} free, room for {} files", image.display(), fs.superblock.sectors, fs.free_sectors(),
+                fs.superblock.dir_sectors as usize * filesystem::ENTRIES_PER_SECTOR);
+        },
+        "ls" => {
+            let fs = FileSystem::open(image)?;
+            for entry in fs.entries() {
+                println!("{:<24} {:>10} bytes  sector {}", entry.name, entry.size, entry.start);
+            }
+            println!("{} sectors free", fs.free_sectors());
+        },
+        "cp-in" => {
+            let file = matches.get_one::<PathBuf>("file").unwrap();
+            let name = match matches.get_one::<String>("name") {
+                Some(name) => name.clone(),
+                None => file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+            };
+            let data = fs::read(file).map_err(|e| Error::new(&format!("{}: {}", file.display(), e)))?;
+            FileSystem::open(image)?.write(&name, &data)?;
+        },
+        "cp-out" => {
+            let file = matches.get_one::<PathBuf>("file").unwrap();
+            let data = FileSystem::open(image)?.read(matches.get_one::<String>("name").unwrap())?;
+            fs::write(file, data).map_err(|e| Error::new(&format!("{}: {}", file.display(), e)))?;
+        },
+        "rm" => {
+            FileSystem::open(image)?.remove(matches.get_one::<String>("name").unwrap())?;
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn tps_image(command: &str, matches: &clap::ArgMatches) -> Result<(), Error> {
+    let path = matches.get_one::<PathBuf>("image").unwrap();
+    let read = |file: &PathBuf| fs::read(file)
+        .map_err(|e| Error::new(&format!("{}: {}", file.display(), e)));
+
+    match command {
+        "new" => {
+            tps::Image::create(path)?;
+        },
+        "info" => {
+            print!("{}", tps::Image::open(path)?.info()?);
+        },
+        "write-boot" => {
+            let code = read(matches.get_one::<PathBuf>("bin").unwrap())?;
+            tps::Image::open(path)?.write_boot(&code)?;
+        },
+        "put" => {
+            let sector = *matches.get_one::<u8>("sector").unwrap() as usize;
+            let data = read(matches.get_one::<PathBuf>("file").unwrap())?;
+            let count = tps::Image::open(path)?.write_sectors(sector, &data)?;
+            println!("{} bytes written to sectors {}..{}", data.len(), sector, sector + count);
+        },
+        "get" => {
+            let sector = *matches.get_one::<u8>("sector").unwrap() as usize;
+            let count = *matches.get_one::<u8>("count").unwrap() as usize;
+            let file = matches.get_one::<PathBuf>("file").unwrap();
+            let data = tps::Image::open(path)?.read_sectors(sector, count)?;
+            fs::write(file, data).map_err(|e| Error::new(&format!("{}: {}", file.display(), e)))?;
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+// Addresses in the filter may be given as symbols
+fn build_tracer(path: &Path, matches: &clap::ArgMatches, symbols: &SymbolTable) -> Result<Tracer, Error> {
+    let address = |name: &str| -> Result<Option<Word>, Error> {
+        match matches.get_one::<String>(name) {
+            Some(arg) => Ok(Some(symbols.parse_address(arg)? as Word)),
+            None => Ok(None),
+        }
+    };
+
+    let range = match matches.get_one::<String>("trace-range") {
+        Some(range) => {
+            let (start, end) = range.split_once(':').ok_or_else(|| Error::new("Trace range must be START:END"))?;
+            Some(symbols.parse_address(start)? as Word..symbols.parse_address(end)? as Word)
+        },
+        None => None,
+    };
+    let mode = matches.get_one::<String>("trace-mode").map(|mode| match mode.as_str() {
+        "user" => TraceMode::User,
+        _ => TraceMode::Supervisor,
+    });
+
+    let filter = TraceFilter {
+        range,
+        mode,
+        start: address("trace-start")?,
+        stop: address("trace-stop")?,
+    };
+    Tracer::create(path, filter)
+}
+
+fn run_headless(mut talea: HeadlessTalea, cycles: Option<u64>, screenshot: Option<&PathBuf>, save_state: Option<&PathBuf>) -> Result<(), Error> {
+    let cycle = 1_000_000_000 / talea.machine.cpu.frequency as u64;
+    // The cycles count from where the system is, which a restored snapshot may have moved on
+    let target = cycles.map(|c| talea.system.clock.saturating_add(c.saturating_mul(cycle))).unwrap_or(u64::MAX);
+    let frame = 16_000_000; // 16ms
 
+    // There is no window to pace the emulation, so it runs as fast as it can
+    // in slices of one frame, updating the video device in between
+    let mut result = Ok(());
+    while talea.system.clock < target {
+        let slice = std::cmp::min(frame, target - talea.system.clock);
+        if let Err(err) = talea.system.run_for(slice) {
+            println!("System stopped at {} ns", talea.system.clock);
+            result = Err(err);
+            break;
+        }
+        if let Some(video) = talea.video.as_mut() {
+            video.update_headless(&talea.system)?;
+        }
+    }
+    if result.is_ok() {
+        talea.system.shutdown();
+    }
+
+    if let Some(path) = screenshot {
+        let video = talea.video.as_mut().ok_or_else(|| Error::new("The machine has no screen to save"))?;
+        video.screen.render().or_else(|e| Err(Error::new(&format!("{}", e))))?;
+        video.screen.save_frame(path)?;
+    }
+
+    if let Some(path) = save_state {
+        save_snapshot(path, &talea.system, talea.video.as_mut())?;
+    }
 
+    result
+}
@@ -102,6 +102,8 @@ SYSCALL  = SYS @ 0x2
 GSREG    = SYS @ 0x3
 SSREG    = SYS @ 0x4
 SYSRET   = SYS @ 0x6
+TLBFLUSH = SYS @ 0x7
+TLBINV   = SYS @ 0x8
 
 
 #subruledef reg {
@@ -283,6 +285,8 @@ SYSRET   = SYS @ 0x6
     gsreg   {rd: reg} => GSREG @ rd @ BLANK10 @ BLANK10
     ssreg   {rs1: reg} => SSREG @ rs1 @ BLANK10 @ BLANK10
     sysret  => SYSRET @ BLANK15 @ BLANK10
+    tlbflush => TLBFLUSH @ BLANK15 @ BLANK10
+    tlbinv  {rs1: reg} => TLBINV @ rs1 @ BLANK10 @ BLANK10
 }
 
 #ruledef {
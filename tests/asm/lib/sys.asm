@@ -11,10 +11,24 @@ _IVT = 0xf800
 _PDT = 0xff00
 
 ; TTY
-T_RX = TTY + 0x00
-T_TX = TTY + 0x02
-T_STAT = TTY + 0x03
-T_CTRL = TTY + 0x04
+T_RX     = TTY + 0x00
+T_RXLEN  = TTY + 0x01
+T_TX     = TTY + 0x02
+T_TXLEN  = TTY + 0x03
+T_STAT   = TTY + 0x04
+T_CTRL   = TTY + 0x05
+
+; status flags
+T_busy       = 1 << 0
+T_txempty    = 1 << 1
+T_rxready    = 1 << 2
+T_txfull     = 1 << 3
+T_rxoverrun  = 1 << 4
+T_txoverrun  = 1 << 5
+
+; control bits
+T_rxint = 1 << 0
+T_txint = 1 << 1
 
 ; VIDEO
 V_COMMAND = VDO + 0x0
@@ -97,8 +111,9 @@ IVT_DIVISION_ZERO       = 0x05 * 4
 IVT_PRIVILEGE_VIOLATION = 0x06 * 4
 IVT_PAGE_FAULT          = 0x07 * 4
 IVT_ACCESS_VIOLATION    = 0x08 * 4
-IVT_TTY_TRANSMIT       = 0x0a * 4
+IVT_TTY_RECEIVED       = 0x0a * 4
 IVT_KBD_CHARACTER      = 0x0b * 4
 IVT_KBD_SCANCODE       = 0x0c * 4
 IVT_TPS_LOAD_FINISHED  = 0x0d * 4
-IVT_DISK_LOAD_FINISHED = 0x0e * 4
\ No newline at end of file
+IVT_DISK_LOAD_FINISHED = 0x0e * 4
+IVT_TTY_TX_EMPTY       = 0x11 * 4
\ No newline at end of file
@@ -1,44 +1,39 @@
-; Write Hello world to the tty
+; Echo what is typed on the tty
 #include "lib/master.asm"
 #include "lib/sys.asm"
 
-RX_LEN = T_RX + 1
-IVT_TTY_EOT = 0xf
 #addr 0
 start:
     li  a0, le(0b1_1_0_010_111110_11111111_000000000000)
-    ;          0b1_1_0_101_111110_11111111_000000000000 
+    ;          0b1_1_0_101_111110_11111111_000000000000
     ssreg a0
-    la a0, tty_transmit_handler
+    la a0, tty_received_handler
     li t1, _IVT
-    swd a0, IVT_TTY_TRANSMIT(t1)
+    swd a0, IVT_TTY_RECEIVED(t1)
     la sp, supervisor_stack
-    addi t1, zero, 1
+    addi t1, zero, T_rxint
+    sbd t1, T_CTRL(zero)
     .receive:
         j .receive
 
 
-tty_transmit_handler:
-    pushb zero, sp      ; NULL terminator
-    
+; The bytes come out of RX in the order they arrived, and are sent back as they are
+tty_received_handler:
     .transmit:
+        lbud a0, T_RXLEN(zero)
+        beq zero, a0, .end
         lbud a0, T_RX(zero)
-        pushb a0, sp
-        lbud a0, RX_LEN(zero)
-        beq zero, a0, .print
-        j .transmit
 
-    .print:
-        popb a0, sp
-        beq a0, zero, .end
+    .wait:
+        lbud t0, T_STAT(zero)
+        andi t0, t0, T_txfull
+        bne t0, zero, .wait
         sbd a0, T_TX(zero)
-        j .print
+        j .transmit
 
     .end:
-        addi a0, zero, 10 ; NEWLINE
-        sbd a0, T_TX(zero)
         sysret
 
 
 #res 1024
-supervisor_stack:
\ No newline at end of file
+supervisor_stack: